@@ -1,5 +1,20 @@
 use std::fmt::{self, Debug};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
 use crate::ext::prim::u20;
+use crate::devices::{PortMappedDevice, InterruptController};
+use crate::cpu::cycle::{BusCycle, BusStatus};
+
+/// The 5150 motherboard adds one wait state to every I/O bus cycle.
+pub const IO_WAIT_STATES_DEFAULT:u8         = 0x01;
+/// DMA channel 0 refreshes DRAM every 72 clocks (PIT channel 1 reload 18)
+/// and holds the bus for four clocks while doing so. The timer is not
+/// emulated, so [M5150::start] enables refresh at this rate through
+/// [BusInterface::set_dma_refresh] as the BIOS would program it.
+pub const DMA_REFRESH_PERIOD:u32            = 72;
+pub const DMA_REFRESH_CLOCKS:u8             = 0x04;
+/// Granularity at which writes invalidate the CPU's decoded instructions.
+pub const CODE_PAGE_SIZE:usize              = 0x1000;
 
 #[derive(Debug, Clone)]
 pub enum BusMemoryError {
@@ -15,8 +30,24 @@ impl fmt::Display for BusMemoryError {
 }
 
 pub struct BusInterface {
-    ram:[u8; 1024 * 1024],
+    ram:Box<[u8]>,
     address_latch:u20,
+
+    devices:Vec<Box<dyn PortMappedDevice>>,
+    ports:HashMap<u16, usize>, /* port -> index into devices */
+    pic:Option<Box<dyn InterruptController>>,
+
+    /* wait states inserted per bus cycle, later entries take precedence */
+    mem_wait_states:Vec<(RangeInclusive<u32>, u8)>,
+    io_wait_states:Vec<(RangeInclusive<u16>, u8)>,
+    io_wait_default:u8,
+
+    dma_refresh:Option<(u32, u8)>, /* period in clocks, clocks stolen */
+    refresh_counter:u32,
+    refresh_pending:u32, /* refreshes not yet charged to the CPU */
+
+    code_pages:Box<[bool]>, /* pages holding decoded instructions */
+    dirty_pages:Vec<u32>,   /* code pages written since last taken */
 }
 
 impl Default for BusInterface {
@@ -28,9 +59,23 @@ impl Default for BusInterface {
 impl BusInterface {
     pub fn new() -> Self {
         Self {
-            ram:[0x00; 1024 * 1024],
+            ram:vec![0x00; 1024 * 1024].into_boxed_slice(),
             address_latch:u20::new(0x00),
-            ..BusInterface::default()
+
+            devices:Vec::new(),
+            ports:HashMap::new(),
+            pic:None,
+
+            mem_wait_states:Vec::new(),
+            io_wait_states:Vec::new(),
+            io_wait_default:IO_WAIT_STATES_DEFAULT,
+
+            dma_refresh:None,
+            refresh_counter:0,
+            refresh_pending:0,
+
+            code_pages:vec![false; 1024 * 1024 / CODE_PAGE_SIZE].into_boxed_slice(),
+            dirty_pages:Vec::new(),
         }
     }
 
@@ -42,8 +87,176 @@ impl BusInterface {
         0x00
     }
 
+    /// Address of the most recent memory access.
+    pub fn address_latch(&self) -> u32 {
+        self.address_latch.get()
+    }
+
     pub fn read_8(&mut self, addr:usize) -> Result<u8, BusMemoryError> {
-        //if addr > self.ram.len() { return Err(OutOfBounds) }
-        Ok(1)
+        if addr >= self.ram.len() { return Err(BusMemoryError::OutOfBounds) }
+        self.address_latch.set(addr as u32);
+        Ok(self.ram[addr])
+    }
+
+    pub fn write_8(&mut self, addr:usize, val:u8) -> Result<(), BusMemoryError> {
+        if addr >= self.ram.len() { return Err(BusMemoryError::OutOfBounds) }
+        self.address_latch.set(addr as u32);
+        self.ram[addr] = val;
+        self.touch(addr);
+        Ok(())
+    }
+
+    /// Reads memory without driving the bus, for debuggers and the
+    /// disassembler. Memory outside of RAM reads as 0xFF.
+    pub fn peek_8(&self, addr:usize) -> u8 {
+        self.ram.get(addr).copied().unwrap_or(0xFF)
+    }
+
+    /// Copies [data] into memory starting at [addr].
+    pub fn load(&mut self, addr:usize, data:&[u8]) -> Result<(), BusMemoryError> {
+        let end = addr.checked_add(data.len())
+            .ok_or(BusMemoryError::OutOfBounds)?;
+        if end > self.ram.len() { return Err(BusMemoryError::OutOfBounds) }
+        self.ram[addr..end].copy_from_slice(data);
+        for page in (addr / CODE_PAGE_SIZE)..end.div_ceil(CODE_PAGE_SIZE) {
+            self.touch(page * CODE_PAGE_SIZE);
+        }
+        Ok(())
+    }
+
+    /// Marks the page holding [addr] as containing decoded code, so that
+    /// the next write to it is reported by [take_dirty_pages].
+    pub fn mark_code(&mut self, addr:u32) {
+        self.code_pages[addr as usize / CODE_PAGE_SIZE] = true;
+    }
+
+    /// Code pages written since the last call, as page numbers. A page is
+    /// reported once, until marked again.
+    pub fn take_dirty_pages(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.dirty_pages)
+    }
+
+    fn touch(&mut self, addr:usize) {
+        let page = addr / CODE_PAGE_SIZE;
+        if std::mem::take(&mut self.code_pages[page]) {
+            self.dirty_pages.push(page as u32);
+        }
+    }
+
+    /// Inserts [n] wait states into every memory access within [range], e.g.
+    /// for slow expansion card memory.
+    pub fn set_memory_wait_states(&mut self, range:RangeInclusive<u32>, n:u8) {
+        self.mem_wait_states.push((range, n));
+    }
+
+    /// Inserts [n] wait states into every access to the ports in [range],
+    /// on top of the default.
+    pub fn set_io_wait_states(&mut self, range:RangeInclusive<u16>, n:u8) {
+        self.io_wait_states.push((range, n));
+    }
+
+    /// Wait states for I/O ports without a specific entry.
+    pub fn set_io_wait_default(&mut self, n:u8) {
+        self.io_wait_default = n;
+    }
+
+    /// Enables periodic DMA refresh cycles, each stealing [clocks] from the
+    /// bus every [period] clocks. A period of 0 disables refresh.
+    pub fn set_dma_refresh(&mut self, period:u32, clocks:u8) {
+        self.dma_refresh = if period > 0 { Some((period, clocks)) } else { None };
+        self.refresh_counter = 0;
+        self.refresh_pending = 0;
+    }
+
+    /// Period and stolen clocks of DMA refresh, if enabled.
+    pub fn dma_refresh(&self) -> Option<(u32, u8)> {
+        self.dma_refresh
+    }
+
+    /// Advances the bus by one clock.
+    pub fn clock(&mut self) {
+        if let Some((period, _)) = self.dma_refresh {
+            self.refresh_counter += 1;
+            if self.refresh_counter >= period {
+                self.refresh_counter = 0;
+                self.refresh_pending += 1;
+            }
+        }
+    }
+
+    /// Clocks stolen by the DMA refreshes that came due since last taken.
+    /// Instruction-level execution, which has no bus cycles to stretch,
+    /// adds them to the clocks of the instruction.
+    pub fn take_refresh_clocks(&mut self) -> u32 {
+        let Some((_, stolen)) = self.dma_refresh else { return 0 };
+        std::mem::take(&mut self.refresh_pending) * stolen as u32
+    }
+
+    /// Forgets the DMA refreshes that came due while the CPU left the bus
+    /// idle. The DMA controller takes the bus in between CPU cycles then,
+    /// and the CPU loses no time to them.
+    pub fn drop_refresh(&mut self) {
+        self.refresh_pending = 0;
+    }
+
+    /// Number of wait states the bus holds READY low for during [c], from
+    /// the wait-state tables. DMA refreshes are taken separately through
+    /// [take_refresh_clocks].
+    pub fn wait_states(&self, c:&BusCycle) -> u8 {
+        match c.status {
+            BusStatus::IoRead | BusStatus::IoWrite => {
+                let port = c.addr as u16;
+                self.io_wait_states.iter().rev()
+                    .find(|(r, _)| r.contains(&port))
+                    .map_or(0, |&(_, n)| n)
+                    .saturating_add(self.io_wait_default)
+            },
+            BusStatus::CodeFetch | BusStatus::MemRead | BusStatus::MemWrite => {
+                self.mem_wait_states.iter().rev()
+                    .find(|(r, _)| r.contains(&c.addr))
+                    .map_or(0, |&(_, n)| n)
+            },
+            _ => 0,
+        }
+    }
+
+    /// Maps all ports reported by [dev] onto the I/O bus. Ports already
+    /// claimed by another device are taken over by the new one.
+    pub fn attach_device(&mut self, dev:Box<dyn PortMappedDevice>) {
+        let idx = self.devices.len();
+        for port in dev.ports() {
+            self.ports.insert(port, idx);
+        }
+        self.devices.push(dev);
+    }
+
+    /// Connects the INTR and INTA lines to [pic].
+    pub fn attach_interrupt_controller(&mut self, pic:Box<dyn InterruptController>) {
+        self.pic = Some(pic);
+    }
+
+    /// Level of the INTR line driven by the interrupt controller.
+    pub fn intr(&self) -> bool {
+        self.pic.as_ref().is_some_and(|p| p.intr())
+    }
+
+    /// Vector put on the data bus during INTA. Nothing answers without an
+    /// interrupt controller, so the bus floats high.
+    pub fn acknowledge_interrupt(&mut self) -> u8 {
+        self.pic.as_mut().map_or(0xFF, |p| p.acknowledge())
+    }
+
+    /// Reads from an I/O port. Unmapped ports float high.
+    pub fn io_read_8(&mut self, port:u16) -> u8 {
+        match self.ports.get(&port) {
+            Some(&idx) => self.devices[idx].read_8(port),
+            None => 0xFF,
+        }
+    }
+
+    pub fn io_write_8(&mut self, port:u16, val:u8) {
+        if let Some(&idx) = self.ports.get(&port) {
+            self.devices[idx].write_8(port, val);
+        }
     }
 }
@@ -1,17 +1,129 @@
 use std::fmt;
+use crate::core::bus::{DMA_REFRESH_PERIOD, DMA_REFRESH_CLOCKS};
+use crate::cpu::{
+    I8088, CpuStatus,
+    cores::{Cpu, CpuCore},
+};
 
 pub struct M5150 {
     mstate:MachineState,
     astate:ActivityState,
+    cpu:Option<Box<dyn Cpu>>, /* only empty while moving to another core */
 }
 
 impl M5150 {
-    fn start(&mut self) {
-    
+    pub fn new(core:CpuCore) -> Self {
+        Self {
+            mstate:MachineState::Off,
+            astate:ActivityState::Paused,
+            cpu:Some(core.build(I8088::new())),
+        }
+    }
+
+    pub fn cpu(&self) -> &dyn Cpu {
+        self.cpu.as_deref().expect("CPU fitted to a core")
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut dyn Cpu {
+        self.cpu.as_deref_mut().expect("CPU fitted to a core")
+    }
+
+    /// Moves the processor to another execution core, keeping its state.
+    pub fn set_core(&mut self, core:CpuCore) {
+        if self.cpu().core() == core {
+            return;
+        }
+        let cpu = self.cpu.take().expect("CPU fitted to a core");
+        self.cpu = Some(core.build(cpu.into_inner()));
+    }
+
+    /// Single-steps the CPU. Returns a message for the console when it
+    /// stopped or failed.
+    pub fn step(&mut self) -> Option<String> {
+        match self.cpu_mut().step() {
+            Ok(status) => self.report(&status),
+            Err(e) => Some(e.to_string()),
+        }
+    }
+
+    /// Runs the machine for up to [clocks] clocks, stopping early on the
+    /// conditions [step] reports. A hung CPU is clocked on along with its
+    /// devices, and the machine runs again once an NMI wakes it.
+    pub fn run(&mut self, clocks:u64) -> Option<String> {
+        for _ in 0..clocks {
+            let msg = match self.cpu_mut().cycle() {
+                Ok(status) => self.report(&status),
+                Err(e) => Some(e.to_string()),
+            };
+            if msg.is_some() {
+                return msg;
+            }
+            if matches!(self.astate, ActivityState::Hung) && !self.cpu().halted() {
+                self.astate = ActivityState::Running;
+            }
+        }
+        None
+    }
+
+    /// Powers the machine on. The CPU comes out of reset at FFFF:0000 and
+    /// starts running the BIOS, with DMA channel 0 refreshing memory.
+    pub fn start(&mut self) {
+        self.cpu_mut().reset();
+        self.cpu_mut().bus_mut().set_dma_refresh(DMA_REFRESH_PERIOD, DMA_REFRESH_CLOCKS);
+        self.mstate = MachineState::On;
+        self.astate = ActivityState::Running;
+    }
+
+    pub fn stop(&mut self) {
+        self.mstate = MachineState::Off;
+        self.astate = ActivityState::Paused;
     }
 
-    fn stop(&mut self) {
+    pub fn state(&self) -> (MachineState, ActivityState) {
+        (self.mstate, self.astate)
+    }
 
+    /// Carries out an operation requested from the console. Operations
+    /// that do not apply to the current activity are ignored. Returns a
+    /// message for the console, as [step] does.
+    pub fn operate(&mut self, op:MachineOperation) -> Option<String> {
+        match op {
+            MachineOperation::Pause if self.astate.can_pause() => {
+                self.astate = ActivityState::Paused;
+            },
+            MachineOperation::Resume if self.astate.can_resume() => {
+                self.astate = ActivityState::Running;
+            },
+            MachineOperation::Run if self.astate.can_run() => {
+                self.astate = ActivityState::Running;
+            },
+            // Calls are not stepped over yet, so all steps go into them.
+            MachineOperation::SingleStep | MachineOperation::StepOver
+            | MachineOperation::StepInto if self.astate.can_step() => {
+                self.astate = ActivityState::SingleStep;
+                return self.step();
+            },
+            MachineOperation::Reset => {
+                self.mstate = MachineState::Rebooting;
+                self.start();
+            },
+            _ => {},
+        }
+        None
+    }
+
+    /// Follows the status the CPU returned from a step. Returns a message
+    /// for the console when execution stopped by itself, or when the CPU
+    /// hung, which is reported once rather than on every clock it stays
+    /// halted for.
+    pub fn report(&mut self, status:&CpuStatus) -> Option<String> {
+        self.astate = match (status, self.astate) {
+            (CpuStatus::Breakpoint, _) => ActivityState::Breakpoint,
+            (CpuStatus::Hang, ActivityState::Hung) => return None,
+            (CpuStatus::Hang, _) => ActivityState::Hung,
+            _ => return None,
+        };
+        Some(status.to_string())
     }
 }
 
@@ -38,6 +150,9 @@ pub enum ActivityState {
     Running,
     Breakpoint,
     SingleStep,
+    /// Halted with interrupts disabled. The CPU is still clocked, and runs
+    /// again when woken by NMI, or by a reset.
+    Hung,
 }
 
 impl ActivityState {
@@ -48,7 +163,7 @@ impl ActivityState {
     /// Can we pause the running machine?
     fn can_pause(&self) -> bool {
         matches!(self, ActivityState::Running | ActivityState::Breakpoint
-            | ActivityState::SingleStep)
+            | ActivityState::SingleStep | ActivityState::Hung)
     }
     /// Can we resume execution of a running machine?
     fn can_run(&self) -> bool {
@@ -67,6 +182,7 @@ impl fmt::Display for ActivityState {
             ActivityState::Running => write!(f, "Running"),
             ActivityState::Breakpoint => write!(f, "Breakpoint"),
             ActivityState::SingleStep => write!(f, "SingleStep"),
+            ActivityState::Hung => write!(f, "Hung"),
         }
     }
 }
@@ -95,3 +211,76 @@ impl fmt::Display for MachineOperation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{flags::FLAGS_FIXED, register::Register};
+
+    #[test]
+    fn test_reset() {
+        let mut m = M5150::new(CpuCore::Fast);
+        // The BIOS jumps from the reset vector to its entry point.
+        m.cpu_mut().bus_mut().load(0xFFFF0, &[0xEA, 0x5B, 0xE0, 0x00, 0xF0]).unwrap();
+        m.cpu_mut().bus_mut().load(0xFE05B, &[0xF4]).unwrap();
+        m.cpu_mut().set_register(Register::DS, 0x1234);
+        m.cpu_mut().set_register(Register::FLAGS, 0xFFFF);
+
+        assert_eq!(m.cpu().bus().dma_refresh(), None);
+        m.start();
+        assert_eq!(m.cpu().bus().dma_refresh(), Some((DMA_REFRESH_PERIOD, DMA_REFRESH_CLOCKS)));
+        assert_eq!(m.cpu().register(Register::CS), 0xFFFF);
+        assert_eq!(m.cpu().ip(), 0x0000);
+        assert_eq!(m.cpu().register(Register::DS), 0x0000);
+        assert_eq!(m.cpu().flags(), FLAGS_FIXED);
+
+        assert!(m.run(100).is_some());
+        assert!(matches!(m.state().1, ActivityState::Hung));
+        assert_eq!(m.cpu().register(Register::CS), 0xF000);
+
+        m.operate(MachineOperation::Reset);
+        assert!(matches!(m.state(), (MachineState::On, ActivityState::Running)));
+        assert_eq!(m.cpu().ip(), 0x0000);
+        assert_eq!(m.cpu().register(Register::CS), 0xFFFF);
+    }
+
+    #[test]
+    fn test_nmi_wakes_hung() {
+        for core in [CpuCore::Fast, CpuCore::CycleExact] {
+            let mut m = M5150::new(core);
+            // cli; hlt at the reset vector, and an NMI handler at 0000:0500
+            // running mov ax, 0x55; jmp $
+            m.cpu_mut().bus_mut().load(0xFFFF0, &[0xFA, 0xF4]).unwrap();
+            m.cpu_mut().bus_mut().load(0x08, &[0x00, 0x05, 0x00, 0x00]).unwrap();
+            m.cpu_mut().bus_mut().load(0x500, &[0xB8, 0x55, 0x00, 0xEB, 0xFE]).unwrap();
+            m.start();
+            m.cpu_mut().set_register(Register::SS, 0x0000);
+            m.cpu_mut().set_register(Register::SP, 0x1000);
+            assert!(m.run(100).is_some());
+            assert!(matches!(m.state().1, ActivityState::Hung));
+
+            // Still hung, the machine keeps clocking without another report.
+            let clocks = m.cpu().clocks();
+            assert!(m.run(100).is_none());
+            assert!(m.cpu().clocks() > clocks);
+            assert!(matches!(m.state().1, ActivityState::Hung));
+
+            m.cpu_mut().set_nmi(true);
+            assert!(m.run(200).is_none());
+            assert!(matches!(m.state().1, ActivityState::Running));
+            assert_eq!(m.cpu().register(Register::AX), 0x55);
+        }
+    }
+
+    #[test]
+    fn test_set_core() {
+        let mut m = M5150::new(CpuCore::Fast);
+        m.cpu_mut().set_register(Register::DS, 0x1234);
+        m.set_core(CpuCore::CycleExact);
+        assert_eq!(m.cpu().core(), CpuCore::CycleExact);
+        assert_eq!(m.cpu().register(Register::DS), 0x1234);
+        m.set_core(CpuCore::Fast);
+        assert_eq!(m.cpu().core(), CpuCore::Fast);
+        assert_eq!(m.cpu().register(Register::DS), 0x1234);
+    }
+}
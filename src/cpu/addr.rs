@@ -1,6 +1,7 @@
-use crate::cpu::I8088;
+use std::fmt;
+use crate::cpu::{I8088, decode::MemoryOperand};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Segment {
     CS,
     DS,
@@ -8,6 +9,63 @@ pub enum Segment {
     ES,
 }
 
+impl fmt::Display for Segment {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Segment::CS => write!(f, "cs"),
+            Segment::DS => write!(f, "ds"),
+            Segment::SS => write!(f, "ss"),
+            Segment::ES => write!(f, "es"),
+        }
+    }
+}
+
+/// Result of a ModR/M effective address calculation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EffectiveAddress {
+    pub segment:Segment,
+    pub offset:u16,
+    /// 20-bit physical address.
+    pub physical:u32,
+    /// EA calculation clocks, including any segment override penalty.
+    pub clocks:u8,
+}
+
+impl MemoryOperand {
+    /// Is this the mod 00, r/m 110 direct addressing form?
+    pub fn is_direct(&self) -> bool {
+        self.md == 0b00 && self.rm == 0b110
+    }
+
+    /// BP-based modes address the stack segment, all others the data
+    /// segment.
+    pub fn default_segment(&self) -> Segment {
+        match self.rm {
+            0b010 | 0b011 => Segment::SS,
+            0b110 if !self.is_direct() => Segment::SS,
+            _ => Segment::DS,
+        }
+    }
+
+    /// Clock cost of the EA calculation on the 8088, excluding the segment
+    /// override penalty.
+    pub fn ea_clocks(&self) -> u8 {
+        if self.is_direct() { return 6; }
+        let disp = self.md != 0b00;
+        match (self.rm, disp) {
+            /* [bx+si], [bp+di] */
+            (0b000 | 0b011, false) => 7,
+            (0b000 | 0b011, true) => 11,
+            /* [bx+di], [bp+si] */
+            (0b001 | 0b010, false) => 8,
+            (0b001 | 0b010, true) => 12,
+            /* [si], [di], [bp], [bx] */
+            (_, false) => 5,
+            (_, true) => 9,
+        }
+    }
+}
+
 impl I8088 {
     // Calculates a 20-bit physical address from a 16-bit segment value and a
     // 16-bit offset. Last calculated address is stored for future use.
@@ -20,6 +78,34 @@ impl I8088 {
         } as u32) << 4) + o as u32) & 0xFFFFFu32;
         self.le
     }
+
+    // Calculates the effective address of a ModR/M memory operand. The
+    // offset wraps at 64K; a segment override replaces the default segment
+    // and costs two additional clocks.
+    pub fn calculate_effective_address(&mut self, m:&MemoryOperand,
+                                       seg:Option<Segment>)
+                                       -> EffectiveAddress {
+        let base = match m.rm {
+            0b000 => self.bx.wrapping_add(self.si),
+            0b001 => self.bx.wrapping_add(self.di),
+            0b010 => self.bp.wrapping_add(self.si),
+            0b011 => self.bp.wrapping_add(self.di),
+            0b100 => self.si,
+            0b101 => self.di,
+            0b110 if m.is_direct() => 0,
+            0b110 => self.bp,
+            _ => self.bx,
+        };
+        let offset = base.wrapping_add(m.disp);
+        let segment = seg.unwrap_or(m.default_segment());
+        let clocks = m.ea_clocks() + if seg.is_some() { 2 } else { 0 };
+        EffectiveAddress {
+            segment,
+            offset,
+            physical:self.calculate_physical_address(segment, offset),
+            clocks,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -32,4 +118,35 @@ mod tests {
         assert_eq!(cpu.calculate_physical_address(Segment::CS, 0xFF), 0xFF);
         assert_ne!(cpu.calculate_physical_address(Segment::CS, 0xFE), 0xFF);
     }
+
+    #[test]
+    fn test_effective_address() {
+        let mut cpu = I8088::new();
+        cpu.bp = 0xFFF0;
+        cpu.si = 0x0020;
+        cpu.ss = 0x1000;
+        cpu.ds = 0x2000;
+
+        // [bp+si-0x4] wraps at 64K and defaults to SS
+        let m = MemoryOperand { md:0b01, rm:0b010, disp:0xFFFC };
+        let ea = cpu.calculate_effective_address(&m, None);
+        assert_eq!(ea.segment, Segment::SS);
+        assert_eq!(ea.offset, 0x000C);
+        assert_eq!(ea.physical, 0x1000C);
+        assert_eq!(ea.clocks, 12);
+
+        // mod 00 r/m 110 is a direct address in DS, not [bp]
+        let m = MemoryOperand { md:0b00, rm:0b110, disp:0x1234 };
+        let ea = cpu.calculate_effective_address(&m, None);
+        assert_eq!(ea.segment, Segment::DS);
+        assert_eq!(ea.physical, 0x21234);
+        assert_eq!(ea.clocks, 6);
+
+        // es: [bp]
+        let m = MemoryOperand { md:0b01, rm:0b110, disp:0x0000 };
+        let ea = cpu.calculate_effective_address(&m, Some(Segment::ES));
+        assert_eq!(ea.segment, Segment::ES);
+        assert_eq!(ea.offset, 0xFFF0);
+        assert_eq!(ea.clocks, 11);
+    }
 }
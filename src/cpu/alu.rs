@@ -0,0 +1,446 @@
+use crate::cpu::{
+    I8088, CpuError,
+    decode::OperandSize,
+    mnemonic::Mnemonic,
+    flags::*,
+};
+
+impl OperandSize {
+    pub fn mask(&self) -> u16 {
+        match self {
+            OperandSize::Byte => 0x00FF,
+            OperandSize::Word => 0xFFFF,
+        }
+    }
+
+    /// Sign bit of a value of this size.
+    pub fn msb(&self) -> u16 {
+        match self {
+            OperandSize::Byte => 0x0080,
+            OperandSize::Word => 0x8000,
+        }
+    }
+
+    pub fn bytes(&self) -> u16 {
+        match self {
+            OperandSize::Byte => 1,
+            OperandSize::Word => 2,
+        }
+    }
+}
+
+/// Quotient and remainder of a successful DIV or IDIV. Errors are raised
+/// as INT 0 by the EU.
+pub type DivResult = Result<(u16, u16), CpuError>;
+
+impl I8088 {
+    pub(crate) fn alu_add(&mut self, a:u16, b:u16, carry:bool,
+                          s:OperandSize) -> u16 {
+        let res = a.wrapping_add(b).wrapping_add(carry as u16) & s.mask();
+        self.defer_flags(LazyFlags {
+            op:LazyOp::Add, a, b, carry, res, size:s, kept:LazyCarry::Value(false)
+        });
+        res
+    }
+
+    pub(crate) fn alu_sub(&mut self, a:u16, b:u16, borrow:bool,
+                          s:OperandSize) -> u16 {
+        let res = a.wrapping_sub(b).wrapping_sub(borrow as u16) & s.mask();
+        self.defer_flags(LazyFlags {
+            op:LazyOp::Sub, a, b, carry:borrow, res, size:s,
+            kept:LazyCarry::Value(false)
+        });
+        res
+    }
+
+    /// Sets flags for the result of AND, OR, XOR and TEST. AF is documented
+    /// as undefined; the 8088 always clears it.
+    pub(crate) fn alu_logic(&mut self, res:u16, s:OperandSize) -> u16 {
+        self.defer_flags(LazyFlags {
+            op:LazyOp::Logic, a:0, b:0, carry:false, res, size:s,
+            kept:LazyCarry::Value(false)
+        });
+        res
+    }
+
+    /// INC and DEC leave CF untouched. In lazy mode they take over where
+    /// the CF pending from an earlier operation comes from, rather than
+    /// evaluating it.
+    pub(crate) fn alu_inc(&mut self, a:u16, s:OperandSize) -> u16 {
+        let res = a.wrapping_add(1) & s.mask();
+        let kept = self.lazy_carry();
+        self.defer_flags(LazyFlags {
+            op:LazyOp::Inc, a, b:1, carry:false, res, size:s, kept
+        });
+        res
+    }
+
+    pub(crate) fn alu_dec(&mut self, a:u16, s:OperandSize) -> u16 {
+        let res = a.wrapping_sub(1) & s.mask();
+        let kept = self.lazy_carry();
+        self.defer_flags(LazyFlags {
+            op:LazyOp::Dec, a, b:1, carry:false, res, size:s, kept
+        });
+        res
+    }
+
+    /// Rotates and shifts [a] by [count] bits. The 8088 does not mask the
+    /// count, so shifting by CL runs the full number of iterations. A count
+    /// of zero leaves all flags unchanged.
+    ///
+    /// For counts above one, OF is left from the final iteration rather than
+    /// being undefined. Shifts set AF to bit 4 of the result of SHL/SAL and
+    /// clear it for SHR/SAR; rotates leave SF, ZF, PF and AF alone.
+    pub(crate) fn alu_shift(&mut self, m:Mnemonic, a:u16, count:u8,
+                            s:OperandSize) -> u16 {
+        if count == 0 { return a; }
+        // The undocumented /6 shift sets all bits, with flags as for OR.
+        if m == Mnemonic::SETMO { return self.alu_logic(s.mask(), s); }
+        let msb = s.msb();
+        let mut v = a;
+        // Only the rotates through carry need the incoming CF.
+        let mut cf = matches!(m, Mnemonic::RCL | Mnemonic::RCR) && self.flag(FLAG_CF);
+        let mut of = false;
+        for _ in 0..count {
+            match m {
+                Mnemonic::ROL => {
+                    cf = v & msb != 0;
+                    v = ((v << 1) | cf as u16) & s.mask();
+                    of = (v & msb != 0) ^ cf;
+                },
+                Mnemonic::ROR => {
+                    cf = v & 1 != 0;
+                    v = (v >> 1) | if cf { msb } else { 0 };
+                    of = (v ^ (v << 1)) & msb != 0;
+                },
+                Mnemonic::RCL => {
+                    let out = v & msb != 0;
+                    v = ((v << 1) | cf as u16) & s.mask();
+                    cf = out;
+                    of = (v & msb != 0) ^ cf;
+                },
+                Mnemonic::RCR => {
+                    let out = v & 1 != 0;
+                    v = (v >> 1) | if cf { msb } else { 0 };
+                    cf = out;
+                    of = (v ^ (v << 1)) & msb != 0;
+                },
+                Mnemonic::SHL | Mnemonic::SAL => {
+                    cf = v & msb != 0;
+                    v = (v << 1) & s.mask();
+                    of = (v & msb != 0) ^ cf;
+                },
+                Mnemonic::SHR => {
+                    cf = v & 1 != 0;
+                    of = v & msb != 0;
+                    v >>= 1;
+                },
+                Mnemonic::SAR => {
+                    cf = v & 1 != 0;
+                    of = false;
+                    v = (v >> 1) | (v & msb);
+                },
+                _ => unreachable!("{:?} is not a shift", m),
+            }
+        }
+        let co = if cf { FLAG_CF } else { 0 } | if of { FLAG_OF } else { 0 };
+        match m {
+            // Shifts set every arithmetic flag, so nothing pending is needed.
+            Mnemonic::SHL | Mnemonic::SAL => {
+                let af = if v & 0x10 != 0 { FLAG_AF } else { 0 };
+                self.set_arith_flags(co | af | Self::szp(v, msb));
+            },
+            Mnemonic::SHR | Mnemonic::SAR => {
+                self.set_arith_flags(co | Self::szp(v, msb));
+            },
+            _ => {
+                self.set_flag(FLAG_CF, cf);
+                self.set_flag(FLAG_OF, of);
+            },
+        }
+        v
+    }
+
+    /// SF, ZF and PF are undefined after MUL and IMUL. The 8088 leaves them
+    /// set from the high half of the product, and clears AF.
+    fn set_mul_flags(&mut self, hi:u16, overflow:bool, s:OperandSize) {
+        let co = if overflow { FLAG_CF | FLAG_OF } else { 0 };
+        self.set_arith_flags(co | Self::szp(hi, s.msb()));
+    }
+
+    /// Unsigned multiply. Returns (high, low) halves of the product.
+    pub(crate) fn alu_mul(&mut self, a:u16, b:u16, s:OperandSize)
+                          -> (u16, u16) {
+        let (hi, lo) = match s {
+            OperandSize::Byte => {
+                let p = (a & 0xFF) * (b & 0xFF);
+                (p >> 8, p & 0xFF)
+            },
+            OperandSize::Word => {
+                let p = a as u32 * b as u32;
+                ((p >> 16) as u16, p as u16)
+            },
+        };
+        self.set_mul_flags(hi, hi != 0, s);
+        (hi, lo)
+    }
+
+    /// Signed multiply. Returns (high, low) halves of the product.
+    pub(crate) fn alu_imul(&mut self, a:u16, b:u16, s:OperandSize)
+                           -> (u16, u16) {
+        let (hi, lo, ext) = match s {
+            OperandSize::Byte => {
+                let p = (a as u8 as i8 as i16) * (b as u8 as i8 as i16);
+                let p = p as u16;
+                (p >> 8, p & 0xFF, p != p as u8 as i8 as i16 as u16)
+            },
+            OperandSize::Word => {
+                let p = (a as i16 as i32) * (b as i16 as i32);
+                ((p >> 16) as u16, p as u16, p != p as i16 as i32)
+            },
+        };
+        self.set_mul_flags(hi, ext, s);
+        (hi, lo)
+    }
+
+    /// Unsigned divide of (hi:lo) by [d]. The arithmetic flags are undefined
+    /// and left unchanged.
+    pub(crate) fn alu_div(&mut self, hi:u16, lo:u16, d:u16, s:OperandSize)
+                          -> DivResult {
+        if d & s.mask() == 0 { return Err(CpuError::DivideByZero); }
+        let (n, d) = match s {
+            OperandSize::Byte => (((hi & 0xFF) << 8 | lo & 0xFF) as u32,
+                                  (d & 0xFF) as u32),
+            OperandSize::Word => (((hi as u32) << 16) | lo as u32, d as u32),
+        };
+        let q = n / d;
+        if q > s.mask() as u32 { return Err(CpuError::DivideOverflow); }
+        Ok((q as u16, (n % d) as u16))
+    }
+
+    /// Signed divide of (hi:lo) by [d]. The quotient may take the full range
+    /// of the destination, including 0x80 (byte) and 0x8000 (word), as the
+    /// 8088 leaves it to the negation to produce them: only quotients outside
+    /// of -128..127 and -32768..32767 raise a divide error.
+    pub(crate) fn alu_idiv(&mut self, hi:u16, lo:u16, d:u16, s:OperandSize)
+                           -> DivResult {
+        let (n, d, max) = match s {
+            OperandSize::Byte => (((hi & 0xFF) << 8 | lo & 0xFF) as i16 as i32,
+                                  d as u8 as i8 as i32, 0x7F),
+            OperandSize::Word => ((((hi as u32) << 16) | lo as u32) as i32,
+                                  d as i16 as i32, 0x7FFF),
+        };
+        if d == 0 { return Err(CpuError::DivideByZero); }
+        let q = (n as i64 / d as i64) as i32;
+        if !(-max - 1..=max).contains(&q) { return Err(CpuError::DivideOverflow); }
+        let r = (n as i64 % d as i64) as i32;
+        Ok((q as u16 & s.mask(), r as u16 & s.mask()))
+    }
+
+    pub(crate) fn alu_daa(&mut self, al:u8) -> u8 {
+        let (old_al, old_cf) = (al, self.flag(FLAG_CF));
+        let mut al = al;
+        if al & 0x0F > 9 || self.flag(FLAG_AF) {
+            al = al.wrapping_add(6);
+            self.set_flag(FLAG_AF, true);
+        } else {
+            self.set_flag(FLAG_AF, false);
+        }
+        if old_al > 0x99 || old_cf {
+            al = al.wrapping_add(0x60);
+            self.set_flag(FLAG_CF, true);
+        } else {
+            self.set_flag(FLAG_CF, false);
+        }
+        // OF is undefined, and reflects the signed overflow of the adjust.
+        self.set_flag(FLAG_OF, !old_al & al & 0x80 != 0);
+        self.set_szp(al as u16, 0x80);
+        al
+    }
+
+    pub(crate) fn alu_das(&mut self, al:u8) -> u8 {
+        let (old_al, old_cf) = (al, self.flag(FLAG_CF));
+        let mut al = al;
+        if al & 0x0F > 9 || self.flag(FLAG_AF) {
+            al = al.wrapping_sub(6);
+            self.set_flag(FLAG_AF, true);
+        } else {
+            self.set_flag(FLAG_AF, false);
+        }
+        if old_al > 0x99 || old_cf {
+            al = al.wrapping_sub(0x60);
+            self.set_flag(FLAG_CF, true);
+        } else {
+            self.set_flag(FLAG_CF, false);
+        }
+        self.set_flag(FLAG_OF, old_al & !al & 0x80 != 0);
+        self.set_szp(al as u16, 0x80);
+        al
+    }
+
+    /// ASCII adjust after addition. The 8088 adjusts AL and AH separately,
+    /// unlike later processors which add 0x106 to AX. The undefined OF, SF,
+    /// ZF and PF are those of the 8-bit addition to AL, before masking.
+    pub(crate) fn alu_aaa(&mut self, ax:u16) -> u16 {
+        let (al, mut ah) = (ax as u8, (ax >> 8) as u8);
+        let adjust = al & 0x0F > 9 || self.flag(FLAG_AF);
+        let al = self.alu_add(al as u16, if adjust { 6 } else { 0 }, false,
+                              OperandSize::Byte) as u8;
+        if adjust {
+            ah = ah.wrapping_add(1);
+        }
+        self.set_flag(FLAG_AF, adjust);
+        self.set_flag(FLAG_CF, adjust);
+        ((ah as u16) << 8) | (al & 0x0F) as u16
+    }
+
+    pub(crate) fn alu_aas(&mut self, ax:u16) -> u16 {
+        let (al, mut ah) = (ax as u8, (ax >> 8) as u8);
+        let adjust = al & 0x0F > 9 || self.flag(FLAG_AF);
+        let al = self.alu_sub(al as u16, if adjust { 6 } else { 0 }, false,
+                              OperandSize::Byte) as u8;
+        if adjust {
+            ah = ah.wrapping_sub(1);
+        }
+        self.set_flag(FLAG_AF, adjust);
+        self.set_flag(FLAG_CF, adjust);
+        ((ah as u16) << 8) | (al & 0x0F) as u16
+    }
+
+    /// ASCII adjust after multiply, with an arbitrary base. A zero base is a
+    /// divide error, returned as [CpuError::DivideByZero]. The undefined OF,
+    /// AF and CF are cleared.
+    pub(crate) fn alu_aam(&mut self, al:u8, base:u8) -> Result<u16, CpuError> {
+        if base == 0 { return Err(CpuError::DivideByZero); }
+        let (ah, al) = (al / base, al % base);
+        self.alu_logic(al as u16, OperandSize::Byte);
+        Ok(((ah as u16) << 8) | al as u16)
+    }
+
+    /// ASCII adjust before division. The 8088 performs the final step as an
+    /// 8-bit ADD, which also defines OF, AF and CF.
+    pub(crate) fn alu_aad(&mut self, ax:u16, base:u8) -> u16 {
+        let (al, ah) = (ax as u8, (ax >> 8) as u8);
+        self.alu_add(al as u16, ah.wrapping_mul(base) as u16, false,
+                     OperandSize::Byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_flags() {
+        let mut cpu = I8088::new();
+        cpu.load_flags(0x0000);
+        assert_eq!(cpu.flags, 0xF002);
+        cpu.load_flags(0xFFFF);
+        assert_eq!(cpu.flags, 0xFFD7);
+    }
+
+    #[test]
+    fn test_lazy_flags_match_eager() {
+        let mut eager = I8088::new();
+        let mut lazy = I8088::new();
+        lazy.set_flags_mode(FlagsMode::Lazy);
+        let samples = [0x0000, 0x0001, 0x000F, 0x0010, 0x007F, 0x0080,
+                       0x00FF, 0x7FFF, 0x8000, 0xFFFF];
+        for s in [OperandSize::Byte, OperandSize::Word] {
+            for &a in &samples {
+                for &b in &samples {
+                    let (a, b) = (a & s.mask(), b & s.mask());
+                    for cpu in [&mut eager, &mut lazy] {
+                        cpu.set_flag(FLAG_CF, b & 1 != 0);
+                        cpu.alu_add(a, b, true, s);
+                        cpu.alu_inc(cpu.flags() & a, s);
+                        cpu.alu_sub(a, b, cpu.flag(FLAG_AF), s);
+                        cpu.alu_dec(cpu.flags() & b, s);
+                    }
+                    assert_eq!(eager.flags(), lazy.flags());
+                    for cpu in [&mut eager, &mut lazy] {
+                        cpu.alu_logic(a ^ b, s);
+                    }
+                    assert_eq!(eager.flags(), lazy.flags());
+                }
+            }
+        }
+        assert!(lazy.lazy_flags.is_some());
+    }
+
+    #[test]
+    fn test_lazy_flags_materializations() {
+        // mov cx, 100
+        // l: add ax, bx; adc dx, 0; inc si; dec di; shl bx, 1; loop l; hlt
+        let code = [0xB9, 0x64, 0x00, 0x01, 0xD8, 0x83, 0xD2, 0x00, 0x46,
+                    0x4F, 0xD1, 0xE3, 0xE2, 0xF5, 0xF4];
+        let mut counts = Vec::new();
+        let mut regs = Vec::new();
+        for mode in [FlagsMode::Eager, FlagsMode::Lazy] {
+            let mut cpu = I8088::new();
+            cpu.set_flags_mode(mode);
+            cpu.bus.load(0x100, &code).unwrap();
+            cpu.set_ip(0x100);
+            cpu.bx = 0x9001;
+            while !cpu.halted() {
+                cpu.advance().unwrap();
+            }
+            counts.push(cpu.flags_materializations());
+            regs.push((cpu.ax, cpu.dx, cpu.si, cpu.di, cpu.flags()));
+        }
+        assert_eq!(regs[0], regs[1]);
+        assert_eq!(counts[0], 400);
+        assert_eq!(counts[1], 0);
+    }
+
+    #[test]
+    fn test_undefined_flags() {
+        let mut cpu = I8088::new();
+        cpu.set_flag(FLAG_AF, true);
+        cpu.alu_logic(0x00, OperandSize::Byte);
+        assert!(!cpu.flag(FLAG_AF));
+
+        // 0x80 * 0x02 = 0x0100: SF/ZF/PF follow the high byte
+        cpu.alu_mul(0x80, 0x02, OperandSize::Byte);
+        assert!(cpu.flag(FLAG_CF) && cpu.flag(FLAG_OF));
+        assert!(!cpu.flag(FLAG_ZF) && !cpu.flag(FLAG_PF));
+        cpu.alu_mul(0x10, 0x02, OperandSize::Byte);
+        assert!(cpu.flag(FLAG_ZF) && cpu.flag(FLAG_PF));
+
+        // AAA with AL=0x7A: the adjust overflows into the sign bit
+        cpu.set_flag(FLAG_AF, false);
+        assert_eq!(cpu.alu_aaa(0x007A), 0x0100);
+        assert!(cpu.flag(FLAG_OF) && cpu.flag(FLAG_SF) && cpu.flag(FLAG_CF));
+
+        // SHL sets AF from bit 4 of the result
+        cpu.alu_shift(Mnemonic::SHL, 0x08, 1, OperandSize::Byte);
+        assert!(cpu.flag(FLAG_AF));
+    }
+
+    #[test]
+    fn test_divide_errors() {
+        let mut cpu = I8088::new();
+        let (b, w) = (OperandSize::Byte, OperandSize::Word);
+        assert!(matches!(cpu.alu_div(0, 0x10, 0x100, b),
+            Err(CpuError::DivideByZero)));
+        assert!(matches!(cpu.alu_div(0x10, 0x00, 0x10, b),
+            Err(CpuError::DivideOverflow)));
+
+        // -128 / 1 and -32768 / 1 fit, one step further does not.
+        assert!(matches!(cpu.alu_idiv(0xFF, 0x80, 0x01, b),
+            Ok((0x80, 0x00))));
+        assert!(matches!(cpu.alu_idiv(0xFFFF, 0x8000, 0x0001, w),
+            Ok((0x8000, 0x0000))));
+        assert!(matches!(cpu.alu_idiv(0x00, 0x80, 0x01, b),
+            Err(CpuError::DivideOverflow)));
+        assert!(matches!(cpu.alu_idiv(0xFFFF, 0x7FFF, 0x0001, w),
+            Err(CpuError::DivideOverflow)));
+        assert!(matches!(cpu.alu_idiv(0xFF, 0x81, 0x01, b),
+            Ok((0x81, 0x00))));
+        // -7 / 2 truncates towards zero, the remainder takes the dividend sign.
+        assert!(matches!(cpu.alu_idiv(0xFF, 0xF9, 0x02, b),
+            Ok((0xFD, 0xFF))));
+
+        assert!(matches!(cpu.alu_aam(0x10, 0), Err(CpuError::DivideByZero)));
+    }
+}
@@ -0,0 +1,879 @@
+use std::{collections::HashMap, fmt};
+use crate::{
+    core::bus::BusInterface,
+    cpu::{
+        addr::Segment,
+        decode::{Form, OperandSize, Spec, forms},
+        mnemonic::Mnemonic::{self, *},
+        register::Register,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    Syntax(String),
+    UnknownMnemonic(String),
+    InvalidOperands(String),
+    AmbiguousSize(String),
+    ImmediateRange(i32),
+    BranchRange(i32),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    OutOfBounds(u32),
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmError::Syntax(s) => write!(f, "Syntax error: {}.", s),
+            AsmError::UnknownMnemonic(m) => write!(f, "Unknown mnemonic '{}'.", m),
+            AsmError::InvalidOperands(s) => {
+                write!(f, "No encoding of '{}' takes these operands.", s)
+            },
+            AsmError::AmbiguousSize(s) => {
+                write!(f, "Operand size of '{}' must be given as byte or word.", s)
+            },
+            AsmError::ImmediateRange(v) => {
+                write!(f, "Immediate value {} does not fit the operand.", v)
+            },
+            AsmError::BranchRange(d) => {
+                write!(f, "Branch target is {} bytes away, out of range of a short jump.", d)
+            },
+            AsmError::UndefinedLabel(l) => write!(f, "Undefined label '{}'.", l),
+            AsmError::DuplicateLabel(l) => write!(f, "Label '{}' is already defined.", l),
+            AsmError::OutOfBounds(a) => write!(f, "Address {:05X} is outside of memory.", a),
+        }
+    }
+}
+
+/// A constant, optionally relative to a label. `$` is the address of the
+/// instruction being assembled.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Value {
+    label:Option<String>,
+    offset:i32,
+}
+
+#[derive(Clone, Debug)]
+struct Memory {
+    size:Option<OperandSize>,
+    segment:Option<Segment>,
+    base:Option<u8>, /* ModR/M r/m, or None for a direct address */
+    disp:Value,
+}
+
+#[derive(Clone, Debug)]
+enum Arg {
+    Reg(Register),
+    Mem(Memory),
+    Imm(Value),
+    Far(u16, u16),
+}
+
+#[derive(Clone, Debug)]
+struct Statement {
+    prefixes:Vec<u8>,
+    mnemonic:Mnemonic,
+    args:Vec<Arg>,
+    far:bool,
+    short:bool,
+}
+
+enum Body {
+    Empty,
+    Data(Vec<u8>),
+    Instruction(Statement),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FixupKind {
+    Abs16,
+    Rel8(u16),  /* offset of the next instruction */
+    Rel16(u16),
+}
+
+/// A reference to a label that was not yet defined when it was assembled.
+#[derive(Clone, Debug)]
+struct Fixup {
+    label:String,
+    addend:i32,
+    segment:u16,
+    offset:u16,
+    kind:FixupKind,
+}
+
+/// Field following the opcode and ModR/M byte.
+enum Field {
+    Byte(i32),
+    Word(Value),
+    Rel8(Value),
+    Rel16(Value),
+    Far(u16, u16),
+}
+
+/// Why a form does not encode a statement.
+enum Miss {
+    Operands,
+    Immediate(i32),
+    Branch(i32),
+}
+
+struct Encoding {
+    bytes:Vec<u8>,
+    fixups:Vec<Fixup>,
+    size:OperandSize,
+    /* Operand size taken from the form alone, for unsized memory or immediates */
+    implied:bool,
+    /* Shortest first; forward short branches and undocumented aliases last */
+    rank:(bool, usize, bool),
+}
+
+/// Mini-assembler for Intel syntax 8088 source, in the manner of DEBUG's `A`
+/// command. Each line is encoded using the decoder's opcode map and written
+/// into memory at the current address, which then advances past it. Labels
+/// are local to one assembler; forward references are patched in memory
+/// when the label is defined.
+pub struct Assembler {
+    segment:u16,
+    offset:u16,
+    labels:HashMap<String, u16>,
+    fixups:Vec<Fixup>,
+}
+
+impl Assembler {
+    pub fn new(segment:u16, offset:u16) -> Self {
+        Self { segment, offset, labels:HashMap::new(), fixups:Vec::new() }
+    }
+
+    /// Segment and offset the next line is assembled at.
+    pub fn address(&self) -> (u16, u16) {
+        (self.segment, self.offset)
+    }
+
+    /// Moves the assembly point. Labels stay defined.
+    pub fn set_address(&mut self, segment:u16, offset:u16) {
+        self.segment = segment;
+        self.offset = offset;
+    }
+
+    pub fn label(&self, name:&str) -> Option<u16> {
+        self.labels.get(&name.to_lowercase()).copied()
+    }
+
+    /// Assembles one line, of the form `[label:] [prefixes] mnemonic
+    /// [operands] [; comment]`, and writes it into memory. Returns the bytes
+    /// written. Nothing is written or defined if the line has an error.
+    pub fn assemble(&mut self, bus:&mut BusInterface, line:&str)
+                    -> Result<Vec<u8>, AsmError> {
+        let (label, body) = parse_line(line)?;
+        let at = self.offset;
+
+        if let Some(l) = &label {
+            if self.labels.contains_key(l) {
+                return Err(AsmError::DuplicateLabel(l.clone()));
+            }
+            self.labels.insert(l.clone(), at);
+        }
+        let result = match body {
+            Body::Empty => Ok((Vec::new(), Vec::new())),
+            Body::Data(bytes) => Ok((bytes, Vec::new())),
+            Body::Instruction(s) => self.encode(&s, at, line.trim()),
+        }.and_then(|r| {
+            // Forward references to this label are resolved before anything
+            // is committed, so a short branch out of range is reported here.
+            let patches = match &label {
+                Some(l) => self.resolve(l, at)?,
+                None => Vec::new(),
+            };
+            Ok((r, patches))
+        });
+        let ((bytes, fixups), patches) = match result {
+            Ok(r) => r,
+            Err(e) => {
+                if let Some(l) = &label { self.labels.remove(l); }
+                return Err(e);
+            },
+        };
+        for (segment, offset, data) in patches {
+            write(bus, segment, offset, &data)?;
+        }
+        write(bus, self.segment, at, &bytes)?;
+        if let Some(l) = &label {
+            self.fixups.retain(|f| &f.label != l);
+        }
+        self.fixups.extend(fixups);
+        self.offset = at.wrapping_add(bytes.len() as u16);
+        Ok(bytes)
+    }
+
+    /// Ends the session, reporting the first label that was referenced but
+    /// never defined.
+    pub fn finish(&self) -> Result<(), AsmError> {
+        match self.fixups.first() {
+            Some(f) => Err(AsmError::UndefinedLabel(f.label.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Bytes to patch into pending references to [label], defined at [addr].
+    fn resolve(&self, label:&str, addr:u16) -> Result<Vec<(u16, u16, Vec<u8>)>, AsmError> {
+        self.fixups.iter().filter(|f| f.label == label).map(|f| {
+            let addr = addr.wrapping_add(f.addend as u16);
+            let data = match f.kind {
+                FixupKind::Abs16 => addr.to_le_bytes().to_vec(),
+                FixupKind::Rel8(end) => {
+                    let d = addr.wrapping_sub(end) as i16;
+                    if !(-128..=127).contains(&d) {
+                        return Err(AsmError::BranchRange(d as i32));
+                    }
+                    vec![d as u8]
+                },
+                FixupKind::Rel16(end) => addr.wrapping_sub(end).to_le_bytes().to_vec(),
+            };
+            Ok((f.segment, f.offset, data))
+        }).collect()
+    }
+
+    fn value(&self, v:&Value, at:u16) -> Option<i32> {
+        match v.label.as_deref() {
+            None => Some(v.offset),
+            Some("$") => Some(at as i32 + v.offset),
+            Some(l) => self.labels.get(l).map(|a| *a as i32 + v.offset),
+        }
+    }
+
+    /// Picks the best form of the opcode map for [s].
+    fn encode(&self, s:&Statement, at:u16, text:&str)
+              -> Result<(Vec<u8>, Vec<Fixup>), AsmError> {
+        let mut found:Vec<Encoding> = Vec::new();
+        let mut miss = None;
+        for form in forms().filter(|f| f.mnemonic == s.mnemonic) {
+            match self.encode_form(&form, s, at) {
+                Ok(e) => found.push(e),
+                Err(Miss::Operands) => {},
+                Err(m) => { miss.get_or_insert(m); },
+            }
+        }
+
+        let implied:Vec<_> = found.iter().filter(|e| e.implied).collect();
+        if implied.iter().any(|e| e.size != implied[0].size) {
+            return Err(AsmError::AmbiguousSize(text.to_string()));
+        }
+        match found.into_iter().min_by_key(|e| e.rank) {
+            Some(e) => Ok((e.bytes, e.fixups)),
+            None => Err(match miss {
+                Some(Miss::Immediate(v)) => AsmError::ImmediateRange(v),
+                Some(Miss::Branch(d)) => AsmError::BranchRange(d),
+                _ => AsmError::InvalidOperands(text.to_string()),
+            }),
+        }
+    }
+
+    fn encode_form(&self, form:&Form, s:&Statement, at:u16) -> Result<Encoding, Miss> {
+        let specs:Vec<Spec> = [form.dst, form.src].into_iter()
+            .filter(|sp| *sp != Spec::N)
+            .collect();
+        if specs.len() != s.args.len() || form.far != s.far {
+            return Err(Miss::Operands);
+        }
+
+        let word = form.size == OperandSize::Word;
+        let fits = |r:&Register| r.segment().is_none() && r.is_8bit() != word;
+        let mut implied = false;
+        let mut pinned = false;
+        let mut reg = form.group.unwrap_or(0);
+        let mut rm:Option<(u8, Option<Field>)> = None;
+        let mut segment = None;
+        let mut fields = Vec::new();
+
+        for (spec, arg) in specs.iter().zip(&s.args) {
+            match (spec, arg) {
+                (Spec::E, Arg::Reg(r)) if fits(r) => {
+                    rm = Some((0xC0 | r.index(), None));
+                    pinned = true;
+                },
+                (Spec::E, Arg::Mem(m)) => {
+                    match m.size {
+                        Some(sz) if sz != form.size => return Err(Miss::Operands),
+                        Some(_) => pinned = true,
+                        None => implied = true,
+                    }
+                    rm = Some(self.modrm(m, at));
+                    segment = m.segment;
+                },
+                (Spec::G, Arg::Reg(r)) if fits(r) => { reg = r.index(); pinned = true; },
+                (Spec::S, Arg::Reg(r)) if r.segment().is_some() => reg = r.index(),
+                (Spec::A, Arg::Reg(r)) if fits(r) && r.index() == 0 => pinned = true,
+                (Spec::Z, Arg::Reg(r)) if fits(r) && r.index() == form.opcode & 7 => {
+                    pinned = true;
+                },
+                (Spec::F(f), Arg::Reg(r)) if f == r => pinned |= r.segment().is_none(),
+                (Spec::K(k), Arg::Imm(v)) if self.value(v, at) == Some(*k as i32) => {},
+                (Spec::I, Arg::Imm(v)) if word => {
+                    implied = true;
+                    fields.push(self.word(v, at)?);
+                },
+                (Spec::I | Spec::Ib, Arg::Imm(v)) => {
+                    implied |= *spec == Spec::I;
+                    match self.value(v, at) {
+                        Some(n) if (-128..=255).contains(&n) => fields.push(Field::Byte(n)),
+                        Some(n) => return Err(Miss::Immediate(n)),
+                        None => return Err(Miss::Operands),
+                    }
+                },
+                (Spec::Is, Arg::Imm(v)) => {
+                    implied = true;
+                    match self.value(v, at) {
+                        Some(n) if (-128..=127).contains(&(n as u16 as i16))
+                            && (-32768..=65535).contains(&n) => fields.push(Field::Byte(n)),
+                        _ => return Err(Miss::Operands),
+                    }
+                },
+                (Spec::Iw, Arg::Imm(v)) => fields.push(self.word(v, at)?),
+                (Spec::J8, Arg::Imm(v)) => fields.push(Field::Rel8(v.clone())),
+                (Spec::J16, Arg::Imm(v)) if !s.short => fields.push(Field::Rel16(v.clone())),
+                (Spec::Ap, Arg::Far(sg, o)) => fields.push(Field::Far(*sg, *o)),
+                (Spec::O, Arg::Mem(m)) if m.base.is_none() => {
+                    match m.size {
+                        Some(sz) if sz != form.size => return Err(Miss::Operands),
+                        Some(_) => pinned = true,
+                        None => implied = true,
+                    }
+                    fields.push(Field::Word(m.disp.clone()));
+                    segment = m.segment;
+                },
+                _ => return Err(Miss::Operands),
+            }
+        }
+
+        let mut bytes = s.prefixes.clone();
+        if let Some(sg) = segment {
+            bytes.push(match sg {
+                Segment::ES => 0x26,
+                Segment::CS => 0x2E,
+                Segment::SS => 0x36,
+                Segment::DS => 0x3E,
+            });
+        }
+        bytes.push(form.opcode);
+        if let Some((modrm, disp)) = rm {
+            bytes.push(modrm | reg << 3);
+            if let Some(d) = disp { fields.insert(0, d); }
+        }
+        else if form.has_modrm() {
+            return Err(Miss::Operands);
+        }
+
+        let len:usize = bytes.len() + fields.iter().map(|f| match f {
+            Field::Byte(_) | Field::Rel8(_) => 1,
+            Field::Word(_) | Field::Rel16(_) => 2,
+            Field::Far(..) => 4,
+        }).sum::<usize>();
+        let end = at.wrapping_add(len as u16);
+
+        let mut fixups = Vec::new();
+        let mut forward = false;
+        for f in fields {
+            let pos = at.wrapping_add(bytes.len() as u16);
+            let mut fixup = |v:&Value, kind| fixups.push(Fixup {
+                label:v.label.clone().unwrap_or_default(),
+                addend:v.offset,
+                segment:self.segment,
+                offset:pos,
+                kind,
+            });
+            match f {
+                Field::Byte(n) => bytes.push(n as u8),
+                Field::Word(v) => {
+                    let n = self.value(&v, at).unwrap_or_else(|| {
+                        fixup(&v, FixupKind::Abs16);
+                        0
+                    });
+                    bytes.extend((n as u16).to_le_bytes());
+                },
+                Field::Rel8(v) => match self.value(&v, at) {
+                    Some(t) => {
+                        let d = (t as u16).wrapping_sub(end) as i16;
+                        if !(-128..=127).contains(&d) { return Err(Miss::Branch(d as i32)); }
+                        bytes.push(d as u8);
+                    },
+                    None => {
+                        fixup(&v, FixupKind::Rel8(end));
+                        forward = !s.short;
+                        bytes.push(0);
+                    },
+                },
+                Field::Rel16(v) => {
+                    let d = self.value(&v, at).map(|t| (t as u16).wrapping_sub(end))
+                        .unwrap_or_else(|| { fixup(&v, FixupKind::Rel16(end)); 0 });
+                    bytes.extend(d.to_le_bytes());
+                },
+                Field::Far(sg, o) => {
+                    bytes.extend(o.to_le_bytes());
+                    bytes.extend(sg.to_le_bytes());
+                },
+            }
+        }
+
+        Ok(Encoding {
+            rank:(forward, bytes.len(), alias(form)),
+            bytes,
+            fixups,
+            size:form.size,
+            implied:implied && !pinned,
+        })
+    }
+
+    fn word(&self, v:&Value, at:u16) -> Result<Field, Miss> {
+        match self.value(v, at) {
+            Some(n) if !(-32768..=65535).contains(&n) => Err(Miss::Immediate(n)),
+            _ => Ok(Field::Word(v.clone())),
+        }
+    }
+
+    /// ModR/M mode and r/m bits for a memory operand, with the displacement
+    /// field that follows. Forward references take a 16-bit displacement.
+    fn modrm(&self, m:&Memory, at:u16) -> (u8, Option<Field>) {
+        let Some(base) = m.base else {
+            return (0b110, Some(Field::Word(m.disp.clone())));
+        };
+        match self.value(&m.disp, at).map(|n| n as u16) {
+            Some(0) if base != 0b110 => (base, None),
+            Some(n) if (-128..=127).contains(&(n as i16)) => {
+                (0b01 << 6 | base, Some(Field::Byte(n as i32)))
+            },
+            _ => (0b10 << 6 | base, Some(Field::Word(m.disp.clone()))),
+        }
+    }
+}
+
+/// Undocumented aliases in the opcode map, whole opcodes or members of a
+/// group, which only assemble when spelled out in bytes.
+fn alias(form:&Form) -> bool {
+    matches!((form.opcode, form.group),
+        (0x60..=0x6F | 0xC0 | 0xC1 | 0xC8 | 0xC9 | 0x82, _)
+        | (0xF6 | 0xF7, Some(1)) | (0xFF, Some(7)) | (0xD0..=0xD3, Some(6)))
+}
+
+fn write(bus:&mut BusInterface, segment:u16, offset:u16, data:&[u8]) -> Result<(), AsmError> {
+    for (i, b) in data.iter().enumerate() {
+        let addr = ((segment as u32) << 4).wrapping_add(offset.wrapping_add(i as u16) as u32)
+            & 0xFFFFF;
+        bus.load(addr as usize, &[*b]).map_err(|_| AsmError::OutOfBounds(addr))?;
+    }
+    Ok(())
+}
+
+fn syntax(s:&str) -> AsmError {
+    AsmError::Syntax(s.to_string())
+}
+
+fn is_identifier(s:&str) -> bool {
+    let mut c = s.chars();
+    matches!(c.next(), Some(ch) if ch.is_ascii_alphabetic() || "_.@".contains(ch))
+        && c.all(|ch| ch.is_ascii_alphanumeric() || "_.@$".contains(ch))
+}
+
+fn segment(s:&str) -> Option<Segment> {
+    match s {
+        "es" => Some(Segment::ES),
+        "cs" => Some(Segment::CS),
+        "ss" => Some(Segment::SS),
+        "ds" => Some(Segment::DS),
+        _ => None,
+    }
+}
+
+fn register(s:&str) -> Option<Register> {
+    (0..8).flat_map(|i| [Register::reg8(i), Register::reg16(i)])
+        .chain((0..4).map(Register::sreg))
+        .find(|r| r.to_string() == s)
+}
+
+fn mnemonic(s:&str) -> Option<(Mnemonic, bool)> {
+    let m = match s {
+        "je" => JZ,
+        "jne" => JNZ,
+        "jc" | "jnae" => JB,
+        "jnc" | "jae" => JNB,
+        "jna" => JBE,
+        "jnbe" => JA,
+        "jpe" => JP,
+        "jpo" => JNP,
+        "jnge" => JL,
+        "jge" => JNL,
+        "jng" => JLE,
+        "jnle" => JG,
+        "loopz" => LOOPE,
+        "loopnz" => LOOPNE,
+        "sal" => SHL,
+        "xlatb" => XLAT,
+        "retn" => RET,
+        // RETF is the only mnemonic whose forms are all inter-segment.
+        _ => return forms().map(|f| f.mnemonic)
+            .find(|m| m.to_string() == s)
+            .map(|m| (m, m == RETF)),
+    };
+    Some((m, false))
+}
+
+/// Lower-cases everything outside of quotes and strips the comment.
+fn normalize(line:&str) -> String {
+    let mut out = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match quote {
+            Some(q) => { if c == q { quote = None; } out.push(c); },
+            None if c == ';' => break,
+            None => {
+                if c == '\'' || c == '"' { quote = Some(c); }
+                out.push(c.to_ascii_lowercase());
+            },
+        }
+    }
+    out
+}
+
+/// Splits at commas outside of brackets and quotes.
+fn split_operands(s:&str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let (mut start, mut depth, mut quote) = (0, 0, None);
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, ',') if depth == 0 => { out.push(s[start..i].trim()); start = i + 1; },
+            _ => {},
+        }
+    }
+    out.push(s[start..].trim());
+    out
+}
+
+fn parse_number(s:&str) -> Option<i32> {
+    let n = if let Some(h) = s.strip_prefix("0x") {
+        i64::from_str_radix(h, 16).ok()?
+    }
+    else if let Some(h) = s.strip_suffix('h') {
+        if !s.starts_with(|c:char| c.is_ascii_digit()) { return None; }
+        i64::from_str_radix(h, 16).ok()?
+    }
+    else if s.len() == 3 && (s.starts_with('\'') && s.ends_with('\'')
+                             || s.starts_with('"') && s.ends_with('"')) {
+        s.as_bytes()[1] as i64
+    }
+    else {
+        s.parse::<i64>().ok()?
+    };
+    i32::try_from(n).ok()
+}
+
+/// Parses `term (+|- term)*`. Terms that are not numbers or labels are
+/// passed to [other], for the registers of a memory operand.
+fn parse_value<F>(s:&str, mut other:F) -> Result<Value, AsmError>
+    where F:FnMut(&str, bool) -> Result<(), AsmError> {
+    let mut v = Value::default();
+    let mut rest = s.trim();
+    let mut negative = false;
+    if let Some(r) = rest.strip_prefix('-') { negative = true; rest = r.trim_start(); }
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        if let Some(n) = parse_number(term) {
+            v.offset = v.offset.wrapping_add(if negative { -n } else { n });
+        }
+        else if (term == "$" || is_identifier(term)) && register(term).is_none() {
+            if negative || v.label.is_some() {
+                return Err(syntax(&format!("label '{}' must be added once", term)));
+            }
+            v.label = Some(term.to_string());
+        }
+        else if term.is_empty() {
+            return Err(syntax(&format!("missing term in '{}'", s)));
+        }
+        else {
+            other(term, negative)?;
+        }
+        if end == rest.len() { break; }
+        negative = rest.as_bytes()[end] == b'-';
+        rest = rest[end + 1..].trim_start();
+    }
+    Ok(v)
+}
+
+fn parse_memory(s:&str, size:Option<OperandSize>, mut segment:Option<Segment>)
+                -> Result<Memory, AsmError> {
+    let mut inner = s.trim();
+    if let Some((sg, rest)) = inner.split_once(':') {
+        segment = Some(self::segment(sg.trim())
+            .ok_or_else(|| syntax(&format!("bad segment in '[{}]'", s)))?);
+        inner = rest;
+    }
+    let mut regs = Vec::new();
+    let disp = parse_value(inner, |term, negative| match register(term) {
+        Some(r) if !negative => { regs.push(r); Ok(()) },
+        _ => Err(syntax(&format!("bad address '[{}]'", s))),
+    })?;
+    regs.sort_by_key(|r| r.index());
+    use Register::*;
+    let base = match regs.as_slice() {
+        [] => None,
+        [BX, SI] => Some(0b000),
+        [BX, DI] => Some(0b001),
+        [BP, SI] => Some(0b010),
+        [BP, DI] => Some(0b011),
+        [SI] => Some(0b100),
+        [DI] => Some(0b101),
+        [BP] => Some(0b110),
+        [BX] => Some(0b111),
+        _ => return Err(syntax(&format!("bad address '[{}]'", s))),
+    };
+    Ok(Memory { size, segment, base, disp })
+}
+
+fn parse_operand(s:&str, far:&mut bool, short:&mut bool) -> Result<Arg, AsmError> {
+    let mut rest = s;
+    let mut size = None;
+    loop {
+        let (word, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        match word {
+            "byte" => size = Some(OperandSize::Byte),
+            "word" => size = Some(OperandSize::Word),
+            "far" => *far = true,
+            "short" => *short = true,
+            "ptr" | "near" => {},
+            _ => break,
+        }
+        rest = tail.trim_start();
+    }
+
+    let mut seg = None;
+    if let Some((sg, tail)) = rest.split_once(':') {
+        if let (Some(sg), true) = (segment(sg.trim()), tail.trim_start().starts_with('[')) {
+            seg = Some(sg);
+            rest = tail.trim_start();
+        }
+    }
+    if let Some(inner) = rest.strip_prefix('[') {
+        let inner = inner.strip_suffix(']')
+            .ok_or_else(|| syntax(&format!("unclosed '[' in '{}'", s)))?;
+        return Ok(Arg::Mem(parse_memory(inner, size, seg)?));
+    }
+    if size.is_some() {
+        return Err(syntax(&format!("size given for non-memory operand '{}'", s)));
+    }
+    if let Some(r) = register(rest) {
+        return Ok(Arg::Reg(r));
+    }
+    if let Some((sg, o)) = rest.split_once(':') {
+        return match (parse_number(sg.trim()), parse_number(o.trim())) {
+            (Some(sg), Some(o)) if (0..=0xFFFF).contains(&sg) && (0..=0xFFFF).contains(&o) => {
+                *far = true;
+                Ok(Arg::Far(sg as u16, o as u16))
+            },
+            _ => Err(syntax(&format!("bad far address '{}'", s))),
+        };
+    }
+    parse_value(rest, |term, _| Err(syntax(&format!("unexpected '{}'", term)))).map(Arg::Imm)
+}
+
+fn parse_data(s:&str) -> Result<Vec<u8>, AsmError> {
+    let mut out = Vec::new();
+    for item in split_operands(s) {
+        let quoted = item.len() >= 2 && (item.starts_with('\'') && item.ends_with('\'')
+                                         || item.starts_with('"') && item.ends_with('"'));
+        if quoted {
+            out.extend(item[1..item.len() - 1].bytes());
+            continue;
+        }
+        match parse_number(item) {
+            Some(n) if (-128..=255).contains(&n) => out.push(n as u8),
+            Some(n) => return Err(AsmError::ImmediateRange(n)),
+            None => return Err(syntax(&format!("bad data '{}'", item))),
+        }
+    }
+    Ok(out)
+}
+
+fn parse_line(line:&str) -> Result<(Option<String>, Body), AsmError> {
+    let line = normalize(line);
+    let mut rest = line.trim();
+    let mut label = None;
+
+    let next = |s:&str| -> (String, String) {
+        let (w, t) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        (w.to_string(), t.trim_start().to_string())
+    };
+
+    let (word, _) = next(rest);
+    if let Some(l) = word.strip_suffix(':') {
+        if segment(l).is_none() {
+            if !is_identifier(l) {
+                return Err(syntax(&format!("bad label '{}'", l)));
+            }
+            label = Some(l.to_string());
+            rest = rest[word.len()..].trim_start();
+        }
+    }
+
+    let mut prefixes = Vec::new();
+    let mut owned = rest.to_string();
+    let (mnemonic, operands) = loop {
+        let (word, tail) = next(&owned);
+        if word.is_empty() {
+            // Bare prefixes are emitted as bytes, as DEBUG does.
+            let body = if prefixes.is_empty() { Body::Empty } else { Body::Data(prefixes) };
+            return Ok((label, body));
+        }
+        let prefix = match word.as_str() {
+            "lock" => 0xF0,
+            "rep" | "repe" | "repz" => 0xF3,
+            "repne" | "repnz" => 0xF2,
+            "es:" => 0x26,
+            "cs:" => 0x2E,
+            "ss:" => 0x36,
+            "ds:" => 0x3E,
+            _ => break (word, tail),
+        };
+        prefixes.push(prefix);
+        owned = tail;
+    };
+
+    if mnemonic == "db" {
+        if !prefixes.is_empty() { return Err(syntax("prefix before data")); }
+        return Ok((label, Body::Data(parse_data(&operands)?)));
+    }
+
+    let mut args = Vec::new();
+    let (m, mut far) = match mnemonic.as_str() {
+        "int3" => {
+            args.push(Arg::Imm(Value { label:None, offset:3 }));
+            (INT, false)
+        },
+        w => self::mnemonic(w).ok_or_else(|| AsmError::UnknownMnemonic(w.to_string()))?,
+    };
+    let mut short = false;
+    if !operands.is_empty() {
+        for op in split_operands(&operands) {
+            args.push(parse_operand(op, &mut far, &mut short)?);
+        }
+    }
+    // AAM and AAD default to base 10.
+    if matches!(m, AAM | AAD) && args.is_empty() {
+        args.push(Arg::Imm(Value { label:None, offset:10 }));
+    }
+    Ok((label, Body::Instruction(Statement { prefixes, mnemonic:m, args, far, short })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CpuModel, disasm::disassemble};
+
+    fn asm(line:&str) -> Result<Vec<u8>, AsmError> {
+        let mut bus = BusInterface::new();
+        Assembler::new(0, 0x100).assemble(&mut bus, line)
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(asm("mov ax, 0x1234"), Ok(vec![0xB8, 0x34, 0x12]));
+        assert_eq!(asm("MOV word ptr [bp+si-4], 1234h"),
+                   Ok(vec![0xC7, 0x42, 0xFC, 0x34, 0x12]));
+        assert_eq!(asm("mov al, [0x10]"), Ok(vec![0xA0, 0x10, 0x00]));
+        assert_eq!(asm("mov ax, es:[bx]"), Ok(vec![0x26, 0x8B, 0x07]));
+        assert_eq!(asm("mov ds, ax"), Ok(vec![0x8E, 0xD8]));
+        assert_eq!(asm("add word [bx], 1"), Ok(vec![0x83, 0x07, 0x01]));
+        assert_eq!(asm("add ax, 0x100"), Ok(vec![0x05, 0x00, 0x01]));
+        assert_eq!(asm("mov [bp], al"), Ok(vec![0x88, 0x46, 0x00]));
+        assert_eq!(asm("shl byte [bx], cl"), Ok(vec![0xD2, 0x27]));
+        assert_eq!(asm("shl ax, 1"), Ok(vec![0xD1, 0xE0]));
+        assert_eq!(asm("in al, dx"), Ok(vec![0xEC]));
+        assert_eq!(asm("out 0x20, al"), Ok(vec![0xE6, 0x20]));
+        assert_eq!(asm("int3"), Ok(vec![0xCC]));
+        assert_eq!(asm("int 3"), Ok(vec![0xCC]));
+        assert_eq!(asm("int 0x21"), Ok(vec![0xCD, 0x21]));
+        assert_eq!(asm("aam"), Ok(vec![0xD4, 0x0A]));
+        assert_eq!(asm("es: rep movsb"), Ok(vec![0x26, 0xF3, 0xA4]));
+        assert_eq!(asm("retf"), Ok(vec![0xCB]));
+        assert_eq!(asm("ret 4"), Ok(vec![0xC2, 0x04, 0x00]));
+        assert_eq!(asm("jmp 0xf000:0xe05b"), Ok(vec![0xEA, 0x5B, 0xE0, 0x00, 0xF0]));
+        assert_eq!(asm("jmp far [bx+0x10]"), Ok(vec![0xFF, 0x6F, 0x10]));
+        assert_eq!(asm("call [bx]"), Ok(vec![0xFF, 0x17]));
+        // The documented encodings win over the group aliases.
+        assert_eq!(asm("test word [bx], 1"), Ok(vec![0xF7, 0x07, 0x01, 0x00]));
+        assert_eq!(asm("push word [bx]"), Ok(vec![0xFF, 0x37]));
+        let aliases:Vec<_> = forms().filter(alias)
+            .filter(|f| f.group.is_some() && f.opcode != 0x82)
+            .map(|f| (f.opcode, f.group.unwrap()))
+            .collect();
+        assert_eq!(aliases, [(0xD0, 6), (0xD1, 6), (0xD2, 6), (0xD3, 6),
+                             (0xF6, 1), (0xF7, 1), (0xFF, 7)]);
+        assert_eq!(asm("jmp $"), Ok(vec![0xEB, 0xFE]));
+        assert_eq!(asm("je $+0x12"), Ok(vec![0x74, 0x10]));
+        assert_eq!(asm("call 0x200"), Ok(vec![0xE8, 0xFD, 0x00]));
+        assert_eq!(asm("db 1, 'ab', 0xff"), Ok(vec![0x01, b'a', b'b', 0xFF]));
+        assert_eq!(asm("  ; comment only"), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(asm("mov [bx], 5"), Err(AsmError::AmbiguousSize("mov [bx], 5".into())));
+        assert_eq!(asm("inc [bx]"), Err(AsmError::AmbiguousSize("inc [bx]".into())));
+        assert_eq!(asm("mov al, 0x100"), Err(AsmError::ImmediateRange(0x100)));
+        assert_eq!(asm("jz $+0x200"), Err(AsmError::BranchRange(0x1FE)));
+        assert_eq!(asm("jmp short $+0x200"), Err(AsmError::BranchRange(0x1FE)));
+        assert_eq!(asm("frob ax"), Err(AsmError::UnknownMnemonic("frob".into())));
+        assert_eq!(asm("mov al, bx"), Err(AsmError::InvalidOperands("mov al, bx".into())));
+        assert_eq!(asm("mov byte ax, 1"),
+                   Err(AsmError::Syntax("size given for non-memory operand 'byte ax'".into())));
+        assert_eq!(asm("mov ax, [bx+cx]"), Err(AsmError::Syntax("bad address '[bx+cx]'".into())));
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut bus = BusInterface::new();
+        let mut a = Assembler::new(0x1000, 0x100);
+        for line in ["start: mov cx, count+1", "again: call sub", "loop again",
+                     "jmp done", "sub: ret", "done: jmp start"] {
+            a.assemble(&mut bus, line).unwrap();
+        }
+        assert_eq!(a.finish(), Err(AsmError::UndefinedLabel("count".into())));
+        a.assemble(&mut bus, "count: db 0").unwrap();
+        assert_eq!(a.finish(), Ok(()));
+        assert_eq!(a.assemble(&mut bus, "sub: nop"),
+                   Err(AsmError::DuplicateLabel("sub".into())));
+        assert_eq!(a.label("SUB"), Some(0x10B));
+
+        let text:Vec<String> = disassemble(&bus, CpuModel::I8088, 0x1000, 0x100, 6).iter()
+            .map(|d| d.text()).collect();
+        assert_eq!(text, ["mov cx, 0x10f", "call 0x010b", "loop 0x0103",
+                          "jmp 0x010c", "ret", "jmp short 0x0100"]);
+
+        // A forward short branch must be in range once its label is known.
+        let mut a = Assembler::new(0, 0);
+        a.assemble(&mut bus, "jz far_away").unwrap();
+        a.set_address(0, 0x200);
+        assert_eq!(a.assemble(&mut bus, "far_away: nop"), Err(AsmError::BranchRange(0x1FE)));
+        assert_eq!(a.label("far_away"), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let lines = ["mov word [bp+si-0x4], 0x1234", "lock xchg [0x100], bl",
+                     "mov ax, [es:bx]", "repe cmpsb", "shl byte [bx], cl",
+                     "jmp far [bx+0x10]", "test byte [di+0x80], 0x1", "xlatb",
+                     "les di, [bx]", "salc", "pop cs"];
+        let mut bus = BusInterface::new();
+        let mut a = Assembler::new(0x2000, 0);
+        for line in lines {
+            a.assemble(&mut bus, line).unwrap();
+        }
+        let text:Vec<String> = disassemble(&bus, CpuModel::I8088, 0x2000, 0, lines.len()).iter()
+            .map(|d| d.text()).collect();
+        assert_eq!(text, lines);
+    }
+}
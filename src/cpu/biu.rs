@@ -0,0 +1,158 @@
+use crate::{
+    cpu::{I8088, addr::Segment, cycle::BusStatus, decode::Instruction},
+    ext::queue::Queue,
+};
+
+/// [I8088::ip] from the fetch pointer [pc], the number of bytes [queued],
+/// the REP in progress and the code bytes taken by a stalled instruction.
+pub(crate) fn instruction_pointer(pc:u16, queued:usize, rep:Option<Instruction>,
+                                  code_pending:u16) -> u16 {
+    let rep = rep.map_or(0, |ins| ins.len);
+    pc.wrapping_sub(queued as u16)
+        .wrapping_sub(rep)
+        .wrapping_sub(code_pending)
+}
+
+impl I8088 {
+    /// Address of the instruction being executed. The BIU runs ahead of the
+    /// EU, so this is the fetch pointer minus the bytes still queued. While a
+    /// repeated string instruction is in progress, this is its first prefix.
+    /// Code bytes the EU took out of the queue for an instruction stalled by
+    /// [cycle] still count as part of it.
+    pub fn ip(&self) -> u16 {
+        instruction_pointer(self.pc, self.prefetch_queue.size(), self.rep, self.code_pending)
+    }
+
+    /// Offset of the next byte the BIU will prefetch from CS.
+    pub fn fetch_pointer(&self) -> u16 {
+        self.pc
+    }
+
+    /// Bytes currently held in the prefetch queue, oldest first.
+    pub fn queue_contents(&self) -> Vec<u8> {
+        self.prefetch_queue.iter().copied().collect()
+    }
+
+    /// Transfers control to [ip] in the current code segment. The prefetch
+    /// queue is flushed, as it is on every jump, call, return and
+    /// interrupt, and a repeated string instruction in progress is
+    /// abandoned.
+    pub fn set_ip(&mut self, ip:u16) {
+        self.rep = None;
+        self.flush_queue();
+        self.pc = ip;
+    }
+
+    /// Empties the queue. A fetch already on the bus is let finish, and its
+    /// byte dropped.
+    pub fn flush_queue(&mut self) {
+        self.prefetch_queue.clear();
+        self.fetch_dropped = self.bus_busy()
+            && self.bus_cycle.status == BusStatus::CodeFetch;
+    }
+
+    /// Runs one code fetch bus cycle into the queue, if there is room.
+    pub(crate) fn prefetch(&mut self) -> bool {
+        if self.prefetch_queue.full() { return false; }
+        let addr = self.calculate_physical_address(Segment::CS, self.pc);
+        let b = self.run_bus_cycle(BusStatus::CodeFetch, addr, 0);
+        self.pc = self.pc.wrapping_add(1);
+        self.prefetch_queue.try_push(b)
+    }
+
+    /// Lets the BIU fill the queue on the bus clocks the last EU step left
+    /// idle. [cycle] fetches on the idle clocks themselves; instruction-level
+    /// execution approximates it once the step is done, fetching a byte for
+    /// every four of its clocks not taken by its own transfers. A store only
+    /// goes unseen when it hits a byte that was already queued, and then
+    /// until the queue is next flushed.
+    pub(crate) fn prefetch_idle(&mut self) {
+        let busy = 4 * self.ins_transfers;
+        for _ in 0..self.ins_clocks.saturating_sub(busy) / 4 {
+            if !self.prefetch() { break; }
+        }
+    }
+
+    /// Takes the next code byte from the queue, stalling the EU on a fetch
+    /// cycle when it has run dry. Under [cycle] the EU stalls until the BIU
+    /// has fetched it, and bytes it took stay logged with the instruction.
+    pub(crate) fn fetch_code_8(&mut self) -> u8 {
+        if self.trace_bus {
+            return self.fetch_queued_8();
+        }
+        if self.prefetch_queue.empty() {
+            self.prefetch();
+        }
+        self.prefetch_queue.pop().unwrap_or(0xFF)
+    }
+
+    fn fetch_queued_8(&mut self) -> u8 {
+        if let Some(b) = self.replay() {
+            self.code_pending -= 1;
+            return b;
+        }
+        if self.stalled { return 0xFF; }
+        match self.prefetch_queue.pop() {
+            Some(b) => {
+                self.eu.log.push(b);
+                self.eu.code += 1;
+                self.transfer_index += 1;
+                b
+            },
+            None => {
+                self.stalled = true;
+                0xFF
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefetch_queue() {
+        let mut cpu = I8088::new();
+        // mov byte [0x105], 0x40; nop; nop
+        cpu.bus.load(0x100, &[0xC6, 0x06, 0x05, 0x01, 0x40, 0x90, 0x90])
+            .unwrap();
+        cpu.set_ip(0x100);
+        assert_eq!(cpu.fetch_pointer(), 0x100);
+
+        // The EU had to wait for every byte of the MOV, leaving the bus no
+        // idle clocks to fetch the NOP before it was patched.
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x105);
+        assert!(cpu.queue_contents().is_empty());
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ax, 0x0001);
+    }
+
+    fn patch_after_aam(target:u16) -> I8088 {
+        let mut cpu = I8088::new();
+        // aam; mov [bx], dl; nop; nop; nop
+        cpu.bus.load(0x100, &[0xD4, 0x0A, 0x88, 0x17, 0x90, 0x90, 0x90])
+            .unwrap();
+        cpu.set_ip(0x100);
+        cpu.bx = target;
+        cpu.dx = 0x40; /* inc ax */
+        for _ in 0..5 {
+            cpu.advance().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_prefetch_idle() {
+        // AAM leaves the bus idle long enough to fill the queue with the MOV
+        // and the two NOPs after it, so patching those comes too late.
+        let cpu = patch_after_aam(0x105);
+        assert_eq!(cpu.bus.peek_8(0x105), 0x40);
+        assert_eq!(cpu.ax, 0x0000);
+
+        // The last one had not been fetched yet, and the patch is executed.
+        let cpu = patch_after_aam(0x106);
+        assert_eq!(cpu.ax, 0x0001);
+    }
+}
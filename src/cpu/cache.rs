@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use crate::{
+    core::bus::CODE_PAGE_SIZE,
+    cpu::{I8088, addr::Segment, decode::Instruction},
+    ext::queue::Queue,
+};
+
+/// Instructions already decoded, keyed by the physical address of their
+/// first byte and grouped by page, so a write to a page drops everything
+/// decoded from it.
+#[derive(Default)]
+pub struct DecodeCache {
+    pages:HashMap<u32, HashMap<u32, Instruction>>,
+    hits:u64,
+    misses:u64,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&mut self, addr:u32) -> Option<Instruction> {
+        let page = addr / CODE_PAGE_SIZE as u32;
+        let ins = self.pages.get(&page).and_then(|p| p.get(&addr)).copied();
+        match ins {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        ins
+    }
+
+    pub fn insert(&mut self, addr:u32, ins:Instruction) {
+        let page = addr / CODE_PAGE_SIZE as u32;
+        self.pages.entry(page).or_default().insert(addr, ins);
+    }
+
+    pub fn invalidate(&mut self, page:u32) {
+        self.pages.remove(&page);
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.values().map(|p| p.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Lookups answered from the cache, and those that had to decode.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
+
+impl I8088 {
+    /// Enables or disables the decode cache. It is only consulted in
+    /// instruction-level execution: [cycle] always decodes from the queue.
+    pub fn set_decode_cache(&mut self, enabled:bool) {
+        self.decode_cache_enabled = enabled;
+        self.decode_cache.clear();
+    }
+
+    pub fn decode_cache(&self) -> &DecodeCache {
+        &self.decode_cache
+    }
+
+    // Whether instructions may come from the cache. Playing out bus cycles
+    // needs the code fetches, and the V20 decodes 8080 code elsewhere.
+    pub(crate) fn decode_cached(&self) -> bool {
+        self.decode_cache_enabled && !self.trace_bus && !self.emulation
+    }
+
+    // Looks up the instruction at CS:[ip], after dropping whatever was
+    // decoded from pages written since the last lookup. On a hit, its bytes
+    // are consumed from the queue as if they had been decoded.
+    pub(crate) fn cached_instruction(&mut self, ip:u16) -> Option<Instruction> {
+        for page in self.bus.take_dirty_pages() {
+            self.decode_cache.invalidate(page);
+        }
+        let addr = self.calculate_physical_address(Segment::CS, ip);
+        let ins = self.decode_cache.get(addr)?;
+        for _ in 0..ins.len {
+            if self.prefetch_queue.pop().is_none() {
+                self.pc = self.pc.wrapping_add(1);
+            }
+        }
+        Some(ins)
+    }
+
+    // Caches [ins], decoded from [bytes] at CS:[ip]. Instructions crossing
+    // a page or the end of the segment are left out, as are those decoded
+    // from stale queue bytes that memory no longer holds.
+    pub(crate) fn cache_instruction(&mut self, ip:u16, bytes:&[u8], ins:Instruction) {
+        if ip.checked_add(ins.len).is_none() { return; }
+        let addr = self.calculate_physical_address(Segment::CS, ip);
+        let last = addr + ins.len as u32 - 1;
+        if addr as usize / CODE_PAGE_SIZE != last as usize / CODE_PAGE_SIZE { return; }
+        let current = bytes.iter().enumerate()
+            .all(|(i, &b)| self.bus.peek_8(addr as usize + i) == b);
+        if current {
+            self.bus.mark_code(addr);
+            self.decode_cache.insert(addr, ins);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{I8088, CpuStatus, register::Register};
+
+    #[test]
+    fn test_cache_hits() {
+        let mut cpu = I8088::new();
+        // inc ax; jmp short $-3
+        cpu.bus.load(0x100, &[0x40, 0xEB, 0xFD]).unwrap();
+        cpu.set_ip(0x100);
+        for _ in 0..20 {
+            cpu.advance().unwrap();
+        }
+        assert_eq!(cpu.get(Register::AX), 10);
+        assert_eq!(cpu.decode_cache().len(), 2);
+        assert_eq!(cpu.decode_cache().stats(), (18, 2));
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut cpu = I8088::new();
+        // inc ax; mov byte [0x100], 0x48 (dec ax); jmp short $-8
+        cpu.bus.load(0x100, &[0x40, 0xC6, 0x06, 0x00, 0x01, 0x48, 0xEB, 0xF8]).unwrap();
+        cpu.set_ip(0x100);
+        for _ in 0..6 {
+            cpu.advance().unwrap();
+        }
+        // The jump flushed the queue, so the patched opcode is seen.
+        assert_eq!(cpu.get(Register::AX), 0);
+
+        // Writes from outside the CPU invalidate too. The byte is already
+        // queued, so jump back to see it.
+        cpu.bus.load(0x100, &[0xF4]).unwrap();
+        cpu.set_ip(0x100);
+        assert!(matches!(cpu.advance(), Ok(CpuStatus::Hang)));
+    }
+
+    #[test]
+    fn test_stale_queue() {
+        let mut cpu = I8088::new();
+        // aam; mov [bx], dl; inc ax, queued during AAM and patched to dec ax
+        cpu.bus.load(0x100, &[0xD4, 0x0A, 0x88, 0x17, 0x40]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.set(Register::BX, 0x104);
+        cpu.set(Register::DX, 0x48);
+        for _ in 0..3 {
+            cpu.advance().unwrap();
+        }
+        assert_eq!(cpu.get(Register::AX), 1);
+        // The store dropped the AAM and MOV, and the INC came from the queue
+        // rather than memory, so it was not cached.
+        assert!(cpu.decode_cache().is_empty());
+    }
+}
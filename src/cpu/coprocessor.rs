@@ -0,0 +1,107 @@
+use crate::{
+    cpu::{I8088, decode::{Instruction, Operand}, eu::Location},
+    devices::fpu::I8087,
+};
+
+impl I8088 {
+    /// Installs an 8087 in the coprocessor socket.
+    pub fn attach_coprocessor(&mut self, fpu:I8087) {
+        self.fpu = Some(fpu);
+    }
+
+    pub fn coprocessor(&self) -> Option<&I8087> {
+        self.fpu.as_ref()
+    }
+
+    /// ESC. The 8088 calculates the effective address and reads the first
+    /// byte of a memory operand, which the 8087 picks up from the bus along
+    /// with the address. Without a coprocessor the byte is discarded.
+    pub(crate) fn escape(&mut self, ins:&Instruction) {
+        let mut mem = None;
+        if let Operand::Memory(_) = ins.src {
+            if let Location::Memory(sg, o) = self.resolve(&ins.src, ins) {
+                mem = Some(self.calculate_physical_address(sg, o));
+                self.read_mem_8(sg, o);
+            }
+        }
+        let ip = ((self.cs as u32) << 4)
+            .wrapping_add(self.ip().wrapping_sub(ins.len) as u32) & 0xFFFFF;
+        // The 8087 only takes the instruction once the EU is through with it,
+        // not on attempts that stall on the bus and are backed out of.
+        if self.stalled { return; }
+        let Some(fpu) = self.fpu.as_mut() else { return };
+        let opcode = (ins.opcode as u16 & 0x07) << 8 | ins.modrm.unwrap_or(0) as u16;
+        fpu.execute(opcode, ip, mem, &mut self.bus);
+        let int = fpu.interrupt();
+        self.set_fpu_int(int);
+    }
+
+    /// WAIT. The 8088 idles until TEST, driven by BUSY of the coprocessor,
+    /// goes low. TEST is sampled every five clocks, and is tied low when
+    /// the socket is empty.
+    pub(crate) fn wait_for_coprocessor(&mut self) {
+        if self.stalled { return; }
+        if let Some(fpu) = self.fpu.as_mut() {
+            let n = fpu.busy_clocks().div_ceil(5) * 5;
+            fpu.run(n);
+            self.ins_clocks += n;
+        }
+    }
+
+    // The coprocessor works alongside the EU.
+    pub(crate) fn run_coprocessor(&mut self) {
+        let clocks = self.ins_clocks;
+        if self.stalled { return; }
+        if let Some(fpu) = self.fpu.as_mut() {
+            fpu.run(clocks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{addr::Segment, interrupt::VECTOR_NMI, testing::run};
+
+    #[test]
+    fn test_detection() {
+        // fninit; fnstcw [0x200]
+        let code = [0xDB, 0xE3, 0xD9, 0x3E, 0x00, 0x02];
+        let mut cpu = I8088::new();
+        run(&mut cpu, &code);
+        assert_eq!(cpu.read_mem_16(Segment::DS, 0x200), 0x0000);
+
+        let mut cpu = I8088::new();
+        cpu.attach_coprocessor(I8087::new());
+        run(&mut cpu, &code);
+        assert_eq!(cpu.read_mem_16(Segment::DS, 0x200), 0x03FF);
+    }
+
+    #[test]
+    fn test_wait_and_exceptions() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        cpu.attach_coprocessor(I8087::new());
+        // NMI handler at 0070:0000
+        cpu.bus.load(VECTOR_NMI as usize * 4, &[0x00, 0x00, 0x70, 0x00]).unwrap();
+        cpu.bus.load(0x200, &0x037Bu16.to_le_bytes()).unwrap();
+        // fldpi; wait; fldcw [0x200]; fld1; fldz; fdivp st1; nop
+        cpu.bus.load(0x100, &[0xD9, 0xEB, 0x9B, 0xD9, 0x2E, 0x00, 0x02, 0xD9, 0xE8,
+                              0xD9, 0xEE, 0xDE, 0xF9, 0x90]).unwrap();
+        cpu.set_ip(0x100);
+
+        // WAIT holds the EU until the 8087 is done.
+        cpu.run_instructions(1).unwrap();
+        assert!(cpu.coprocessor().unwrap().busy());
+        cpu.run_instructions(1).unwrap();
+        assert!(cpu.instruction_clocks() > 3);
+        assert!(!cpu.coprocessor().unwrap().busy());
+
+        // The unmasked zero divide raises NMI.
+        cpu.run_instructions(4).unwrap();
+        assert!(cpu.coprocessor().unwrap().interrupt());
+        cpu.run_instructions(1).unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0070, 0x0000));
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x10D);
+    }
+}
@@ -0,0 +1,356 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::{
+    core::bus::BusInterface,
+    cpu::{
+        I8088, CpuStatus, CpuError,
+        cycle::{BusCycle, TState},
+        register::Register,
+    },
+};
+
+/// Processor as driven by the machine. Implemented by each execution core,
+/// which all wrap the same [I8088] state so one can be swapped for another
+/// without losing the machine.
+pub trait Cpu {
+    /// Runs the next instruction, or REP iteration, for the debugger. An
+    /// instruction sitting on a breakpoint is executed.
+    fn step(&mut self) -> Result<CpuStatus, CpuError>;
+    /// Runs a single clock.
+    fn cycle(&mut self) -> Result<CpuStatus, CpuError>;
+    fn register(&self, r:Register) -> u16;
+    fn set_register(&mut self, r:Register, v:u16);
+    fn ip(&self) -> u16;
+    fn set_ip(&mut self, ip:u16);
+    fn flags(&self) -> u16;
+    /// Whether the CPU is stopped by HLT, waiting for an interrupt.
+    fn halted(&self) -> bool;
+    fn set_intr(&mut self, level:bool);
+    fn set_nmi(&mut self, level:bool);
+    fn reset(&mut self);
+    /// Total number of clocks run.
+    fn clocks(&self) -> u64;
+    fn bus(&self) -> &BusInterface;
+    fn bus_mut(&mut self) -> &mut BusInterface;
+    fn core(&self) -> CpuCore;
+    /// Gives up the processor, at an instruction boundary, so it can be
+    /// fitted to another core.
+    fn into_inner(self:Box<Self>) -> I8088;
+}
+
+/// Execution core selected in the machine configuration.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CpuCore {
+    /// Executes whole instructions and charges their clocks at once. Bus
+    /// cycles, wait states and prefetch timing are not modelled.
+    Fast,
+    /// Runs the bus clock by clock through the T-states, with the EU
+    /// stalled on each of its transfers until the bus cycle ends.
+    CycleExact,
+}
+
+impl CpuCore {
+    /// Fits [cpu] to this core.
+    pub fn build(&self, cpu:I8088) -> Box<dyn Cpu> {
+        match self {
+            CpuCore::Fast => Box::new(FastCore::new(cpu)),
+            CpuCore::CycleExact => Box::new(CycleCore::new(cpu)),
+        }
+    }
+}
+
+impl fmt::Display for CpuCore {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuCore::Fast => write!(f, "fast"),
+            CpuCore::CycleExact => write!(f, "cycle"),
+        }
+    }
+}
+
+impl FromStr for CpuCore {
+    type Err = String;
+
+    fn from_str(s:&str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "fast" => Ok(CpuCore::Fast),
+            "cycle" => Ok(CpuCore::CycleExact),
+            _ => Err(format!("Unknown CPU core: {}", s)),
+        }
+    }
+}
+
+/// Instruction-level core. [Cpu::cycle] runs an instruction on its first
+/// clock and lets the clocks it took run down before the next one.
+pub struct FastCore {
+    cpu:I8088,
+    debt:u32, /* clocks left of the last instruction */
+}
+
+impl FastCore {
+    pub fn new(cpu:I8088) -> Self {
+        Self { cpu, debt:0 }
+    }
+}
+
+/// Cycle-exact core, running the BIU through [TState]s.
+pub struct CycleCore {
+    cpu:I8088,
+}
+
+impl CycleCore {
+    pub fn new(cpu:I8088) -> Self {
+        Self { cpu }
+    }
+}
+
+macro_rules! delegate {
+    () => {
+        fn register(&self, r:Register) -> u16 {
+            self.cpu.get(r)
+        }
+
+        fn set_register(&mut self, r:Register, v:u16) {
+            self.cpu.set(r, v)
+        }
+
+        fn ip(&self) -> u16 {
+            self.cpu.ip()
+        }
+
+        fn set_ip(&mut self, ip:u16) {
+            self.cpu.set_ip(ip)
+        }
+
+        fn flags(&self) -> u16 {
+            self.cpu.flags()
+        }
+
+        fn halted(&self) -> bool {
+            self.cpu.halted()
+        }
+
+        fn set_intr(&mut self, level:bool) {
+            self.cpu.set_intr(level)
+        }
+
+        fn set_nmi(&mut self, level:bool) {
+            self.cpu.set_nmi(level)
+        }
+
+        fn clocks(&self) -> u64 {
+            self.cpu.clocks()
+        }
+
+        fn bus(&self) -> &BusInterface {
+            self.cpu.bus()
+        }
+
+        fn bus_mut(&mut self) -> &mut BusInterface {
+            self.cpu.bus_mut()
+        }
+    };
+}
+
+impl Cpu for FastCore {
+    fn step(&mut self) -> Result<CpuStatus, CpuError> {
+        let status = self.cpu.step()?;
+        self.cpu.charge_clocks();
+        self.debt = 0;
+        Ok(status)
+    }
+
+    fn cycle(&mut self) -> Result<CpuStatus, CpuError> {
+        if self.debt > 0 {
+            self.debt -= 1;
+            return Ok(CpuStatus::Normal);
+        }
+        let status = self.cpu.advance()?;
+        // Halted, or stopped at a breakpoint, the CPU still takes a clock.
+        self.debt = self.cpu.charge_clocks().max(1) - 1;
+        if self.cpu.ins_clocks == 0 {
+            self.cpu.clocks += 1;
+            self.cpu.bus.clock();
+            self.cpu.bus.drop_refresh();
+        }
+        Ok(status)
+    }
+
+    fn reset(&mut self) {
+        self.cpu.reset();
+        self.debt = 0;
+    }
+
+    fn core(&self) -> CpuCore {
+        CpuCore::Fast
+    }
+
+    fn into_inner(self:Box<Self>) -> I8088 {
+        self.cpu
+    }
+
+    delegate!();
+}
+
+impl Cpu for CycleCore {
+    /// Clocks the current instruction to its end, or the next one through
+    /// when at a boundary.
+    fn step(&mut self) -> Result<CpuStatus, CpuError> {
+        if self.cpu.at_boundary() {
+            self.cpu.resume_breakpoint();
+        }
+        let mut status = CpuStatus::Normal;
+        loop {
+            let s = self.cpu.cycle()?;
+            if !matches!(s, CpuStatus::Normal) {
+                status = s;
+            }
+            if self.cpu.at_boundary() {
+                return Ok(status);
+            }
+        }
+    }
+
+    fn cycle(&mut self) -> Result<CpuStatus, CpuError> {
+        self.cpu.cycle()
+    }
+
+    fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    fn core(&self) -> CpuCore {
+        CpuCore::CycleExact
+    }
+
+    // Clocks the instruction in progress to its end, as the other core only
+    // starts whole ones. One that fails is abandoned, leaving IP on its
+    // first byte. A fetch still on the bus is dropped along with the
+    // T-state.
+    fn into_inner(mut self:Box<Self>) -> I8088 {
+        while !self.cpu.at_boundary() {
+            if self.cpu.cycle().is_err() {
+                self.cpu.abandon_instruction();
+                break;
+            }
+        }
+        self.cpu.t_state = TState::TI;
+        self.cpu.bus_cycle = BusCycle::PASSIVE;
+        self.cpu.wait_states = 0;
+        self.cpu
+    }
+
+    delegate!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::asm::Assembler;
+
+    fn program() -> I8088 {
+        let mut cpu = I8088::new();
+        // mov ax, 0x1234; mov bx, ax; inc bx; mov [0x200], bx; hlt with IF clear
+        cpu.bus.load(0x100, &[0xB8, 0x34, 0x12, 0x89, 0xC3, 0x43,
+                              0x89, 0x1E, 0x00, 0x02, 0xF4]).unwrap();
+        cpu.set_ip(0x100);
+        cpu
+    }
+
+    #[test]
+    fn test_cores_agree() {
+        for core in [CpuCore::Fast, CpuCore::CycleExact] {
+            let mut cpu = core.build(program());
+            for _ in 0..4 {
+                assert!(matches!(cpu.step(), Ok(CpuStatus::Normal)));
+            }
+            assert!(matches!(cpu.step(), Ok(CpuStatus::Hang)));
+            assert_eq!(cpu.register(Register::AX), 0x1234);
+            assert_eq!(cpu.register(Register::BX), 0x1235);
+            assert_eq!(cpu.bus().peek_8(0x200), 0x35);
+            assert_eq!(cpu.ip(), 0x10B);
+            assert!(cpu.clocks() > 0);
+        }
+    }
+
+    #[test]
+    fn test_cores_agree_on_transfers() {
+        // Instructions with several transfers, which the cycle-exact core
+        // backs out of and runs again as each of them ends.
+        let program = [
+            "mov sp, 0x800", "mov word [0x84], isr", "mov word [0x86], 0",
+            "mov cx, 5", "mov si, 0x300", "mov di, 0x400", "rep movsb",
+            "call sub", "int 0x21", "mov dx, 3",
+            "next: add [0x500], dx", "dec dx", "jnz next",
+            "mov cl, 3", "shl word [0x500], cl", "mul word [0x500]", "hlt",
+            "sub: inc word [0x402]", "ret",
+            "isr: add word [0x404], 7", "iret",
+        ];
+        let mut results = Vec::new();
+        for core in [CpuCore::Fast, CpuCore::CycleExact] {
+            let mut cpu = I8088::new();
+            let mut asm = Assembler::new(0, 0x100);
+            for line in program {
+                asm.assemble(&mut cpu.bus, line).unwrap();
+            }
+            asm.finish().unwrap();
+            cpu.bus.load(0x300, &[1, 2, 3, 4, 5]).unwrap();
+            cpu.set_ip(0x100);
+            let mut cpu = core.build(cpu);
+            while matches!(cpu.step(), Ok(CpuStatus::Normal)) {}
+            let regs:Vec<u16> = [Register::AX, Register::CX, Register::DX, Register::SP,
+                                 Register::SI, Register::DI, Register::IP, Register::FLAGS]
+                .iter().map(|&r| cpu.register(r)).collect();
+            let mem:Vec<u8> = (0x400..0x508).map(|a| cpu.bus().peek_8(a)).collect();
+            results.push((regs, mem));
+        }
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0].1[..6], [1, 2, 4, 4, 5 + 7, 0]);
+    }
+
+    #[test]
+    fn test_fast_cycle() {
+        let mut cpu = CpuCore::Fast.build(program());
+        // mov ax, imm16 takes 4 clocks: the first runs it.
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.register(Register::AX), 0x1234);
+        for _ in 0..3 {
+            cpu.cycle().unwrap();
+            assert_eq!(cpu.register(Register::BX), 0);
+        }
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.register(Register::BX), 0x1234);
+        assert_eq!(cpu.clocks(), 4 + 2);
+    }
+
+    #[test]
+    fn test_swap_core() {
+        let mut cpu = CpuCore::CycleExact.build(program());
+        for _ in 0..3 {
+            cpu.cycle().unwrap();
+        }
+        let mut cpu = CpuCore::Fast.build(cpu.into_inner());
+        assert_eq!(cpu.core(), CpuCore::Fast);
+        while !matches!(cpu.step(), Ok(CpuStatus::Hang)) {}
+        assert_eq!(cpu.register(Register::BX), 0x1235);
+        assert_eq!("cycle".parse::<CpuCore>(), Ok(CpuCore::CycleExact));
+    }
+
+    #[test]
+    fn test_swap_core_on_decode_error() {
+        let mut cpu = I8088::new();
+        // FE /2 is not an instruction.
+        cpu.bus.load(0x100, &[0xFE, 0xD0]).unwrap();
+        cpu.set_ip(0x100);
+        let mut core = Box::new(CycleCore::new(cpu));
+        // The opcode is fetched and taken, and the ModR/M byte waited for.
+        for _ in 0..6 {
+            core.cycle().unwrap();
+        }
+        assert!(!core.cpu.at_boundary());
+        let mut cpu = CpuCore::Fast.build(core.into_inner());
+        assert_eq!(cpu.ip(), 0x100);
+        assert!(matches!(cpu.step(), Err(CpuError::Decode(_))));
+        assert_eq!(cpu.ip(), 0x100);
+    }
+}
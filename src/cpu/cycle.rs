@@ -1,6 +1,15 @@
-use crate::cpu::I8088;
+use std::fmt;
+use crate::{
+    cpu::{
+        I8088, CpuStatus, CpuError,
+        addr::Segment,
+        decode::Instruction,
+        flags::LazyFlags,
+    },
+    ext::queue::Queue,
+};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TState {
     None,
     TS,
@@ -9,12 +18,605 @@ pub enum TState {
     T2,
     T3,
     T4,
+    /// Wait state, inserted between T3 and T4 while READY is low.
+    TW,
+    /// Idle clock, no bus cycle in progress.
+    TI,
+}
+
+impl fmt::Display for TState {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TState::None => write!(f, "--"),
+            TState::TS => write!(f, "Ts"),
+            TState::T0 => write!(f, "T0"),
+            TState::T1 => write!(f, "T1"),
+            TState::T2 => write!(f, "T2"),
+            TState::T3 => write!(f, "T3"),
+            TState::T4 => write!(f, "T4"),
+            TState::TW => write!(f, "Tw"),
+            TState::TI => write!(f, "Ti"),
+        }
+    }
+}
+
+/// Bus cycle type, as signalled on the S2-S0 status lines.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BusStatus {
+    InterruptAck,
+    IoRead,
+    IoWrite,
+    Halt,
+    CodeFetch,
+    MemRead,
+    MemWrite,
+    Passive,
+}
+
+impl BusStatus {
+    /// Encoding on the S2-S0 pins.
+    pub fn bits(&self) -> u8 {
+        match self {
+            BusStatus::InterruptAck => 0b000,
+            BusStatus::IoRead => 0b001,
+            BusStatus::IoWrite => 0b010,
+            BusStatus::Halt => 0b011,
+            BusStatus::CodeFetch => 0b100,
+            BusStatus::MemRead => 0b101,
+            BusStatus::MemWrite => 0b110,
+            BusStatus::Passive => 0b111,
+        }
+    }
+}
+
+impl fmt::Display for BusStatus {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusStatus::InterruptAck => write!(f, "INTA"),
+            BusStatus::IoRead => write!(f, "IOR"),
+            BusStatus::IoWrite => write!(f, "IOW"),
+            BusStatus::Halt => write!(f, "HALT"),
+            BusStatus::CodeFetch => write!(f, "CODE"),
+            BusStatus::MemRead => write!(f, "MEMR"),
+            BusStatus::MemWrite => write!(f, "MEMW"),
+            BusStatus::Passive => write!(f, "PASV"),
+        }
+    }
+}
+
+/// A single 8-bit transfer on the system bus.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BusCycle {
+    pub status:BusStatus,
+    /// 20-bit memory address or 16-bit port number. The two INTA cycles are
+    /// numbered 0 and 1.
+    pub addr:u32,
+}
+
+impl BusCycle {
+    pub const PASSIVE:BusCycle = BusCycle { status:BusStatus::Passive, addr:0 };
+}
+
+/// Progress of the instruction the EU is running under [I8088::cycle]. The
+/// log holds, in the order the instruction asked for them, the code bytes it
+/// took from the queue, the inputs it sampled and the results of the bus
+/// cycles it stalled on, so that it can be run again up to where it stopped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EuProgress {
+    pub log:Vec<u8>,
+    pub code:u16,    /* code bytes in the log */
+    pub request:Option<(BusCycle, u8)>, /* bus cycle it waits for, and the byte to write */
+    pub elapsed:u32, /* clocks since it first stalled on the bus */
+    pub busy:u32,    /* clocks it has left once done with the bus */
+}
+
+// EU state from before an attempt at running the instruction, put back when
+// the attempt stalls. Code bytes taken out of the queue stay taken.
+struct Checkpoint {
+    regs:[u16; 12],
+    flags:u16,
+    lazy_flags:Option<LazyFlags>,
+    materializations:u64,
+    emulation:bool,
+    md_writable:bool,
+    le:u32,
+    pc:u16,
+    queue:[u8; 4],
+    queued:usize,
+    fetch_dropped:bool,
+    rep:Option<Instruction>,
+    nmi_latch:bool,
+    interrupt_inhibit:bool,
+    trap:bool,
+    halted:bool,
+    breakpoint_resume:bool,
+    ins_clocks:u32,
+    code:u16,
 }
 
 impl I8088 {
     /// Executes one CPU cycle, accurately emulating the transfer between and
     /// execution of T-states.
-    pub fn cycle(&mut self) {
+    ///
+    /// The EU runs first. An instruction that needs the bus stalls until
+    /// the BIU has run the bus cycle, and its data is only read or written
+    /// at the end of T3, or of the last TW while READY is held low. Once
+    /// done with the bus, the EU takes the rest of the clocks the
+    /// instruction is timed at. The BIU then starts the next bus cycle when
+    /// the last one is over, giving the EU precedence and otherwise fetching
+    /// into the queue while it has room, or idles in TI.
+    pub fn cycle(&mut self) -> Result<CpuStatus, CpuError> {
+        self.clocks += 1;
+        self.bus.clock();
+        let status = self.cycle_eu();
+        self.t_state = match self.t_state {
+            TState::T1 => TState::T2,
+            TState::T2 => TState::T3,
+            TState::T3 | TState::TW => {
+                if self.sample_ready() {
+                    self.end_bus_cycle();
+                    TState::T4
+                } else {
+                    TState::TW
+                }
+            },
+            _ => self.start_bus_cycle(),
+        };
+        // A refresh coming due while the CPU holds the bus stretches the
+        // cycle in progress, and one falling on an idle clock costs nothing.
+        match self.t_state {
+            TState::T2 | TState::T3 | TState::TW => {
+                let n = self.wait_states as u32 + self.bus.take_refresh_clocks();
+                self.wait_states = n.min(u8::MAX as u32) as u8;
+            },
+            TState::TI => self.bus.drop_refresh(),
+            _ => {},
+        }
+        status
+    }
+
+    // Attempts the instruction, unless the EU is busy or waits for the bus.
+    // An attempt that stalls is backed out of, leaving only the code bytes
+    // it took and the inputs it sampled in the log.
+    fn cycle_eu(&mut self) -> Result<CpuStatus, CpuError> {
+        if self.eu.busy > 0 {
+            self.eu.busy -= 1;
+            return Ok(CpuStatus::Normal);
+        }
+        if self.eu_waiting() {
+            self.eu.elapsed += 1;
+            return Ok(CpuStatus::Normal);
+        }
+        let checkpoint = self.checkpoint();
+        self.trace_bus = true;
+        let status = self.advance();
+        self.trace_bus = false;
+        self.transfer_index = 0;
+        if std::mem::take(&mut self.stalled) {
+            // Waiting for code is not part of the instruction's timing.
+            if self.eu.request.is_some() {
+                self.eu.elapsed += 1;
+            }
+            self.back_out(checkpoint);
+            return Ok(CpuStatus::Normal);
+        }
+        let busy = self.ins_clocks.saturating_sub(self.eu.elapsed + 1);
+        self.eu = EuProgress { busy, ..EuProgress::default() };
+        self.code_pending = 0;
+        status
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            regs:[self.ax, self.bx, self.cx, self.dx, self.si, self.di, self.bp, self.sp,
+                  self.ds, self.cs, self.ss, self.es],
+            flags:self.flags,
+            lazy_flags:self.lazy_flags,
+            materializations:self.materializations,
+            emulation:self.emulation,
+            md_writable:self.md_writable,
+            le:self.le,
+            pc:self.pc,
+            queue:self.queue_array(),
+            queued:self.prefetch_queue.size(),
+            fetch_dropped:self.fetch_dropped,
+            rep:self.rep,
+            nmi_latch:self.nmi_latch,
+            interrupt_inhibit:self.interrupt_inhibit,
+            trap:self.trap,
+            halted:self.halted,
+            breakpoint_resume:self.breakpoint_resume,
+            ins_clocks:self.ins_clocks,
+            code:self.eu.code,
+        }
+    }
+
+    // Copy of the queue that needs no allocation, as [checkpoint] is taken
+    // on every clock the EU attempts an instruction.
+    fn queue_array(&self) -> [u8; 4] {
+        let mut queue = [0; 4];
+        for (q, &b) in queue.iter_mut().zip(self.prefetch_queue.iter()) {
+            *q = b;
+        }
+        queue
+    }
+
+    fn back_out(&mut self, c:Checkpoint) {
+        [self.ax, self.bx, self.cx, self.dx, self.si, self.di, self.bp, self.sp,
+         self.ds, self.cs, self.ss, self.es] = c.regs;
+        self.flags = c.flags;
+        self.lazy_flags = c.lazy_flags;
+        self.materializations = c.materializations;
+        self.emulation = c.emulation;
+        self.md_writable = c.md_writable;
+        self.le = c.le;
+        self.pc = c.pc;
+        self.prefetch_queue.clear();
+        let taken = (self.eu.code - c.code) as usize;
+        for &b in &c.queue[taken..c.queued] {
+            self.prefetch_queue.try_push(b);
+        }
+        self.fetch_dropped = c.fetch_dropped;
+        self.rep = c.rep;
+        self.nmi_latch = c.nmi_latch;
+        self.interrupt_inhibit = c.interrupt_inhibit;
+        self.trap = c.trap;
+        self.halted = c.halted;
+        self.breakpoint_resume = c.breakpoint_resume;
+        self.ins_clocks = c.ins_clocks;
+        self.code_pending = self.eu.code;
+    }
+
+    // The EU has the bus first. Otherwise the BIU fetches ahead while the
+    // queue has room, unless HLT stopped it.
+    fn start_bus_cycle(&mut self) -> TState {
+        let (c, data) = match self.eu.request.take() {
+            Some(r) => r,
+            None if !self.halted && !self.prefetch_queue.full() => {
+                let addr = self.calculate_physical_address(Segment::CS, self.pc);
+                self.fetch_dropped = false;
+                (BusCycle { status:BusStatus::CodeFetch, addr }, 0)
+            },
+            None => {
+                self.bus_cycle = BusCycle::PASSIVE;
+                return TState::TI;
+            },
+        };
+        self.bus_cycle = c;
+        self.bus_data = data;
+        // A DMA refresh that came due as the cycle starts, or during the one
+        // before it, is charged to it.
+        let n = self.bus.wait_states(&c) as u32 + self.bus.take_refresh_clocks();
+        self.wait_states = n.min(u8::MAX as u32) as u8;
+        TState::T1
+    }
+
+    // Makes the transfer of the bus cycle, as READY lets it end. Fetched
+    // bytes go to the queue and anything else to the EU.
+    fn end_bus_cycle(&mut self) {
+        let c = self.bus_cycle;
+        let v = self.bus_transfer(c, self.bus_data);
+        if c.status != BusStatus::CodeFetch {
+            self.eu.log.push(v);
+        } else if !std::mem::take(&mut self.fetch_dropped) {
+            self.pc = self.pc.wrapping_add(1);
+            self.prefetch_queue.try_push(v);
+        }
+    }
+
+    pub(crate) fn bus_busy(&self) -> bool {
+        matches!(self.t_state, TState::T1 | TState::T2 | TState::T3 | TState::TW)
+    }
+
+    // The EU stalled on a bus cycle that has not ended yet.
+    fn eu_waiting(&self) -> bool {
+        self.eu.request.is_some()
+            || (self.bus_busy() && self.bus_cycle.status != BusStatus::CodeFetch)
+    }
+
+    /// T-state of the current clock.
+    pub fn t_state(&self) -> TState {
+        self.t_state
+    }
+
+    /// Bus cycle in progress, or a passive cycle when idle.
+    pub fn bus_cycle(&self) -> BusCycle {
+        self.bus_cycle
+    }
+
+    /// Total number of clocks run through [cycle].
+    pub fn clocks(&self) -> u64 {
+        self.clocks
+    }
+
+    /// Clocks taken by the last instruction, or REP iteration, run by the
+    /// EU, as given by its timing and the data it operated on.
+    pub fn instruction_clocks(&self) -> u32 {
+        self.ins_clocks
+    }
+
+    /// Drives the READY input. Bus cycles are held in TW while it is low.
+    pub fn set_ready(&mut self, ready:bool) {
+        self.ready = ready;
+    }
+
+    /// READY as sampled in T3 and TW. The wait state generator holds it low
+    /// for the number of wait states the bus requested at T1, and the
+    /// external input can extend the cycle further.
+    fn sample_ready(&mut self) -> bool {
+        if self.wait_states > 0 {
+            self.wait_states -= 1;
+            return false;
+        }
+        self.ready
+    }
+
+    // The next clock starts an instruction.
+    pub(crate) fn at_boundary(&self) -> bool {
+        self.eu.busy == 0 && self.eu.log.is_empty() && !self.eu_waiting()
+    }
+
+    // Drops the instruction in progress under [cycle], along with the log of
+    // its replay, and refetches it from its first byte.
+    pub(crate) fn abandon_instruction(&mut self) {
+        let ip = self.ip();
+        self.eu = EuProgress::default();
+        self.transfer_index = 0;
+        self.code_pending = 0;
+        self.stalled = false;
+        self.set_ip(ip);
+    }
+
+    // Accounts for the clocks of the last EU step in instruction-level
+    // execution, where no bus cycles are played out. Its bus cycles are
+    // taken to come first, and DMA refreshes that come due during them hold
+    // the CPU up for the clocks they steal, during which more of them can
+    // come due. Refreshes falling on the idle clocks after them are free.
+    pub(crate) fn charge_clocks(&mut self) -> u32 {
+        let busy = (4 * self.ins_transfers).min(self.ins_clocks);
+        let mut stolen = 0;
+        for i in 0..self.ins_clocks {
+            self.bus.clock();
+            if i < busy {
+                stolen += self.bus.take_refresh_clocks();
+            } else {
+                self.bus.drop_refresh();
+            }
+        }
+        let mut clocks = self.ins_clocks;
+        while stolen > 0 {
+            for _ in 0..stolen {
+                self.bus.clock();
+            }
+            clocks += stolen;
+            stolen = self.bus.take_refresh_clocks();
+        }
+        self.clocks += clocks as u64;
+        clocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::register::Register;
+
+    #[test]
+    fn test_bus_cycle_states() {
+        let mut cpu = I8088::new();
+        // mov al, [bx]
+        cpu.bus.load(0x100, &[0x8A, 0x07]).unwrap();
+        cpu.bus.load(0x000, &[0x5A]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.set_breakpoint(0x102);
+
+        let mut states = Vec::new();
+        let mut cycles = Vec::new();
+        while matches!(cpu.cycle(), Ok(CpuStatus::Normal)) {
+            states.push(cpu.t_state());
+            if cpu.t_state() == TState::T1 {
+                cycles.push(cpu.bus_cycle().status);
+            }
+        }
+        assert_eq!(&states[..5], &[TState::T1, TState::T2, TState::T3,
+                                   TState::T4, TState::T1]);
+        // Two fetches for the instruction and the operand read, then the
+        // BIU fetches ahead while the EU takes the rest of its 13 clocks.
+        assert_eq!(cycles, [BusStatus::CodeFetch, BusStatus::CodeFetch,
+                            BusStatus::MemRead, BusStatus::CodeFetch,
+                            BusStatus::CodeFetch, BusStatus::CodeFetch]);
+        assert_eq!(cpu.clocks(), 8 + 13 + 1);
+        assert_eq!(cpu.get(Register::AL), 0x5A);
+
+        // The EU is stopped at the breakpoint, and the bus goes idle once
+        // the BIU has filled the queue.
+        assert_eq!(cpu.bus_cycle(), BusCycle { status:BusStatus::CodeFetch, addr:0x104 });
+        for _ in 0..8 {
+            assert!(matches!(cpu.cycle(), Ok(CpuStatus::Breakpoint)));
+        }
+        assert_eq!(cpu.queue_contents().len(), 4);
+        assert_eq!(cpu.t_state(), TState::TI);
+        assert_eq!(cpu.bus_cycle(), BusCycle::PASSIVE);
+
+        // Resumed, add [bx+si], al stalls on its read on the next clock.
+        cpu.resume_breakpoint();
+        assert!(matches!(cpu.cycle(), Ok(CpuStatus::Normal)));
+        assert_eq!((cpu.t_state(), cpu.bus_cycle().status), (TState::T1, BusStatus::MemRead));
+        assert_eq!(cpu.ip(), 0x102);
+    }
+
+    #[test]
+    fn test_idle_clocks() {
+        let mut cpu = I8088::new();
+        // aam; nop
+        cpu.bus.load(0x100, &[0xD4, 0x0A, 0x90]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.set_breakpoint(0x102);
+
+        let mut clocks = 0;
+        let mut idle = 0;
+        while !matches!(cpu.cycle(), Ok(CpuStatus::Breakpoint)) {
+            clocks += 1;
+            if cpu.t_state() == TState::TI { idle += 1; }
+        }
+        // AAM takes 83 clocks once its two bytes are fetched. The four
+        // fetches filling the queue overlap with it.
+        assert_eq!(clocks, 8 + 83);
+        assert_eq!(idle, clocks - 6 * 4);
+    }
+
+    // Clocks until AL is loaded by MOV AL, [BX], with READY held low for
+    // [hold] clocks once the read reaches T3.
+    fn clocks_to_load(hold:u32) -> u64 {
+        let mut cpu = I8088::new();
+        cpu.bus.load(0x100, &[0x8A, 0x07]).unwrap();
+        cpu.bus.load(0x200, &[0x5A]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.set(Register::BX, 0x200);
+        let mut held = 0;
+        while cpu.get(Register::AL) != 0x5A {
+            cpu.cycle().unwrap();
+            if cpu.bus_cycle().status != BusStatus::MemRead { continue; }
+            match cpu.t_state() {
+                TState::T2 => cpu.set_ready(false),
+                TState::TW if held + 1 == hold => {
+                    held += 1;
+                    cpu.set_ready(true);
+                },
+                TState::TW => held += 1,
+                _ => {},
+            }
+            // The read has not happened before READY lets the cycle end.
+            if matches!(cpu.t_state(), TState::T1 | TState::T2 | TState::T3 | TState::TW) {
+                assert_eq!(cpu.get(Register::AL), 0);
+            }
+        }
+        cpu.clocks()
+    }
+
+    #[test]
+    fn test_ready_delays_read() {
+        let base = clocks_to_load(1);
+        assert_eq!(clocks_to_load(4), base + 3);
+        assert_eq!(clocks_to_load(10), base + 9);
+    }
+
+    #[test]
+    fn test_wait_state_table() {
+        let mut cpu = I8088::new();
+        // in al, 0x60
+        cpu.bus.load(0x100, &[0xE4, 0x60]).unwrap();
+        cpu.bus.set_memory_wait_states(0xA0000..=0xBFFFF, 2);
+        cpu.set_ip(0x100);
+
+        let mut io_cycles = 0;
+        let mut waits = 0;
+        while cpu.clocks() < 100 {
+            cpu.cycle().unwrap();
+            if cpu.bus_cycle().status == BusStatus::IoRead {
+                io_cycles += 1;
+                if cpu.t_state() == TState::TW { waits += 1; }
+            }
+        }
+        // One default wait state on I/O, stretching the cycle to 5 clocks.
+        assert_eq!((io_cycles, waits), (5, 1));
+
+        let c = BusCycle { status:BusStatus::MemRead, addr:0xB8000 };
+        assert_eq!(cpu.bus.wait_states(&c), 2);
+        let c = BusCycle { status:BusStatus::MemRead, addr:0x00400 };
+        assert_eq!(cpu.bus.wait_states(&c), 0);
+
+        // Port ranges add to the default.
+        cpu.bus.set_io_wait_states(0x3F8..=0x3FF, 3);
+        let io = |addr| BusCycle { status:BusStatus::IoRead, addr };
+        assert_eq!(cpu.bus.wait_states(&io(0x3F8)), 4);
+        assert_eq!(cpu.bus.wait_states(&io(0x060)), 1);
+        cpu.bus.set_io_wait_default(0);
+        assert_eq!(cpu.bus.wait_states(&io(0x3FF)), 3);
+
+        cpu.bus.set_dma_refresh(2, 4);
+        cpu.bus.clock();
+        cpu.bus.clock();
+        assert_eq!(cpu.bus.wait_states(&c), 0);
+        assert_eq!(cpu.bus.take_refresh_clocks(), 4);
+        assert_eq!(cpu.bus.take_refresh_clocks(), 0);
+    }
+
+    // Runs [clocks] clocks, returning the wait states of every bus cycle
+    // that started in them.
+    fn cycle_waits(cpu:&mut I8088, clocks:u32) -> Vec<(BusStatus, u32)> {
+        let mut cycles:Vec<(BusStatus, u32)> = Vec::new();
+        for _ in 0..clocks {
+            cpu.cycle().unwrap();
+            match cpu.t_state() {
+                TState::T1 => cycles.push((cpu.bus_cycle().status, 0)),
+                TState::TW => cycles.last_mut().unwrap().1 += 1,
+                _ => {},
+            }
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_dma_refresh() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        // NMI handler at 0060:0000
+        cpu.bus.load(0x08, &[0x00, 0x00, 0x60, 0x00]).unwrap();
+        // aam; mov al, [0x400]; hlt
+        cpu.bus.load(0x100, &[0xD4, 0x0A, 0xA0, 0x00, 0x04, 0xF4]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.bus.set_dma_refresh(20, 4);
+
+        // Refreshes are charged to the bus cycle they land on, and those
+        // falling on the idle clocks of AAM are not piled up for the read.
+        let cycles = cycle_waits(&mut cpu, 150);
+        assert!(cycles.iter().all(|&(_, n)| n <= 4));
+        assert!(cycles.iter().any(|&(_, n)| n == 4));
+        let read = cycles.iter().find(|(s, _)| *s == BusStatus::MemRead).unwrap();
+        assert_eq!(read.1, 0);
+        assert!(cpu.halted);
+
+        // Nor are those of a long HLT charged to the NMI that ends it.
+        cycle_waits(&mut cpu, 500);
+        cpu.set_nmi(true);
+        let cycles = cycle_waits(&mut cpu, 60);
+        assert!(cycles.iter().any(|(s, _)| *s == BusStatus::MemWrite));
+        assert!(cycles.iter().all(|&(_, n)| n <= 4));
+
+        // Instruction-level execution charges the refreshes that come due
+        // during the bus cycles of an instruction to it, and drops the rest.
+        let mut cpu = I8088::new();
+        // aam; aam
+        cpu.bus.load(0x100, &[0xD4, 0x0A, 0xD4, 0x0A]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.bus.set_dma_refresh(20, 4);
+        // Fetching the two bytes and then four more takes the first 24
+        // clocks, and the refresh at clock 20 lands on them. Those at 40,
+        // 60 and 80 fall on idle clocks.
+        cpu.run_instructions(1).unwrap();
+        assert_eq!(cpu.clocks(), 83 + 4);
+        // Two more bytes fetched, and the refreshes at 100 and 120 are idle.
+        cpu.run_instructions(1).unwrap();
+        assert_eq!(cpu.clocks(), 2 * 83 + 4);
+    }
 
+    #[test]
+    fn test_wait_states() {
+        let mut cpu = I8088::new();
+        cpu.set_ip(0x100);
+        cpu.set_ready(false);
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.t_state(), TState::T1);
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.t_state(), TState::T3);
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.t_state(), TState::TW);
+        cpu.set_ready(true);
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.t_state(), TState::T4);
+        assert_eq!(cpu.clocks(), 6);
     }
 }
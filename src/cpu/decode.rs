@@ -1,16 +1,22 @@
+use std::error::Error;
 use std::fmt::{self, Debug, Display, Formatter};
-use crate::cpu::mnemonic::Mnemonic;
+use crate::cpu::{
+    CpuModel, addr::Segment, mnemonic::Mnemonic, register::Register,
+    timing::Timing,
+};
 
 #[derive(Debug, Clone)]
 pub enum DecodeError {
     UnknownOpcode(u8),
     UnimplementedOpcode(u8),
-
+    /// More than [MAX_PREFIXES] prefix bytes in a row.
+    TooManyPrefixes,
 }
 
-pub struct Instruction {
-    m:Mnemonic,
-}
+/// Prefixes accepted ahead of an opcode. The 8088 itself has no limit, but
+/// real code never uses more than a few, and a longer run is memory that
+/// does not hold code, such as a fill of 0x26 or 0xF3 bytes.
+pub const MAX_PREFIXES:u16                  = 0xFF;
 
 impl Error for DecodeError {}
 
@@ -23,6 +29,693 @@ impl Display for DecodeError {
             DecodeError::UnimplementedOpcode(op) => {
                 write!(f, "Unimplemented opcode {:#2X}", op)
             },
+            DecodeError::TooManyPrefixes => {
+                write!(f, "More than {} prefixes", MAX_PREFIXES)
+            },
         }
     }
 }
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OperandSize {
+    Byte,
+    Word,
+}
+
+/// Memory operand as encoded by a ModR/M byte. Direct addresses (including
+/// the A0-A3 moffs forms) are stored as mod 00, r/m 110.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryOperand {
+    pub md:u8,    /* ModR/M mod field */
+    pub rm:u8,    /* ModR/M r/m field */
+    pub disp:u16, /* displacement, sign-extended if 8-bit */
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    Register(Register),
+    Memory(MemoryOperand),
+    Immediate(u16),
+    /// Signed displacement relative to the end of the instruction.
+    Relative(i16),
+    /// Direct far pointer (segment, offset).
+    Far(u16, u16),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub mnemonic:Mnemonic,
+    pub opcode:u8,
+    pub size:OperandSize,
+    pub dst:Operand,
+    pub src:Operand,
+    pub src2:Operand, /* immediate of the three-operand IMUL */
+    pub modrm:Option<u8>,
+
+    pub segment:Option<Segment>, /* segment override prefix */
+    pub rep:Option<Mnemonic>,    /* REP (F3) or REPNE (F2) */
+    pub lock:bool,
+    /// Inter-segment CALL, JMP or RET.
+    pub far:bool,
+
+    /// Total length in bytes, including all prefixes.
+    pub len:u16,
+    pub timing:Timing,
+}
+
+/// Operand specifiers used by the opcode table.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Spec {
+    N,              /* no operand */
+    E,              /* ModR/M r/m, register or memory */
+    G,              /* ModR/M reg, general register */
+    S,              /* ModR/M reg, segment register */
+    I,              /* immediate of operand size */
+    Ib,             /* 8-bit immediate */
+    Is,             /* 8-bit immediate, sign-extended to operand size */
+    Iw,             /* 16-bit immediate */
+    A,              /* accumulator, AL or AX */
+    Z,              /* general register encoded in opcode bits 0-2 */
+    F(Register),    /* fixed register */
+    K(u16),         /* implied constant */
+    J8,             /* 8-bit relative displacement */
+    J16,            /* 16-bit relative displacement */
+    Ap,             /* direct far pointer */
+    O,              /* direct memory offset */
+    X,              /* ESC opcode, built from opcode and ModR/M reg */
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Kind {
+    Op(Mnemonic),
+    Group(usize),
+    Segment(Segment),
+    Prefix(Mnemonic),
+    /// 0x0F on the V20, followed by a second opcode byte.
+    Extended,
+    /// Not emulated.
+    Invalid,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    kind:Kind,
+    size:OperandSize,
+    dst:Spec,
+    src:Spec,
+    extra:Spec,
+}
+
+const B:OperandSize = OperandSize::Byte;
+const W:OperandSize = OperandSize::Word;
+
+const fn op(m:Mnemonic, size:OperandSize, dst:Spec, src:Spec) -> Entry {
+    Entry { kind:Kind::Op(m), size, dst, src, extra:Spec::N }
+}
+
+const fn op3(m:Mnemonic, size:OperandSize, dst:Spec, src:Spec,
+             extra:Spec) -> Entry {
+    Entry { kind:Kind::Op(m), size, dst, src, extra }
+}
+
+const fn grp(g:usize, size:OperandSize, dst:Spec, src:Spec) -> Entry {
+    Entry { kind:Kind::Group(g), size, dst, src, extra:Spec::N }
+}
+
+const fn seg(s:Segment) -> Entry {
+    other(Kind::Segment(s))
+}
+
+const fn pfx(m:Mnemonic) -> Entry {
+    other(Kind::Prefix(m))
+}
+
+const fn other(kind:Kind) -> Entry {
+    Entry { kind, size:B, dst:Spec::N, src:Spec::N, extra:Spec::N }
+}
+
+use Mnemonic::*;
+use Spec::*;
+
+/// Primary opcode map. The 8088 decodes every byte, and the undocumented
+/// opcodes are mostly mirrors of their neighbours through unused bits:
+/// 0x60-0x6F alias the Jcc opcodes, 0xC0/0xC1 and 0xC8/0xC9 alias RET and
+/// RETF, and 0xF1 is another LOCK. Within the groups, /1 of 0xF6/0xF7 is
+/// another TEST and /7 of 0xFF another PUSH. Only /2 to /7 of 0xFE are left
+/// undecoded.
+#[rustfmt::skip]
+static OPCODES:[Entry; 256] = [
+    /* 0x00 */ op(ADD, B, E, G),   op(ADD, W, E, G),
+    /* 0x02 */ op(ADD, B, G, E),   op(ADD, W, G, E),
+    /* 0x04 */ op(ADD, B, A, I),   op(ADD, W, A, I),
+    /* 0x06 */ op(PUSH, W, F(Register::ES), N),
+    /* 0x07 */ op(POP, W, F(Register::ES), N),
+    /* 0x08 */ op(OR, B, E, G),    op(OR, W, E, G),
+    /* 0x0A */ op(OR, B, G, E),    op(OR, W, G, E),
+    /* 0x0C */ op(OR, B, A, I),    op(OR, W, A, I),
+    /* 0x0E */ op(PUSH, W, F(Register::CS), N),
+    /* 0x0F */ op(POP, W, F(Register::CS), N),
+    /* 0x10 */ op(ADC, B, E, G),   op(ADC, W, E, G),
+    /* 0x12 */ op(ADC, B, G, E),   op(ADC, W, G, E),
+    /* 0x14 */ op(ADC, B, A, I),   op(ADC, W, A, I),
+    /* 0x16 */ op(PUSH, W, F(Register::SS), N),
+    /* 0x17 */ op(POP, W, F(Register::SS), N),
+    /* 0x18 */ op(SBB, B, E, G),   op(SBB, W, E, G),
+    /* 0x1A */ op(SBB, B, G, E),   op(SBB, W, G, E),
+    /* 0x1C */ op(SBB, B, A, I),   op(SBB, W, A, I),
+    /* 0x1E */ op(PUSH, W, F(Register::DS), N),
+    /* 0x1F */ op(POP, W, F(Register::DS), N),
+    /* 0x20 */ op(AND, B, E, G),   op(AND, W, E, G),
+    /* 0x22 */ op(AND, B, G, E),   op(AND, W, G, E),
+    /* 0x24 */ op(AND, B, A, I),   op(AND, W, A, I),
+    /* 0x26 */ seg(Segment::ES),   op(DAA, B, N, N),
+    /* 0x28 */ op(SUB, B, E, G),   op(SUB, W, E, G),
+    /* 0x2A */ op(SUB, B, G, E),   op(SUB, W, G, E),
+    /* 0x2C */ op(SUB, B, A, I),   op(SUB, W, A, I),
+    /* 0x2E */ seg(Segment::CS),   op(DAS, B, N, N),
+    /* 0x30 */ op(XOR, B, E, G),   op(XOR, W, E, G),
+    /* 0x32 */ op(XOR, B, G, E),   op(XOR, W, G, E),
+    /* 0x34 */ op(XOR, B, A, I),   op(XOR, W, A, I),
+    /* 0x36 */ seg(Segment::SS),   op(AAA, B, N, N),
+    /* 0x38 */ op(CMP, B, E, G),   op(CMP, W, E, G),
+    /* 0x3A */ op(CMP, B, G, E),   op(CMP, W, G, E),
+    /* 0x3C */ op(CMP, B, A, I),   op(CMP, W, A, I),
+    /* 0x3E */ seg(Segment::DS),   op(AAS, B, N, N),
+    /* 0x40 */ op(INC, W, Z, N),   op(INC, W, Z, N),
+    /* 0x42 */ op(INC, W, Z, N),   op(INC, W, Z, N),
+    /* 0x44 */ op(INC, W, Z, N),   op(INC, W, Z, N),
+    /* 0x46 */ op(INC, W, Z, N),   op(INC, W, Z, N),
+    /* 0x48 */ op(DEC, W, Z, N),   op(DEC, W, Z, N),
+    /* 0x4A */ op(DEC, W, Z, N),   op(DEC, W, Z, N),
+    /* 0x4C */ op(DEC, W, Z, N),   op(DEC, W, Z, N),
+    /* 0x4E */ op(DEC, W, Z, N),   op(DEC, W, Z, N),
+    /* 0x50 */ op(PUSH, W, Z, N),  op(PUSH, W, Z, N),
+    /* 0x52 */ op(PUSH, W, Z, N),  op(PUSH, W, Z, N),
+    /* 0x54 */ op(PUSH, W, Z, N),  op(PUSH, W, Z, N),
+    /* 0x56 */ op(PUSH, W, Z, N),  op(PUSH, W, Z, N),
+    /* 0x58 */ op(POP, W, Z, N),   op(POP, W, Z, N),
+    /* 0x5A */ op(POP, W, Z, N),   op(POP, W, Z, N),
+    /* 0x5C */ op(POP, W, Z, N),   op(POP, W, Z, N),
+    /* 0x5E */ op(POP, W, Z, N),   op(POP, W, Z, N),
+    /* 0x60 */ op(JO, B, J8, N),   op(JNO, B, J8, N),
+    /* 0x62 */ op(JB, B, J8, N),   op(JNB, B, J8, N),
+    /* 0x64 */ op(JZ, B, J8, N),   op(JNZ, B, J8, N),
+    /* 0x66 */ op(JBE, B, J8, N),  op(JA, B, J8, N),
+    /* 0x68 */ op(JS, B, J8, N),   op(JNS, B, J8, N),
+    /* 0x6A */ op(JP, B, J8, N),   op(JNP, B, J8, N),
+    /* 0x6C */ op(JL, B, J8, N),   op(JNL, B, J8, N),
+    /* 0x6E */ op(JLE, B, J8, N),  op(JG, B, J8, N),
+    /* 0x70 */ op(JO, B, J8, N),   op(JNO, B, J8, N),
+    /* 0x72 */ op(JB, B, J8, N),   op(JNB, B, J8, N),
+    /* 0x74 */ op(JZ, B, J8, N),   op(JNZ, B, J8, N),
+    /* 0x76 */ op(JBE, B, J8, N),  op(JA, B, J8, N),
+    /* 0x78 */ op(JS, B, J8, N),   op(JNS, B, J8, N),
+    /* 0x7A */ op(JP, B, J8, N),   op(JNP, B, J8, N),
+    /* 0x7C */ op(JL, B, J8, N),   op(JNL, B, J8, N),
+    /* 0x7E */ op(JLE, B, J8, N),  op(JG, B, J8, N),
+    /* 0x80 */ grp(0, B, E, I),    grp(0, W, E, I),
+    /* 0x82 */ grp(0, B, E, I),    grp(0, W, E, Is),
+    /* 0x84 */ op(TEST, B, E, G),  op(TEST, W, E, G),
+    /* 0x86 */ op(XCHG, B, E, G),  op(XCHG, W, E, G),
+    /* 0x88 */ op(MOV, B, E, G),   op(MOV, W, E, G),
+    /* 0x8A */ op(MOV, B, G, E),   op(MOV, W, G, E),
+    /* 0x8C */ op(MOV, W, E, S),   op(LEA, W, G, E),
+    /* 0x8E */ op(MOV, W, S, E),   op(POP, W, E, N),
+    /* 0x90 */ op(NOP, B, N, N),   op(XCHG, W, A, Z),
+    /* 0x92 */ op(XCHG, W, A, Z),  op(XCHG, W, A, Z),
+    /* 0x94 */ op(XCHG, W, A, Z),  op(XCHG, W, A, Z),
+    /* 0x96 */ op(XCHG, W, A, Z),  op(XCHG, W, A, Z),
+    /* 0x98 */ op(CBW, B, N, N),   op(CWD, W, N, N),
+    /* 0x9A */ op(CALL, W, Ap, N), op(WAIT, B, N, N),
+    /* 0x9C */ op(PUSHF, W, N, N), op(POPF, W, N, N),
+    /* 0x9E */ op(SAHF, B, N, N),  op(LAHF, B, N, N),
+    /* 0xA0 */ op(MOV, B, A, O),   op(MOV, W, A, O),
+    /* 0xA2 */ op(MOV, B, O, A),   op(MOV, W, O, A),
+    /* 0xA4 */ op(MOVSB, B, N, N), op(MOVSW, W, N, N),
+    /* 0xA6 */ op(CMPSB, B, N, N), op(CMPSW, W, N, N),
+    /* 0xA8 */ op(TEST, B, A, I),  op(TEST, W, A, I),
+    /* 0xAA */ op(STOSB, B, N, N), op(STOSW, W, N, N),
+    /* 0xAC */ op(LODSB, B, N, N), op(LODSW, W, N, N),
+    /* 0xAE */ op(SCASB, B, N, N), op(SCASW, W, N, N),
+    /* 0xB0 */ op(MOV, B, Z, I),   op(MOV, B, Z, I),
+    /* 0xB2 */ op(MOV, B, Z, I),   op(MOV, B, Z, I),
+    /* 0xB4 */ op(MOV, B, Z, I),   op(MOV, B, Z, I),
+    /* 0xB6 */ op(MOV, B, Z, I),   op(MOV, B, Z, I),
+    /* 0xB8 */ op(MOV, W, Z, I),   op(MOV, W, Z, I),
+    /* 0xBA */ op(MOV, W, Z, I),   op(MOV, W, Z, I),
+    /* 0xBC */ op(MOV, W, Z, I),   op(MOV, W, Z, I),
+    /* 0xBE */ op(MOV, W, Z, I),   op(MOV, W, Z, I),
+    /* 0xC0 */ op(RET, W, Iw, N),  op(RET, W, N, N),
+    /* 0xC2 */ op(RET, W, Iw, N),  op(RET, W, N, N),
+    /* 0xC4 */ op(LES, W, G, E),   op(LDS, W, G, E),
+    /* 0xC6 */ op(MOV, B, E, I),   op(MOV, W, E, I),
+    /* 0xC8 */ op(RETF, W, Iw, N), op(RETF, W, N, N),
+    /* 0xCA */ op(RETF, W, Iw, N), op(RETF, W, N, N),
+    /* 0xCC */ op(INT, B, K(3), N), op(INT, B, Ib, N),
+    /* 0xCE */ op(INTO, B, N, N),  op(IRET, W, N, N),
+    /* 0xD0 */ grp(1, B, E, K(1)), grp(1, W, E, K(1)),
+    /* 0xD2 */ grp(1, B, E, F(Register::CL)),
+    /* 0xD3 */ grp(1, W, E, F(Register::CL)),
+    /* 0xD4 */ op(AAM, B, Ib, N),  op(AAD, B, Ib, N),
+    /* 0xD6 */ op(SALC, B, N, N),  op(XLAT, B, N, N),
+    /* 0xD8 */ op(ESC, B, X, E),   op(ESC, B, X, E),
+    /* 0xDA */ op(ESC, B, X, E),   op(ESC, B, X, E),
+    /* 0xDC */ op(ESC, B, X, E),   op(ESC, B, X, E),
+    /* 0xDE */ op(ESC, B, X, E),   op(ESC, B, X, E),
+    /* 0xE0 */ op(LOOPNE, B, J8, N), op(LOOPE, B, J8, N),
+    /* 0xE2 */ op(LOOP, B, J8, N), op(JCXZ, B, J8, N),
+    /* 0xE4 */ op(IN, B, A, Ib),   op(IN, W, A, Ib),
+    /* 0xE6 */ op(OUT, B, Ib, A),  op(OUT, W, Ib, A),
+    /* 0xE8 */ op(CALL, W, J16, N), op(JMP, W, J16, N),
+    /* 0xEA */ op(JMP, W, Ap, N),  op(JMP, B, J8, N),
+    /* 0xEC */ op(IN, B, A, F(Register::DX)),
+    /* 0xED */ op(IN, W, A, F(Register::DX)),
+    /* 0xEE */ op(OUT, B, F(Register::DX), A),
+    /* 0xEF */ op(OUT, W, F(Register::DX), A),
+    /* 0xF0 */ pfx(LOCK),          pfx(LOCK),
+    /* 0xF2 */ pfx(REPNE),         pfx(REP),
+    /* 0xF4 */ op(HLT, B, N, N),   op(CMC, B, N, N),
+    /* 0xF6 */ grp(2, B, E, N),    grp(2, W, E, N),
+    /* 0xF8 */ op(CLC, B, N, N),   op(STC, B, N, N),
+    /* 0xFA */ op(CLI, B, N, N),   op(STI, B, N, N),
+    /* 0xFC */ op(CLD, B, N, N),   op(STD, B, N, N),
+    /* 0xFE */ grp(3, B, E, N),    grp(4, W, E, N),
+];
+
+/// Extended opcode groups, indexed by the ModR/M reg field.
+static GROUPS:[[Option<Mnemonic>; 8]; 5] = [
+    /* 0x80-0x83 */
+    [Some(ADD), Some(OR), Some(ADC), Some(SBB),
+     Some(AND), Some(SUB), Some(XOR), Some(CMP)],
+    /* 0xD0-0xD3 */
+    [Some(ROL), Some(ROR), Some(RCL), Some(RCR),
+     Some(SHL), Some(SHR), Some(SETMO), Some(SAR)],
+    /* 0xF6-0xF7 */
+    [Some(TEST), Some(TEST), Some(NOT), Some(NEG),
+     Some(MUL), Some(IMUL), Some(DIV), Some(IDIV)],
+    /* 0xFE */
+    [Some(INC), Some(DEC), None, None, None, None, None, None],
+    /* 0xFF */
+    [Some(INC), Some(DEC), Some(CALL), Some(CALL),
+     Some(JMP), Some(JMP), Some(PUSH), Some(PUSH)],
+];
+
+/// Primary opcode map of the V20. It takes the 80186 instructions where the
+/// 8088 has aliases: PUSHA to OUTSW at 0x60-0x6F, shifts by an immediate
+/// count at 0xC0/0xC1 and ENTER/LEAVE at 0xC8/0xC9. 0x0F introduces NEC's
+/// extended opcodes rather than popping CS. The REPC/REPNC prefixes and
+/// the second coprocessor escape at 0x63-0x67 are not emulated.
+fn v20_entry(opcode:u8) -> Entry {
+    match opcode {
+        0x0F => other(Kind::Extended),
+        0x60 => op(PUSHA, W, N, N),
+        0x61 => op(POPA, W, N, N),
+        0x62 => op(BOUND, W, G, E),
+        0x63..=0x67 => other(Kind::Invalid),
+        0x68 => op(PUSH, W, Iw, N),
+        0x69 => op3(IMUL, W, G, E, Iw),
+        0x6A => op(PUSH, W, Is, N),
+        0x6B => op3(IMUL, W, G, E, Is),
+        0x6C => op(INSB, B, N, N),
+        0x6D => op(INSW, W, N, N),
+        0x6E => op(OUTSB, B, N, N),
+        0x6F => op(OUTSW, W, N, N),
+        0xC0 => grp(1, B, E, Ib),
+        0xC1 => grp(1, W, E, Ib),
+        0xC8 => op(ENTER, W, Iw, Ib),
+        0xC9 => op(LEAVE, W, N, N),
+        _ => OPCODES[opcode as usize],
+    }
+}
+
+/// Extended opcodes of the V20, by the byte following 0x0F. The bit
+/// instructions come in byte and word sizes, numbering the bit with CL or
+/// an immediate. INS and EXT take the bit offset in their r/m register and
+/// the field length less one in the reg register or an immediate.
+fn v20_extended(opcode:u8) -> Option<Entry> {
+    let size = if opcode & 0x01 == 0 { B } else { W };
+    Some(match opcode {
+        0x10..=0x1F => {
+            let m = [TEST1, CLR1, SET1, NOT1][(opcode as usize >> 1) & 0x03];
+            let src = if opcode & 0x08 == 0 { F(Register::CL) } else { Ib };
+            op(m, size, E, src)
+        },
+        0x20 => op(ADD4S, B, N, N),
+        0x22 => op(SUB4S, B, N, N),
+        0x26 => op(CMP4S, B, N, N),
+        0x28 => op(ROL4, B, E, N),
+        0x2A => op(ROR4, B, E, N),
+        0x31 => op(INS, B, E, G),
+        0x33 => op(EXT, B, E, G),
+        0x39 => op(INS, B, E, Ib),
+        0x3B => op(EXT, B, E, Ib),
+        0xFF => op(BRKEM, B, Ib, N),
+        _ => return None,
+    })
+}
+
+fn far_opcode(opcode:u8) -> bool {
+    matches!(opcode, 0x9A | 0xEA | 0xC8 | 0xC9 | 0xCA | 0xCB)
+}
+
+// Only TEST takes an immediate in the 0xF6/0xF7 group, and /3 and /5 of
+// 0xFF are indirect inter-segment transfers.
+fn group_src(g:usize, m:Mnemonic, src:Spec) -> Spec {
+    if g == 2 && m == TEST { I } else { src }
+}
+
+fn group_far(g:usize, reg:u8) -> bool {
+    g == 4 && (reg == 3 || reg == 5)
+}
+
+/// A single encoding from the opcode map, as searched by the assembler.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Form {
+    pub opcode:u8,
+    pub group:Option<u8>, /* ModR/M reg field selecting a group member */
+    pub mnemonic:Mnemonic,
+    pub size:OperandSize,
+    pub dst:Spec,
+    pub src:Spec,
+    pub far:bool,
+}
+
+impl Form {
+    pub fn has_modrm(&self) -> bool {
+        self.group.is_some()
+            || [self.dst, self.src].iter().any(|s| matches!(s, E | G | S))
+    }
+}
+
+/// Every encoding in the opcode map, in opcode order.
+pub(crate) fn forms() -> impl Iterator<Item = Form> {
+    (0..=0xFFu8).flat_map(|opcode| {
+        let e = OPCODES[opcode as usize];
+        let form = |m, group, src, far| Form {
+            opcode, group, mnemonic:m, size:e.size, dst:e.dst, src, far
+        };
+        match e.kind {
+            Kind::Op(m) => vec![form(m, None, e.src, far_opcode(opcode))],
+            Kind::Group(g) => (0..8u8)
+                .filter_map(|reg| GROUPS[g][reg as usize].map(|m| {
+                    form(m, Some(reg), group_src(g, m, e.src), group_far(g, reg))
+                }))
+                .collect(),
+            _ => Vec::new(),
+        }
+    })
+}
+
+/// Counts the bytes consumed while decoding a single instruction.
+struct ByteStream<F:FnMut() -> u8> {
+    fetch:F,
+    len:u16,
+}
+
+impl<F:FnMut() -> u8> ByteStream<F> {
+    fn next_8(&mut self) -> u8 {
+        self.len = self.len.wrapping_add(1);
+        (self.fetch)()
+    }
+
+    fn next_16(&mut self) -> u16 {
+        let lo = self.next_8() as u16;
+        let hi = self.next_8() as u16;
+        (hi << 8) | lo
+    }
+}
+
+impl Instruction {
+    /// Decodes a single 8088 instruction, pulling bytes from [fetch] one at
+    /// a time. Prefixes are consumed until a non-prefix opcode is reached,
+    /// up to [MAX_PREFIXES] of them.
+    pub fn decode<F:FnMut() -> u8>(fetch:F) -> Result<Self, DecodeError> {
+        Self::decode_for(CpuModel::I8088, fetch)
+    }
+
+    /// Decodes a single instruction of [model].
+    pub fn decode_for<F:FnMut() -> u8>(model:CpuModel, fetch:F)
+                                       -> Result<Self, DecodeError> {
+        let mut bs = ByteStream { fetch, len:0 };
+        let mut segment:Option<Segment> = None;
+        let mut rep:Option<Mnemonic> = None;
+        let mut lock = false;
+        let mut prefixes:u16 = 0;
+
+        let (opcode, entry) = loop {
+            let opcode = bs.next_8();
+            let entry = match model {
+                CpuModel::I8088 => OPCODES[opcode as usize],
+                CpuModel::V20 => v20_entry(opcode),
+            };
+            if matches!(entry.kind, Kind::Segment(_) | Kind::Prefix(_)) {
+                if prefixes == MAX_PREFIXES {
+                    return Err(DecodeError::TooManyPrefixes);
+                }
+                prefixes += 1;
+            }
+            match entry.kind {
+                Kind::Segment(s) => segment = Some(s),
+                Kind::Prefix(LOCK) => lock = true,
+                Kind::Prefix(m) => rep = Some(m),
+                _ => break (opcode, entry),
+            }
+        };
+        let entry = match entry.kind {
+            Kind::Extended => v20_extended(bs.next_8())
+                .ok_or(DecodeError::UnknownOpcode(opcode))?,
+            _ => entry,
+        };
+
+        let needs_modrm = matches!(entry.kind, Kind::Group(_))
+            || [entry.dst, entry.src].iter().any(|s| matches!(s, E | G | S));
+        let modrm = if needs_modrm { Some(bs.next_8()) } else { None };
+        let reg = modrm.map_or(0, |m| (m >> 3) & 0x07);
+
+        let mut far = far_opcode(opcode)
+            && !matches!(entry.kind, Kind::Op(ENTER | LEAVE));
+        let mut src_spec = entry.src;
+        let mnemonic = match entry.kind {
+            Kind::Op(m) => m,
+            Kind::Group(g) => {
+                let m = GROUPS[g][reg as usize]
+                    .ok_or(DecodeError::UnknownOpcode(opcode))?;
+                src_spec = group_src(g, m, entry.src);
+                far = group_far(g, reg);
+                m
+            },
+            _ => return Err(DecodeError::UnimplementedOpcode(opcode)),
+        };
+
+        let mut ins = Instruction {
+            mnemonic,
+            opcode,
+            size:entry.size,
+            dst:Operand::None,
+            src:Operand::None,
+            src2:Operand::None,
+            modrm,
+            segment,
+            rep,
+            lock,
+            far,
+            len:0,
+            timing:Timing::default(),
+        };
+        // Displacement bytes always precede immediate data, so the r/m
+        // operand is resolved first regardless of operand order.
+        let rm = modrm.map(|m| Self::decode_rm(&mut bs, m, entry.size));
+        ins.dst = Self::decode_operand(&mut bs, entry.dst, &ins, rm);
+        ins.src = Self::decode_operand(&mut bs, src_spec, &ins, rm);
+        ins.src2 = Self::decode_operand(&mut bs, entry.extra, &ins, rm);
+        ins.len = bs.len;
+        ins.timing = match model {
+            CpuModel::I8088 => Timing::of(&ins, prefixes),
+            CpuModel::V20 => Timing::of_v20(&ins, prefixes),
+        };
+        Ok(ins)
+    }
+
+    fn decode_rm<F:FnMut() -> u8>(bs:&mut ByteStream<F>, modrm:u8,
+                                   size:OperandSize) -> Operand {
+        let md = modrm >> 6;
+        let rm = modrm & 0x07;
+        let disp = match (md, rm) {
+            (0b11, _) => return Operand::Register(match size {
+                OperandSize::Byte => Register::reg8(rm),
+                OperandSize::Word => Register::reg16(rm),
+            }),
+            (0b00, 0b110) | (0b10, _) => bs.next_16(),
+            (0b01, _) => bs.next_8() as i8 as u16,
+            _ => 0,
+        };
+        Operand::Memory(MemoryOperand { md, rm, disp })
+    }
+
+    fn decode_operand<F:FnMut() -> u8>(bs:&mut ByteStream<F>, spec:Spec,
+                                        ins:&Instruction,
+                                        rm:Option<Operand>) -> Operand {
+        let reg = ins.modrm.map_or(0, |m| (m >> 3) & 0x07);
+        let gpr = |idx:u8| match ins.size {
+            OperandSize::Byte => Register::reg8(idx),
+            OperandSize::Word => Register::reg16(idx),
+        };
+        match spec {
+            N => Operand::None,
+            E => rm.unwrap_or(Operand::None),
+            G => Operand::Register(gpr(reg)),
+            S => Operand::Register(Register::sreg(reg)),
+            A => Operand::Register(gpr(0)),
+            Z => Operand::Register(gpr(ins.opcode)),
+            F(r) => Operand::Register(r),
+            K(v) => Operand::Immediate(v),
+            I => match ins.size {
+                OperandSize::Byte => Operand::Immediate(bs.next_8() as u16),
+                OperandSize::Word => Operand::Immediate(bs.next_16()),
+            },
+            Ib => Operand::Immediate(bs.next_8() as u16),
+            Is => Operand::Immediate(bs.next_8() as i8 as u16),
+            Iw => Operand::Immediate(bs.next_16()),
+            J8 => Operand::Relative(bs.next_8() as i8 as i16),
+            J16 => Operand::Relative(bs.next_16() as i16),
+            Ap => {
+                let offset = bs.next_16();
+                let segment = bs.next_16();
+                Operand::Far(segment, offset)
+            },
+            O => Operand::Memory(MemoryOperand {
+                md:0b00,
+                rm:0b110,
+                disp:bs.next_16(),
+            }),
+            X => Operand::Immediate((((ins.opcode & 0x07) << 3) | reg) as u16),
+        }
+    }
+
+    /// Does this instruction access memory through its ModR/M operand?
+    pub fn has_memory_operand(&self) -> bool {
+        matches!(self.dst, Operand::Memory(_))
+            || matches!(self.src, Operand::Memory(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes:&[u8]) -> Result<Instruction, DecodeError> {
+        let mut it = bytes.iter().copied();
+        Instruction::decode(|| it.next().expect("decoder overran input"))
+    }
+
+    #[test]
+    fn test_decode_modrm() {
+        // mov word [bp+si-0x4], 0x1234
+        let ins = decode(&[0xC7, 0x42, 0xFC, 0x34, 0x12]).unwrap();
+        assert_eq!(ins.mnemonic, MOV);
+        assert_eq!(ins.size, OperandSize::Word);
+        assert_eq!(ins.dst, Operand::Memory(MemoryOperand {
+            md:0b01, rm:0b010, disp:0xFFFC
+        }));
+        assert_eq!(ins.src, Operand::Immediate(0x1234));
+        assert_eq!(ins.len, 5);
+
+        // add al, bh
+        let ins = decode(&[0x00, 0xF8]).unwrap();
+        assert_eq!(ins.dst, Operand::Register(Register::AL));
+        assert_eq!(ins.src, Operand::Register(Register::BH));
+
+        // mov ax, [0x1234]
+        let ins = decode(&[0xA1, 0x34, 0x12]).unwrap();
+        assert_eq!(ins.src, Operand::Memory(MemoryOperand {
+            md:0b00, rm:0b110, disp:0x1234
+        }));
+    }
+
+    #[test]
+    fn test_decode_groups() {
+        // sub sp, byte -0x2
+        let ins = decode(&[0x83, 0xEC, 0xFE]).unwrap();
+        assert_eq!(ins.mnemonic, SUB);
+        assert_eq!(ins.dst, Operand::Register(Register::SP));
+        assert_eq!(ins.src, Operand::Immediate(0xFFFE));
+
+        // test byte [bx], 0x80
+        let ins = decode(&[0xF6, 0x07, 0x80]).unwrap();
+        assert_eq!(ins.mnemonic, TEST);
+        assert_eq!(ins.src, Operand::Immediate(0x80));
+        assert_eq!(ins.len, 3);
+
+        // jmp far [bx+0x10]
+        let ins = decode(&[0xFF, 0x6F, 0x10]).unwrap();
+        assert_eq!(ins.mnemonic, JMP);
+        assert!(ins.far);
+
+        // shr dx, cl
+        let ins = decode(&[0xD3, 0xEA]).unwrap();
+        assert_eq!(ins.mnemonic, SHR);
+        assert_eq!(ins.src, Operand::Register(Register::CL));
+
+        assert!(matches!(decode(&[0xFE, 0xD0]),
+            Err(DecodeError::UnknownOpcode(0xFE))));
+    }
+
+    #[test]
+    fn test_decode_undocumented() {
+        let ins = decode(&[0xD6]).unwrap();
+        assert_eq!(ins.mnemonic, SALC);
+
+        let ins = decode(&[0x0F]).unwrap();
+        assert_eq!((ins.mnemonic, ins.dst), (POP, Operand::Register(Register::CS)));
+
+        // jz +4 through its alias
+        let ins = decode(&[0x64, 0x04]).unwrap();
+        assert_eq!((ins.mnemonic, ins.dst), (JZ, Operand::Relative(4)));
+
+        let ins = decode(&[0xC0, 0x04, 0x00]).unwrap();
+        assert_eq!((ins.mnemonic, ins.dst), (RET, Operand::Immediate(4)));
+        let ins = decode(&[0xC9]).unwrap();
+        assert_eq!(ins.mnemonic, RETF);
+        assert!(ins.far);
+
+        // lock alias followed by setmo ax, cl
+        let ins = decode(&[0xF1, 0xD3, 0xF0]).unwrap();
+        assert_eq!(ins.mnemonic, SETMO);
+        assert!(ins.lock);
+        assert_eq!(ins.dst, Operand::Register(Register::AX));
+        assert_eq!(ins.len, 3);
+
+        // test word [bx], 0x1234 through /1
+        let ins = decode(&[0xF7, 0x0F, 0x34, 0x12]).unwrap();
+        assert_eq!((ins.mnemonic, ins.src), (TEST, Operand::Immediate(0x1234)));
+        assert_eq!(ins.len, 4);
+        // push bx through /7
+        let ins = decode(&[0xFF, 0xFB]).unwrap();
+        assert_eq!((ins.mnemonic, ins.dst), (PUSH, Operand::Register(Register::BX)));
+        assert!(!ins.far);
+    }
+
+    #[test]
+    fn test_decode_prefixes() {
+        // es: rep movsb
+        let ins = decode(&[0x26, 0xF3, 0xA4]).unwrap();
+        assert_eq!(ins.mnemonic, MOVSB);
+        assert_eq!(ins.segment, Some(Segment::ES));
+        assert_eq!(ins.rep, Some(REP));
+        assert_eq!(ins.len, 3);
+
+        // A run of prefixes with no end, e.g. memory filled with 0x26.
+        let mut fill = std::iter::repeat(0x26);
+        assert!(matches!(Instruction::decode(|| fill.next().unwrap()),
+            Err(DecodeError::TooManyPrefixes)));
+        let mut bytes = vec![0xF3; MAX_PREFIXES as usize];
+        bytes.push(0xA4);
+        assert_eq!(decode(&bytes).unwrap().len, MAX_PREFIXES + 1);
+
+        // jmp 0xf000:0xe05b
+        let ins = decode(&[0xEA, 0x5B, 0xE0, 0x00, 0xF0]).unwrap();
+        assert_eq!(ins.dst, Operand::Far(0xF000, 0xE05B));
+        assert!(ins.far);
+    }
+}
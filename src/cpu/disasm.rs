@@ -0,0 +1,245 @@
+use std::fmt::{self, Write};
+use crate::{
+    core::bus::BusInterface,
+    cpu::{
+        I8088, CpuModel,
+        addr::Segment,
+        decode::{DecodeError, Instruction, MemoryOperand, Operand, OperandSize},
+        mnemonic::Mnemonic::*,
+    },
+};
+
+/// A decoded instruction together with the address and bytes it was
+/// decoded from.
+#[derive(Debug, Clone)]
+pub struct Disassembly {
+    pub segment:u16,
+    pub offset:u16,
+    pub bytes:Vec<u8>,
+    pub instruction:Result<Instruction, DecodeError>,
+}
+
+/// Columns printed in front of the instruction text.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DisasmFormat {
+    pub address:bool,
+    pub bytes:bool,
+}
+
+impl DisasmFormat {
+    pub const TEXT:DisasmFormat = DisasmFormat { address:false, bytes:false };
+    pub const FULL:DisasmFormat = DisasmFormat { address:true, bytes:true };
+}
+
+impl Disassembly {
+    /// Instruction text, with branch targets resolved to offsets in the
+    /// code segment. Bytes that do not decode are emitted as data.
+    pub fn text(&self) -> String {
+        let mut s = String::new();
+        match &self.instruction {
+            Ok(ins) => { let _ = render(&mut s, ins, Some(self.offset)); },
+            Err(_) => { let _ = write!(s, "db {:#04x}", self.bytes[0]); },
+        }
+        s
+    }
+
+    pub fn format(&self, fmt:DisasmFormat) -> String {
+        let mut s = String::new();
+        if fmt.address {
+            let _ = write!(s, "{:04X}:{:04X}  ", self.segment, self.offset);
+        }
+        if fmt.bytes {
+            let hex:String = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let _ = write!(s, "{:<14}", hex);
+        }
+        s + &self.text()
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(DisasmFormat::FULL))
+    }
+}
+
+/// NASM syntax. Relative branch targets are printed from `$`, the start of
+/// the instruction, as its address is not known.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        render(f, self, None)
+    }
+}
+
+/// Disassembles [count] instructions of [model] starting at
+/// [segment]:[offset]. Memory is read without driving the bus, and the
+/// offset wraps within the segment.
+pub fn disassemble(bus:&BusInterface, model:CpuModel, segment:u16, offset:u16, count:usize)
+                   -> Vec<Disassembly> {
+    let base = (segment as usize) << 4;
+    let mut offset = offset;
+    let mut out = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut bytes = Vec::new();
+        let mut o = offset;
+        let instruction = Instruction::decode_for(model, || {
+            let b = bus.peek_8((base + o as usize) & 0xFFFFF);
+            o = o.wrapping_add(1);
+            bytes.push(b);
+            b
+        });
+        // Undecodable bytes are skipped one at a time.
+        let len = match &instruction {
+            Ok(ins) => ins.len as usize,
+            Err(_) => 1,
+        };
+        bytes.truncate(len);
+        out.push(Disassembly { segment, offset, bytes, instruction });
+        offset = offset.wrapping_add(len as u16);
+    }
+    out
+}
+
+impl I8088 {
+    /// Disassembles [count] instructions from CS:IP.
+    pub fn disassemble(&self, count:usize) -> Vec<Disassembly> {
+        disassemble(&self.bus, self.model, self.cs, self.ip(), count)
+    }
+}
+
+fn render<W:Write>(w:&mut W, ins:&Instruction, ip:Option<u16>) -> fmt::Result {
+    let mem = ins.has_memory_operand();
+
+    if ins.lock { write!(w, "lock ")?; }
+    // Overrides are written into the memory operand where there is one.
+    if let (Some(sg), false) = (ins.segment, mem) {
+        write!(w, "{}: ", sg)?;
+    }
+    if let Some(rep) = ins.rep {
+        let name = match (rep, ins.mnemonic) {
+            (REPNE, _) => "repne",
+            (_, CMPSB | CMPSW | SCASB | SCASW) => "repe",
+            _ => "rep",
+        };
+        write!(w, "{} ", name)?;
+    }
+
+    match ins.mnemonic {
+        INT if ins.opcode == 0xCC => return write!(w, "int3"),
+        XLAT => return write!(w, "xlatb"),
+        JMP if ins.opcode == 0xEB => write!(w, "jmp short")?,
+        JMP | CALL if ins.far && mem => write!(w, "{} far", ins.mnemonic)?,
+        RET | RETN if ins.far => write!(w, "retf")?,
+        m => write!(w, "{}", m)?,
+    }
+
+    // Memory operands need a size unless a register operand implies it. The
+    // count of a shift does not.
+    let shift = matches!(ins.mnemonic, ROL | ROR | RCL | RCR | SHL | SAL
+        | SHR | SAR | SETMO | TEST1 | CLR1 | SET1 | NOT1);
+    let reg = [ins.dst, ins.src].iter().any(|o| matches!(o, Operand::Register(_)));
+    let sized = mem && !ins.far && ins.mnemonic != ESC && (shift || !reg);
+
+    let mut first = true;
+    for op in [ins.dst, ins.src, ins.src2] {
+        if op == Operand::None { continue; }
+        write!(w, "{}", if first { " " } else { ", " })?;
+        first = false;
+        match op {
+            Operand::Register(r) => write!(w, "{}", r)?,
+            Operand::Memory(m) => {
+                if sized {
+                    match ins.size {
+                        OperandSize::Byte => write!(w, "byte ")?,
+                        OperandSize::Word => write!(w, "word ")?,
+                    }
+                }
+                write_memory(w, &m, ins.segment)?;
+            },
+            Operand::Immediate(v) => write!(w, "{:#x}", v)?,
+            Operand::Relative(rel) => {
+                let n = ins.len as i32 + rel as i32;
+                match ip {
+                    Some(ip) => write!(w, "{:#06x}", ip.wrapping_add(n as u16))?,
+                    None if n < 0 => write!(w, "$-{:#x}", -n)?,
+                    None => write!(w, "$+{:#x}", n)?,
+                }
+            },
+            Operand::Far(sg, o) => write!(w, "{:#06x}:{:#06x}", sg, o)?,
+            Operand::None => {},
+        }
+    }
+    Ok(())
+}
+
+fn write_memory<W:Write>(w:&mut W, m:&MemoryOperand, seg:Option<Segment>)
+                         -> fmt::Result {
+    write!(w, "[")?;
+    if let Some(sg) = seg { write!(w, "{}:", sg)?; }
+    if m.is_direct() {
+        return write!(w, "{:#x}]", m.disp);
+    }
+    let base = match m.rm {
+        0b000 => "bx+si",
+        0b001 => "bx+di",
+        0b010 => "bp+si",
+        0b011 => "bp+di",
+        0b100 => "si",
+        0b101 => "di",
+        0b110 => "bp",
+        _ => "bx",
+    };
+    write!(w, "{}", base)?;
+    match m.md {
+        // 8-bit displacements are sign extended.
+        0b01 if (m.disp as i16) < 0 => write!(w, "-{:#x}", (m.disp as i16).unsigned_abs())?,
+        0b01 => write!(w, "+{:#x}", m.disp)?,
+        0b10 => write!(w, "+{:#x}", m.disp)?,
+        _ => {},
+    }
+    write!(w, "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes:&[u8]) -> String {
+        let mut it = bytes.iter().copied();
+        Instruction::decode(|| it.next().unwrap()).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_instruction_display() {
+        assert_eq!(text(&[0xC7, 0x42, 0xFC, 0x34, 0x12]),
+                   "mov word [bp+si-0x4], 0x1234");
+        assert_eq!(text(&[0x26, 0xF3, 0xA4]), "es: rep movsb");
+        assert_eq!(text(&[0xF3, 0xA6]), "repe cmpsb");
+        assert_eq!(text(&[0x26, 0x8B, 0x07]), "mov ax, [es:bx]");
+        assert_eq!(text(&[0xA1, 0x34, 0x12]), "mov ax, [0x1234]");
+        assert_eq!(text(&[0xD2, 0x27]), "shl byte [bx], cl");
+        assert_eq!(text(&[0xFF, 0x6F, 0x10]), "jmp far [bx+0x10]");
+        assert_eq!(text(&[0xEA, 0x5B, 0xE0, 0x00, 0xF0]), "jmp 0xf000:0xe05b");
+        assert_eq!(text(&[0xEB, 0xFE]), "jmp short $+0x0");
+        assert_eq!(text(&[0x75, 0xF0]), "jnz $-0xe");
+        assert_eq!(text(&[0xCC]), "int3");
+        assert_eq!(text(&[0xF3, 0xF6, 0xE3]), "rep mul bl");
+        assert_eq!(text(&[0xF0, 0x86, 0x1E, 0x00, 0x01]), "lock xchg [0x100], bl");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut bus = BusInterface::new();
+        // jnz -2; in al, 0x60; 0xFE /7 (invalid), leaving 0xF8 to decode as clc
+        bus.load(0xFFFF0, &[0x75, 0xFE, 0xE4, 0x60, 0xFE, 0xF8, 0xC3]).unwrap();
+        let d = disassemble(&bus, CpuModel::I8088, 0xF000, 0xFFF0, 4);
+        assert_eq!(d[0].to_string(), "F000:FFF0  75FE          jnz 0xfff0");
+        assert_eq!(d[1].format(DisasmFormat::TEXT), "in al, 0x60");
+        assert_eq!(d[2].text(), "db 0xfe");
+        assert_eq!((d[3].offset, d[3].text()), (0xFFF5, "clc".to_string()));
+
+        // 0x60 is an alias of jo on the 8088, but pusha on the V20.
+        bus.load(0x100, &[0x60, 0x00]).unwrap();
+        assert_eq!(disassemble(&bus, CpuModel::I8088, 0, 0x100, 1)[0].text(), "jo 0x0102");
+        assert_eq!(disassemble(&bus, CpuModel::V20, 0, 0x100, 1)[0].text(), "pusha");
+    }
+}
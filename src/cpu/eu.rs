@@ -1,17 +1,310 @@
-use crate::cpu::{I8088, CpuStatus, CpuError};
+use crate::cpu::{
+    I8088, CpuModel, CpuStatus, CpuError,
+    addr::Segment,
+    cycle::{BusCycle, BusStatus},
+    decode::{DecodeError, Instruction, Operand, OperandSize},
+    register::Register,
+};
+
+/// Operand after effective address calculation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Location {
+    Register(Register),
+    Memory(Segment, u16),
+    Immediate(u16),
+}
 
 impl I8088 {
     // Fetch, decode and execute one single instruction.
     pub fn advance(&mut self) -> Result<CpuStatus, CpuError> {
+        self.ins_clocks = 0;
+        self.ins_transfers = 0;
+        let status = self.advance_eu();
+        if !self.trace_bus {
+            self.prefetch_idle();
+        }
+        status
+    }
+
+    fn advance_eu(&mut self) -> Result<CpuStatus, CpuError> {
+        if self.halted {
+            let pending = self.interrupt_pending();
+            if !self.sample(pending) {
+                return Ok(self.halt_status());
+            }
+            self.halted = false;
+        }
+        if let Some(ins) = self.rep {
+            return self.advance_rep(&ins);
+        }
+
         // PC points to the next instruction to be fetched, not the next one
-        // to be executed. [adjust_pc] calculates the real IP.
-        let ip_real:u16 = self.adjust_pc();
-        if (self.is_breakpoint()) {
-            return Ok(CpuStatus::Breakpoint); 
+        // to be executed. [ip] calculates the real IP.
+        let ip_real:u16 = self.ip();
+        if self.is_breakpoint(ip_real) {
+            return Ok(CpuStatus::Breakpoint);
+        }
+        if self.service_interrupts() {
+            return Ok(CpuStatus::Normal);
+        }
+        if self.emulation {
+            return self.execute_8080();
+        }
+
+        let ins = match self.decode(ip_real) {
+            Ok(ins) => ins,
+            Err(e) => {
+                // Leave IP on the offending opcode for the debugger.
+                self.set_ip(ip_real);
+                return Err(CpuError::Decode(e));
+            },
+        };
+        self.execute_traced(&ins)
+    }
+
+    // Decodes the instruction at [ip] from the queue, unless the cache
+    // already holds it.
+    fn decode(&mut self, ip:u16) -> Result<Instruction, DecodeError> {
+        let model = self.model;
+        if !self.decode_cached() {
+            return Instruction::decode_for(model, || self.fetch_code_8());
+        }
+        if let Some(ins) = self.cached_instruction(ip) {
+            return Ok(ins);
+        }
+        let mut bytes = Vec::with_capacity(8);
+        let ins = Instruction::decode_for(model, || {
+            let b = self.fetch_code_8();
+            bytes.push(b);
+            b
+        })?;
+        self.cache_instruction(ip, &bytes, ins);
+        Ok(ins)
+    }
+
+    // Runs the next iteration of a repeated string instruction. Interrupts
+    // are taken between iterations, but the 8088 only backs IP up by one
+    // byte, onto the last prefix. Any earlier prefixes are lost when the
+    // instruction is resumed by IRET, e.g. ES: REP MOVSB continues as
+    // REP MOVSB from DS. The V20 backs up to the first prefix and resumes
+    // correctly.
+    fn advance_rep(&mut self, ins:&Instruction) -> Result<CpuStatus, CpuError> {
+        let pending = !self.interrupt_inhibit && self.interrupt_pending();
+        if self.sample(pending) {
+            // String instructions are a single opcode byte after prefixes.
+            let resume = match self.model {
+                CpuModel::I8088 => self.ip().wrapping_add(ins.len).wrapping_sub(2),
+                CpuModel::V20 => self.ip(),
+            };
+            self.set_ip(resume);
+            self.service_interrupts();
+            return Ok(CpuStatus::Normal);
         }
+        self.execute_traced(ins)
+    }
+
+    /// Single-steps for the emulator's own debugger, executing the
+    /// instruction at CS:IP even if it sits on a breakpoint. Unlike a guest
+    /// debugger, this does not go through TF, so the guest sees no INT 1
+    /// unless it armed one itself, in which case it is taken as the
+    /// following step.
+    pub fn step(&mut self) -> Result<CpuStatus, CpuError> {
+        self.resume_breakpoint();
+        self.advance()
+    }
+
+    /// Lets the next instruction run once even if it sits on a breakpoint,
+    /// to resume from one. [step] and the run functions call this before
+    /// they start, while [advance] and [cycle] keep stopping at a breakpoint
+    /// until it is called.
+    pub fn resume_breakpoint(&mut self) {
+        self.breakpoint_resume = self.rep.is_none() && !self.breakpoints.is_empty();
+    }
+
+    fn is_breakpoint(&mut self, ip:u16) -> bool {
+        let resume = std::mem::take(&mut self.breakpoint_resume);
+        let hit = !resume && !self.breakpoints.is_empty() && {
+            let addr = self.calculate_physical_address(Segment::CS, ip);
+            self.breakpoints.contains(&addr)
+        };
+        self.sample(hit)
+    }
+
+    pub fn set_breakpoint(&mut self, addr:u32) {
+        self.breakpoints.insert(addr & 0xFFFFF);
+    }
 
-        // TODO: check RET / IRET
+    pub fn clear_breakpoint(&mut self, addr:u32) {
+        self.breakpoints.remove(&(addr & 0xFFFFF));
+    }
 
+    /// Runs a bus cycle for the EU and returns the byte read, if any.
+    /// Instruction-level execution makes the transfer at once. Under [cycle]
+    /// the BIU makes it at the end of T3 of a bus cycle of its own, and the
+    /// EU stalls until then: it backs out of the instruction, and runs it
+    /// again once the cycle is over, picking up the results of the
+    /// transfers made so far.
+    pub(crate) fn run_bus_cycle(&mut self, status:BusStatus, addr:u32, data:u8) -> u8 {
+        let c = BusCycle { status, addr };
+        if !self.trace_bus {
+            self.ins_transfers += 1;
+            return self.bus_transfer(c, data);
+        }
+        if let Some(v) = self.replay() {
+            return v;
+        }
+        if !self.stalled {
+            self.stalled = true;
+            self.eu.request = Some((c, data));
+        }
+        0xFF
+    }
+
+    // Next result logged by an earlier run of the stalled instruction.
+    pub(crate) fn replay(&mut self) -> Option<u8> {
+        if self.stalled { return None; }
+        let v = *self.eu.log.get(self.transfer_index)?;
+        self.transfer_index += 1;
+        Some(v)
+    }
+
+    /// Reads an input the EU acts on, such as INTR, which may change while
+    /// [cycle] has the EU stalled. An instruction run again replays the
+    /// first reading, so that it takes the path it started on.
+    pub(crate) fn sample(&mut self, v:bool) -> bool {
+        if !self.trace_bus || self.stalled { return v; }
+        if let Some(s) = self.replay() {
+            return s != 0;
+        }
+        self.eu.log.push(v as u8);
+        self.transfer_index += 1;
+        v
+    }
+
+    // Memory outside of the installed RAM floats high on the 8088 bus, and
+    // writes to it are lost. The first INTA cycle only freezes the priority
+    // logic of the interrupt controller, the vector is read in the second.
+    pub(crate) fn bus_transfer(&mut self, c:BusCycle, data:u8) -> u8 {
+        match c.status {
+            BusStatus::CodeFetch | BusStatus::MemRead => {
+                self.bus.read_8(c.addr as usize).unwrap_or(0xFF)
+            },
+            BusStatus::MemWrite => {
+                let _ = self.bus.write_8(c.addr as usize, data);
+                0
+            },
+            BusStatus::IoRead => self.bus.io_read_8(c.addr as u16),
+            BusStatus::IoWrite => {
+                self.bus.io_write_8(c.addr as u16, data);
+                0
+            },
+            BusStatus::InterruptAck if c.addr == 1 => self.bus.acknowledge_interrupt(),
+            BusStatus::InterruptAck | BusStatus::Halt | BusStatus::Passive => 0,
+        }
+    }
+
+    pub(crate) fn read_phys_8(&mut self, addr:u32) -> u8 {
+        self.run_bus_cycle(BusStatus::MemRead, addr, 0)
+    }
+
+    pub(crate) fn write_phys_8(&mut self, addr:u32, val:u8) {
+        self.run_bus_cycle(BusStatus::MemWrite, addr, val);
+    }
+
+    pub(crate) fn read_phys_16(&mut self, addr:u32) -> u16 {
+        let lo = self.read_phys_8(addr) as u16;
+        let hi = self.read_phys_8((addr + 1) & 0xFFFFF) as u16;
+        (hi << 8) | lo
+    }
+
+    pub(crate) fn read_mem_8(&mut self, s:Segment, o:u16) -> u8 {
+        let addr = self.calculate_physical_address(s, o);
+        self.read_phys_8(addr)
+    }
+
+    pub(crate) fn write_mem_8(&mut self, s:Segment, o:u16, val:u8) {
+        let addr = self.calculate_physical_address(s, o);
+        self.write_phys_8(addr, val);
+    }
+
+    // Word accesses are split into two byte transfers on the 8-bit bus. The
+    // offset of the second byte wraps within the segment.
+    pub(crate) fn read_mem_16(&mut self, s:Segment, o:u16) -> u16 {
+        let lo = self.read_mem_8(s, o) as u16;
+        let hi = self.read_mem_8(s, o.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    pub(crate) fn write_mem_16(&mut self, s:Segment, o:u16, val:u16) {
+        self.write_mem_8(s, o, val as u8);
+        self.write_mem_8(s, o.wrapping_add(1), (val >> 8) as u8);
+    }
+
+    pub(crate) fn read_io_8(&mut self, port:u16) -> u8 {
+        self.run_bus_cycle(BusStatus::IoRead, port as u32, 0)
+    }
+
+    pub(crate) fn write_io_8(&mut self, port:u16, val:u8) {
+        self.run_bus_cycle(BusStatus::IoWrite, port as u32, val);
+    }
+
+    pub(crate) fn push(&mut self, val:u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.write_mem_16(Segment::SS, self.sp, val);
+    }
+
+    pub(crate) fn pop(&mut self) -> u16 {
+        let val = self.read_mem_16(Segment::SS, self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        val
+    }
+
+    pub(crate) fn resolve(&mut self, op:&Operand, ins:&Instruction)
+                          -> Location {
+        match *op {
+            Operand::Register(r) => Location::Register(r),
+            Operand::Memory(m) => {
+                let ea = self.calculate_effective_address(&m, ins.segment);
+                Location::Memory(ea.segment, ea.offset)
+            },
+            Operand::Immediate(v) => Location::Immediate(v),
+            Operand::Relative(rel) => {
+                Location::Immediate(self.ip().wrapping_add(rel as u16))
+            },
+            Operand::Far(_, o) => Location::Immediate(o),
+            Operand::None => Location::Immediate(0),
+        }
+    }
+
+    pub(crate) fn read_loc(&mut self, loc:Location, s:OperandSize) -> u16 {
+        match (loc, s) {
+            (Location::Register(r), _) => self.get(r),
+            (Location::Memory(sg, o), OperandSize::Byte) => {
+                self.read_mem_8(sg, o) as u16
+            },
+            (Location::Memory(sg, o), OperandSize::Word) => {
+                self.read_mem_16(sg, o)
+            },
+            (Location::Immediate(v), _) => v & s.mask(),
+        }
+    }
+
+    pub(crate) fn write_loc(&mut self, loc:Location, s:OperandSize, val:u16) {
+        match (loc, s) {
+            (Location::Register(r), _) => self.set(r, val),
+            (Location::Memory(sg, o), OperandSize::Byte) => {
+                self.write_mem_8(sg, o, val as u8)
+            },
+            (Location::Memory(sg, o), OperandSize::Word) => {
+                self.write_mem_16(sg, o, val)
+            },
+            (Location::Immediate(_), _) => {},
+        }
+    }
 
+    pub(crate) fn read_operand(&mut self, op:&Operand, ins:&Instruction,
+                               s:OperandSize) -> u16 {
+        let loc = self.resolve(op, ins);
+        self.read_loc(loc, s)
     }
 }
@@ -1,14 +1,563 @@
-#[derive(Debug, Clone])
-pub enum ExecutionStatus {
-    UnknownOpcode(u8),
-    Okay,
-}
+use crate::cpu::{
+    I8088, CpuModel, CpuStatus, CpuError,
+    addr::Segment,
+    decode::{Instruction, Operand, OperandSize},
+    eu::Location,
+    flags::*,
+    interrupt::*,
+    mnemonic::Mnemonic::{self, *},
+    register::Register,
+    timing::{div_clocks, mul_clocks},
+};
+
+impl I8088 {
+    /// Executes a decoded instruction. IP already points past it.
+    pub fn execute(&mut self, ins:&Instruction) -> Result<CpuStatus, CpuError> {
+        let s = ins.size;
+        self.ins_clocks = ins.timing.clocks(false);
+        match ins.mnemonic {
+            ADD | ADC | SUB | SBB | CMP | AND | OR | XOR | TEST => {
+                let dst = self.resolve(&ins.dst, ins);
+                let a = self.read_loc(dst, s);
+                let b = self.read_operand(&ins.src, ins, s);
+                let res = match ins.mnemonic {
+                    ADD => self.alu_add(a, b, false, s),
+                    ADC => {
+                        let cf = self.flag(FLAG_CF);
+                        self.alu_add(a, b, cf, s)
+                    },
+                    SUB | CMP => self.alu_sub(a, b, false, s),
+                    SBB => {
+                        let cf = self.flag(FLAG_CF);
+                        self.alu_sub(a, b, cf, s)
+                    },
+                    AND | TEST => self.alu_logic(a & b, s),
+                    OR => self.alu_logic(a | b, s),
+                    _ => self.alu_logic(a ^ b, s),
+                };
+                if !matches!(ins.mnemonic, CMP | TEST) {
+                    self.write_loc(dst, s, res);
+                }
+            },
+            INC | DEC | NOT | NEG => {
+                let dst = self.resolve(&ins.dst, ins);
+                let a = self.read_loc(dst, s);
+                let res = match ins.mnemonic {
+                    INC => self.alu_inc(a, s),
+                    DEC => self.alu_dec(a, s),
+                    NOT => !a & s.mask(),
+                    _ => self.alu_sub(0, a, false, s),
+                };
+                self.write_loc(dst, s, res);
+            },
+            ROL | ROR | RCL | RCR | SHL | SAL | SHR | SAR | SETMO => {
+                let dst = self.resolve(&ins.dst, ins);
+                let a = self.read_loc(dst, s);
+                let count = self.read_operand(&ins.src, ins, OperandSize::Byte);
+                if ins.src == Operand::Register(Register::CL) {
+                    let per_bit = match self.model {
+                        CpuModel::I8088 => 4,
+                        CpuModel::V20 => 1,
+                    };
+                    self.ins_clocks += per_bit * (count as u32 & 0xFF);
+                }
+                let res = self.alu_shift(ins.mnemonic, a, count as u8, s);
+                self.write_loc(dst, s, res);
+            },
+            IMUL if ins.src2 != Operand::None => self.execute_v20(ins),
+            MUL | IMUL => {
+                let b = self.read_operand(&ins.dst, ins, s);
+                if self.model == CpuModel::I8088 {
+                    self.ins_clocks += mul_clocks(ins.mnemonic, s, self.ax, b);
+                }
+                let (hi, lo) = if ins.mnemonic == MUL {
+                    self.alu_mul(self.ax, b, s)
+                } else {
+                    self.alu_imul(self.ax, b, s)
+                };
+                match s {
+                    OperandSize::Byte => self.ax = (hi << 8) | lo,
+                    OperandSize::Word => { self.dx = hi; self.ax = lo; },
+                }
+            },
+            DIV | IDIV => {
+                let d = self.read_operand(&ins.dst, ins, s);
+                let hi = match s {
+                    OperandSize::Byte => self.ax >> 8,
+                    OperandSize::Word => self.dx,
+                };
+                let res = if ins.mnemonic == DIV {
+                    self.alu_div(hi, self.ax, d, s)
+                } else {
+                    self.alu_idiv(hi, self.ax, d, s)
+                };
+                if let (Ok((q, _)), CpuModel::I8088) = (&res, self.model) {
+                    self.ins_clocks += div_clocks(ins.mnemonic, s, hi, d, *q);
+                }
+                match (res, s) {
+                    (Ok((q, r)), OperandSize::Byte) => self.ax = (r << 8) | q,
+                    (Ok((q, r)), OperandSize::Word) => {
+                        self.ax = q;
+                        self.dx = r;
+                    },
+                    (Err(_), _) => self.divide_error(),
+                }
+            },
+            DAA => {
+                let al = self.alu_daa(self.ax as u8);
+                self.set(Register::AL, al as u16);
+            },
+            DAS => {
+                let al = self.alu_das(self.ax as u8);
+                self.set(Register::AL, al as u16);
+            },
+            AAA => self.ax = self.alu_aaa(self.ax),
+            AAS => self.ax = self.alu_aas(self.ax),
+            AAM => {
+                let base = self.adjust_base(ins);
+                match self.alu_aam(self.ax as u8, base as u8) {
+                    Ok(ax) => self.ax = ax,
+                    Err(_) => self.divide_error(),
+                }
+            },
+            AAD => {
+                let base = self.adjust_base(ins);
+                self.ax = self.alu_aad(self.ax, base as u8);
+            },
+            CBW => self.ax = self.ax as u8 as i8 as i16 as u16,
+            CWD => self.dx = if self.ax & 0x8000 != 0 { 0xFFFF } else { 0 },
+
+            MOV => {
+                let v = self.read_operand(&ins.src, ins, s);
+                let dst = self.resolve(&ins.dst, ins);
+                self.write_loc(dst, s, v);
+                self.inhibit_after_segment_load(dst);
+            },
+            XCHG => {
+                let dst = self.resolve(&ins.dst, ins);
+                let src = self.resolve(&ins.src, ins);
+                let a = self.read_loc(dst, s);
+                let b = self.read_loc(src, s);
+                self.write_loc(dst, s, b);
+                self.write_loc(src, s, a);
+            },
+            LEA => {
+                if let Operand::Memory(m) = ins.src {
+                    let ea = self.calculate_effective_address(&m, None);
+                    let dst = self.resolve(&ins.dst, ins);
+                    self.write_loc(dst, s, ea.offset);
+                }
+            },
+            LDS | LES => {
+                if let Location::Memory(sg, o) = self.resolve(&ins.src, ins) {
+                    let off = self.read_mem_16(sg, o);
+                    let seg = self.read_mem_16(sg, o.wrapping_add(2));
+                    let dst = self.resolve(&ins.dst, ins);
+                    self.write_loc(dst, s, off);
+                    if ins.mnemonic == LDS { self.ds = seg; } else { self.es = seg; }
+                }
+            },
+            // Undocumented, sets AL from CF without affecting flags.
+            SALC => {
+                let al = if self.flag(FLAG_CF) { 0xFF } else { 0x00 };
+                self.set(Register::AL, al);
+            },
+            XLAT => {
+                let sg = ins.segment.unwrap_or(Segment::DS);
+                let o = self.bx.wrapping_add(self.ax & 0xFF);
+                let al = self.read_mem_8(sg, o);
+                self.set(Register::AL, al as u16);
+            },
+            LAHF => self.set(Register::AH, self.flags() & 0xFF),
+            SAHF => {
+                let ah = self.ax >> 8;
+                self.load_flags((self.flags() & !FLAGS_LOW_MASK)
+                    | (ah & FLAGS_LOW_MASK));
+            },
+
+            PUSH => {
+                // The 8088 pushes the already decremented value of SP.
+                let v = match ins.dst {
+                    Operand::Register(Register::SP) => self.sp.wrapping_sub(2),
+                    _ => self.read_operand(&ins.dst, ins, s),
+                };
+                self.push(v);
+            },
+            POP => {
+                let v = self.pop();
+                let dst = self.resolve(&ins.dst, ins);
+                self.write_loc(dst, s, v);
+                self.inhibit_after_segment_load(dst);
+            },
+            PUSHF => self.push(self.flags()),
+            POPF => {
+                let v = self.pop();
+                self.load_flags(v);
+            },
+
+            IN => {
+                let port = self.read_operand(&ins.src, ins, OperandSize::Word);
+                let lo = self.read_io_8(port) as u16;
+                match s {
+                    OperandSize::Byte => self.set(Register::AL, lo),
+                    OperandSize::Word => {
+                        let hi = self.read_io_8(port.wrapping_add(1)) as u16;
+                        self.ax = (hi << 8) | lo;
+                    },
+                }
+            },
+            OUT => {
+                let port = self.read_operand(&ins.dst, ins, OperandSize::Word);
+                self.write_io_8(port, self.ax as u8);
+                if s == OperandSize::Word {
+                    self.write_io_8(port.wrapping_add(1), (self.ax >> 8) as u8);
+                }
+            },
+
+            JMP | CALL => self.transfer(ins),
+            RET | RETN | RETF => {
+                let ip = self.pop();
+                if ins.far || ins.mnemonic == RETF {
+                    self.cs = self.pop();
+                }
+                self.set_ip(ip);
+                if let Operand::Immediate(n) = ins.dst {
+                    self.sp = self.sp.wrapping_add(n);
+                }
+            },
+            IRET => {
+                let ip = self.pop();
+                self.cs = self.pop();
+                self.set_ip(ip);
+                let v = self.pop();
+                self.load_flags(v);
+            },
+            INT => {
+                let v = self.read_operand(&ins.dst, ins, OperandSize::Byte);
+                self.interrupt(v as u8);
+            },
+            INTO => {
+                if self.flag(FLAG_OF) {
+                    self.ins_clocks += ins.timing.taken as u32;
+                    self.interrupt(VECTOR_OVERFLOW);
+                }
+            },
+            LOOP | LOOPE | LOOPZ | LOOPNE | LOOPNZ => {
+                self.cx = self.cx.wrapping_sub(1);
+                let zf = self.flag(FLAG_ZF);
+                let taken = self.cx != 0 && match ins.mnemonic {
+                    LOOPE | LOOPZ => zf,
+                    LOOPNE | LOOPNZ => !zf,
+                    _ => true,
+                };
+                if taken { self.jump_relative(ins); }
+            },
+            JCXZ => {
+                if self.cx == 0 { self.jump_relative(ins); }
+            },
+            MOVSB | MOVSW | CMPSB | CMPSW | STOSB | STOSW | LODSB | LODSW
+            | SCASB | SCASW | INSB | INSW | OUTSB | OUTSW => self.string(ins),
+            PUSHA | POPA | BOUND | ENTER | LEAVE | TEST1 | CLR1 | SET1 | NOT1
+            | ADD4S | SUB4S | CMP4S | ROL4 | ROR4 | INS | EXT | BRKEM => {
+                self.execute_v20(ins)
+            },
+
+            CLC => self.set_flag(FLAG_CF, false),
+            STC => self.set_flag(FLAG_CF, true),
+            CMC => self.set_flag(FLAG_CF, !self.flag(FLAG_CF)),
+            CLD => self.set_flag(FLAG_DF, false),
+            STD => self.set_flag(FLAG_DF, true),
+            CLI => self.set_flag(FLAG_IF, false),
+            STI => {
+                // Interrupts are enabled after the following instruction.
+                self.interrupt_inhibit |= !self.flag(FLAG_IF);
+                self.set_flag(FLAG_IF, true);
+            },
+
+            HLT => return Ok(self.halt()),
+            ESC => self.escape(ins),
+            WAIT => self.wait_for_coprocessor(),
+            NOP => {},
+            // Prefixes are folded into the instruction by the decoder.
+            LOCK | REP | REPE | REPZ | REPNE | REPNZ => {},
+            m => match self.condition(m) {
+                Some(true) => self.jump_relative(ins),
+                Some(false) => {},
+                None => unreachable!("{:?} has no execution semantics", m),
+            },
+        }
+        Ok(CpuStatus::Normal)
+    }
+
+    /// Evaluates the condition of a conditional jump, or returns [None] if
+    /// [m] is not one.
+    pub fn condition(&self, m:Mnemonic) -> Option<bool> {
+        let (cf, zf) = (self.flag(FLAG_CF), self.flag(FLAG_ZF));
+        let (sf, of) = (self.flag(FLAG_SF), self.flag(FLAG_OF));
+        let pf = self.flag(FLAG_PF);
+        Some(match m {
+            JO => of,
+            JNO => !of,
+            JB | JC | JNAE => cf,
+            JNB | JAE | JNC => !cf,
+            JZ | JE => zf,
+            JNZ | JNE => !zf,
+            JBE | JNA => cf || zf,
+            JA | JNBE => !(cf || zf),
+            JS => sf,
+            JNS => !sf,
+            JP | JPE => pf,
+            JNP | JPO => !pf,
+            JL | JNGE => sf != of,
+            JNL | JGE => sf == of,
+            JLE | JNG => zf || sf != of,
+            JG | JNLE => !zf && sf == of,
+            _ => return None,
+        })
+    }
 
-impl fmt::Display for ExecutionStatus {
-    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ExecutionStatus::UnknownOpcode => write!(f, "Unknown opcode."),
-            ExecutionStatus::Okay => write!(f, "Execution OK"),
+    // The V20 ignores the immediate of AAM and AAD and always works in
+    // decimal.
+    fn adjust_base(&mut self, ins:&Instruction) -> u16 {
+        match self.model {
+            CpuModel::I8088 => self.read_operand(&ins.dst, ins, OperandSize::Byte),
+            CpuModel::V20 => 10,
         }
     }
+
+    fn jump_relative(&mut self, ins:&Instruction) {
+        self.ins_clocks += ins.timing.taken as u32;
+        if let Operand::Relative(rel) = ins.dst {
+            self.set_ip(self.ip().wrapping_add(rel as u16));
+        }
+    }
+
+    /// JMP and CALL in all their near, far, direct and indirect forms.
+    fn transfer(&mut self, ins:&Instruction) {
+        let call = ins.mnemonic == CALL;
+        let (seg, off) = match ins.dst {
+            Operand::Far(sg, o) => (Some(sg), o),
+            _ if ins.far => match self.resolve(&ins.dst, ins) {
+                Location::Memory(sg, o) => {
+                    let off = self.read_mem_16(sg, o);
+                    (Some(self.read_mem_16(sg, o.wrapping_add(2))), off)
+                },
+                loc => (None, self.read_loc(loc, OperandSize::Word)),
+            },
+            _ => {
+                let loc = self.resolve(&ins.dst, ins);
+                (None, self.read_loc(loc, OperandSize::Word))
+            },
+        };
+        if call {
+            if seg.is_some() { self.push(self.cs); }
+            self.push(self.ip());
+        }
+        if let Some(sg) = seg { self.cs = sg; }
+        self.set_ip(off);
+    }
+
+    /// String instructions, repeated while CX is non-zero if prefixed with
+    /// REP. CMPS and SCAS additionally stop on the ZF condition. Each step
+    /// runs one iteration, and the instruction stays current until the last.
+    fn string(&mut self, ins:&Instruction) {
+        let Some(rep) = ins.rep else {
+            self.string_iteration(ins);
+            return;
+        };
+        // Later steps only take the clocks of their iteration.
+        if self.rep.take().is_some() {
+            self.ins_clocks = 0;
+        }
+        if self.cx == 0 { return; }
+
+        self.ins_clocks += ins.timing.repeat as u32;
+        self.string_iteration(ins);
+        self.cx = self.cx.wrapping_sub(1);
+        let done = self.cx == 0
+            || (matches!(ins.mnemonic, CMPSB | CMPSW | SCASB | SCASW)
+                && self.flag(FLAG_ZF) == (rep == REPNE));
+        if !done {
+            self.rep = Some(*ins);
+        }
+    }
+
+    // The 8088 holds off interrupts for one instruction after any segment
+    // register is loaded, not only SS.
+    fn inhibit_after_segment_load(&mut self, dst:Location) {
+        if let Location::Register(r) = dst {
+            if r.segment().is_some() {
+                self.interrupt_inhibit = true;
+            }
+        }
+    }
+
+    fn string_iteration(&mut self, ins:&Instruction) {
+        let s = ins.size;
+        let src = ins.segment.unwrap_or(Segment::DS);
+        let delta = if self.flag(FLAG_DF) {
+            s.bytes().wrapping_neg()
+        } else {
+            s.bytes()
+        };
+        let read = |cpu:&mut Self, sg:Segment, o:u16| match s {
+            OperandSize::Byte => cpu.read_mem_8(sg, o) as u16,
+            OperandSize::Word => cpu.read_mem_16(sg, o),
+        };
+        let acc = self.ax & s.mask();
+        match ins.mnemonic {
+            MOVSB | MOVSW => {
+                let v = read(self, src, self.si);
+                self.write_loc(Location::Memory(Segment::ES, self.di), s, v);
+            },
+            CMPSB | CMPSW => {
+                let a = read(self, src, self.si);
+                let b = read(self, Segment::ES, self.di);
+                self.alu_sub(a, b, false, s);
+            },
+            STOSB | STOSW => {
+                self.write_loc(Location::Memory(Segment::ES, self.di), s, acc);
+            },
+            LODSB | LODSW => {
+                let v = read(self, src, self.si);
+                let dst = if s == OperandSize::Byte { Register::AL } else { Register::AX };
+                self.set(dst, v);
+            },
+            INSB | INSW => {
+                let mut v = self.read_io_8(self.dx) as u16;
+                if s == OperandSize::Word {
+                    v |= (self.read_io_8(self.dx.wrapping_add(1)) as u16) << 8;
+                }
+                self.write_loc(Location::Memory(Segment::ES, self.di), s, v);
+            },
+            OUTSB | OUTSW => {
+                let v = read(self, src, self.si);
+                self.write_io_8(self.dx, v as u8);
+                if s == OperandSize::Word {
+                    self.write_io_8(self.dx.wrapping_add(1), (v >> 8) as u8);
+                }
+            },
+            _ => {
+                let b = read(self, Segment::ES, self.di);
+                self.alu_sub(acc, b, false, s);
+            },
+        }
+        if matches!(ins.mnemonic, MOVSB | MOVSW | CMPSB | CMPSW | LODSB | LODSW
+            | OUTSB | OUTSW) {
+            self.si = self.si.wrapping_add(delta);
+        }
+        if !matches!(ins.mnemonic, LODSB | LODSW | OUTSB | OUTSW) {
+            self.di = self.di.wrapping_add(delta);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::testing::run;
+
+    #[test]
+    fn test_execute_arithmetic() {
+        let mut cpu = I8088::new();
+        // mov ax, 0x7fff; add ax, 1; adc bl, 0xff
+        run(&mut cpu, &[0xB8, 0xFF, 0x7F, 0x05, 0x01, 0x00, 0x80, 0xD3, 0xFF]);
+        assert_eq!(cpu.ax, 0x8000);
+        assert_eq!(cpu.bx, 0x00FF);
+        assert!(!cpu.flag(FLAG_OF));
+        assert!(cpu.flag(FLAG_SF));
+
+        // mov ax, 0x0010; mov cl, 0x10; div cl
+        let mut cpu = I8088::new();
+        run(&mut cpu, &[0xB8, 0x10, 0x00, 0xB1, 0x10, 0xF6, 0xF1]);
+        assert_eq!(cpu.ax, 0x0001);
+    }
+
+    #[test]
+    fn test_execute_stack_and_calls() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        // call +3; nop; nop; nop; push sp; pop bx; ret
+        cpu.bus.load(0x100, &[0xE8, 0x03, 0x00, 0x90, 0x90, 0x90,
+                              0x54, 0x5B, 0xC3]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x106);
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x103);
+        cpu.advance().unwrap();
+        cpu.advance().unwrap();
+        assert_eq!(cpu.bx, 0x0FFC);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x103);
+        assert_eq!(cpu.sp, 0x1000);
+    }
+
+    #[test]
+    fn test_execute_rep_string() {
+        let mut cpu = I8088::new();
+        cpu.bus.load(0x200, b"hello").unwrap();
+        // mov si, 0x200; mov di, 0x300; mov cx, 5; rep movsb
+        run(&mut cpu, &[0xBE, 0x00, 0x02, 0xBF, 0x00, 0x03, 0xB9, 0x05, 0x00,
+                        0xF3, 0xA4]);
+        assert_eq!(cpu.cx, 0);
+        assert_eq!(cpu.di, 0x305);
+        assert_eq!(cpu.read_mem_8(Segment::ES, 0x304), b'o');
+
+        // One iteration per step, with IP on the prefix until the last.
+        cpu.bus.load(0x100, &[0xF3, 0xAA]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.cx = 3;
+        cpu.advance().unwrap();
+        assert_eq!((cpu.ip(), cpu.cx), (0x100, 2));
+        cpu.advance().unwrap();
+        cpu.advance().unwrap();
+        assert_eq!((cpu.ip(), cpu.cx), (0x102, 0));
+
+        // mov di, 0x300; mov cx, 5; mov al, 'l'; repne scasb
+        run(&mut cpu, &[0xBF, 0x00, 0x03, 0xB9, 0x05, 0x00, 0xB0, b'l',
+                        0xF2, 0xAE]);
+        assert_eq!(cpu.di, 0x303);
+        assert_eq!(cpu.cx, 2);
+        assert!(cpu.flag(FLAG_ZF));
+    }
+
+    #[test]
+    fn test_execute_undocumented() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        // stc; salc; setmo bx, 1; push ax; pop cs
+        run(&mut cpu, &[0xF9, 0xD6, 0xD1, 0xF3, 0x50, 0x0F]);
+        assert_eq!(cpu.ax & 0xFF, 0xFF);
+        assert_eq!(cpu.bx, 0xFFFF);
+        assert!(!cpu.flag(FLAG_CF));
+        assert!(cpu.flag(FLAG_SF) && cpu.flag(FLAG_PF));
+        assert_eq!(cpu.cs, 0x00FF);
+    }
+
+    #[test]
+    fn test_execute_interrupts() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        // INT 0 handler at 0050:0000
+        cpu.bus.load(0x00, &[0x00, 0x00, 0x50, 0x00]).unwrap();
+        cpu.set_flag(FLAG_IF, true);
+        // xor cl, cl; div cl
+        cpu.bus.load(0x100, &[0x30, 0xC9, 0xF6, 0xF1]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        cpu.advance().unwrap();
+        assert_eq!(cpu.cs, 0x0050);
+        assert_eq!(cpu.ip(), 0x0000);
+        assert!(!cpu.flag(FLAG_IF));
+        // The 8088 pushes the address following DIV.
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x104);
+
+        // aam 0
+        cpu.cs = 0x0000;
+        cpu.bus.load(0x100, &[0xD4, 0x00]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0050, 0x0000));
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x102);
+    }
 }
@@ -0,0 +1,294 @@
+use std::fmt;
+use crate::cpu::{I8088, CpuModel, decode::OperandSize};
+
+/// FLAGS register bits
+/// ------------------------------------------------------
+/// Carry out of / borrow into the high-order bit.
+pub const FLAG_CF:u16                       = 0b0000_0000_0000_0001;
+/// Low-order 8 bits of the result contain an even number of set bits.
+pub const FLAG_PF:u16                       = 0b0000_0000_0000_0100;
+/// Carry out of / borrow into bit 3, used for BCD arithmetic.
+pub const FLAG_AF:u16                       = 0b0000_0000_0001_0000;
+pub const FLAG_ZF:u16                       = 0b0000_0000_0100_0000;
+pub const FLAG_SF:u16                       = 0b0000_0000_1000_0000;
+/// Trap flag - single-step mode.
+pub const FLAG_TF:u16                       = 0b0000_0001_0000_0000;
+/// Interrupt-enable flag - maskable interrupts are recognized when set.
+pub const FLAG_IF:u16                       = 0b0000_0010_0000_0000;
+/// Direction flag - string instructions decrement SI/DI when set.
+pub const FLAG_DF:u16                       = 0b0000_0100_0000_0000;
+pub const FLAG_OF:u16                       = 0b0000_1000_0000_0000;
+/// Mode flag of the V20 - native mode when set, 8080 emulation when clear.
+pub const FLAG_MD:u16                       = 0b1000_0000_0000_0000;
+
+/// Bits 12-15 and bit 1 are hardwired to 1 on the 8088, bits 3 and 5 to 0.
+pub const FLAGS_FIXED:u16                   = 0b1111_0000_0000_0010;
+/// Bits that can be changed by POPF and IRET.
+pub const FLAGS_WRITABLE_MASK:u16           = 0b0000_1111_1101_0101;
+
+/// Flags loaded by SAHF and stored by LAHF.
+pub const FLAGS_LOW_MASK:u16 = FLAG_SF | FLAG_ZF | FLAG_AF | FLAG_PF | FLAG_CF;
+/// Flags affected by arithmetic instructions.
+pub const FLAGS_ARITH_MASK:u16 = FLAGS_LOW_MASK | FLAG_OF;
+
+/// Individual bits of the FLAGS register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Flag {
+    CF,
+    PF,
+    AF,
+    ZF,
+    SF,
+    TF,
+    IF,
+    DF,
+    OF,
+    /// V20 only.
+    MD,
+}
+
+impl Flag {
+    pub const ALL:[Flag; 10] = [Flag::CF, Flag::PF, Flag::AF, Flag::ZF,
+        Flag::SF, Flag::TF, Flag::IF, Flag::DF, Flag::OF, Flag::MD];
+
+    pub fn mask(&self) -> u16 {
+        match self {
+            Flag::CF => FLAG_CF,
+            Flag::PF => FLAG_PF,
+            Flag::AF => FLAG_AF,
+            Flag::ZF => FLAG_ZF,
+            Flag::SF => FLAG_SF,
+            Flag::TF => FLAG_TF,
+            Flag::IF => FLAG_IF,
+            Flag::DF => FLAG_DF,
+            Flag::OF => FLAG_OF,
+            Flag::MD => FLAG_MD,
+        }
+    }
+}
+
+impl From<Flag> for u16 {
+    fn from(f:Flag) -> Self {
+        f.mask()
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// How the arithmetic flags are maintained.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlagsMode {
+    /// Flags are computed after every ALU operation.
+    Eager,
+    /// The last flag-setting operation is recorded, and individual flags are
+    /// only computed when read.
+    Lazy,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LazyOp {
+    Add,
+    Sub,
+    Logic,
+    /// INC and DEC carry the CF value from before the operation.
+    Inc,
+    Dec,
+}
+
+/// Where CF comes from, so that INC and DEC can keep the CF of an earlier
+/// operation without evaluating it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LazyCarry {
+    Value(bool),
+    /// Carry out of a + b + carry in.
+    Add(u16, u16, bool, OperandSize),
+    /// Borrow out of a - b - borrow in.
+    Sub(u16, u16, bool),
+}
+
+impl LazyCarry {
+    fn get(&self) -> bool {
+        match *self {
+            LazyCarry::Value(c) => c,
+            LazyCarry::Add(a, b, c, s) => {
+                a as u32 + b as u32 + c as u32 > s.mask() as u32
+            },
+            LazyCarry::Sub(a, b, c) => b as u32 + c as u32 > a as u32,
+        }
+    }
+}
+
+/// A deferred flag-setting ALU operation, sufficient to recompute CF, PF,
+/// AF, ZF, SF and OF on demand.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LazyFlags {
+    pub op:LazyOp,
+    pub a:u16,
+    pub b:u16,
+    pub carry:bool,
+    pub res:u16,
+    pub size:OperandSize,
+    pub kept:LazyCarry, /* CF left alone by INC and DEC */
+}
+
+impl LazyFlags {
+    /// Source of the CF this operation leaves behind.
+    pub(crate) fn carry_out(&self) -> LazyCarry {
+        match self.op {
+            LazyOp::Add => LazyCarry::Add(self.a, self.b, self.carry, self.size),
+            LazyOp::Sub => LazyCarry::Sub(self.a, self.b, self.carry),
+            LazyOp::Logic => LazyCarry::Value(false),
+            LazyOp::Inc | LazyOp::Dec => self.kept,
+        }
+    }
+
+    fn cf(&self) -> bool {
+        self.carry_out().get()
+    }
+
+    fn of(&self) -> bool {
+        let (a, b, res, msb) = (self.a, self.b, self.res, self.size.msb());
+        match self.op {
+            LazyOp::Add | LazyOp::Inc => (a ^ res) & (b ^ res) & msb != 0,
+            LazyOp::Sub | LazyOp::Dec => (a ^ b) & (a ^ res) & msb != 0,
+            LazyOp::Logic => false,
+        }
+    }
+
+    fn af(&self) -> bool {
+        self.op != LazyOp::Logic && (self.a ^ self.b ^ self.res) & 0x10 != 0
+    }
+
+    /// Evaluates a single arithmetic flag.
+    fn flag(&self, f:u16) -> bool {
+        match f {
+            FLAG_CF => self.cf(),
+            FLAG_PF => (self.res as u8).count_ones().is_multiple_of(2),
+            FLAG_AF => self.af(),
+            FLAG_ZF => self.res == 0,
+            FLAG_SF => self.res & self.size.msb() != 0,
+            _ => self.of(),
+        }
+    }
+
+    /// Evaluates all arithmetic flags at once.
+    fn evaluate(&self) -> u16 {
+        [FLAG_CF, FLAG_PF, FLAG_AF, FLAG_ZF, FLAG_SF, FLAG_OF].iter()
+            .filter(|&&f| self.flag(f))
+            .fold(0, |acc, &f| acc | f)
+    }
+}
+
+impl I8088 {
+    /// Reads a single flag, given as a [Flag] or one of the FLAG_ masks.
+    /// MD only exists on the V20, and reads clear in 8080 emulation as it
+    /// does in [flags]. The hardwired bit 15 of the 8088 is not a flag.
+    pub fn flag(&self, f:impl Into<u16>) -> bool {
+        let f = f.into();
+        if f == FLAG_MD {
+            return self.model == CpuModel::V20 && !self.emulation;
+        }
+        match self.lazy_flags {
+            Some(l) if f & FLAGS_ARITH_MASK != 0 => l.flag(f),
+            _ => self.flags & f != 0,
+        }
+    }
+
+    /// Sets a single flag. Pending lazy flags are only evaluated when an
+    /// arithmetic flag is written over them.
+    pub fn set_flag(&mut self, f:impl Into<u16>, v:bool) {
+        let f = f.into();
+        if f & FLAGS_ARITH_MASK != 0 {
+            self.materialize_flags();
+        }
+        if v { self.flags |= f; } else { self.flags &= !f; }
+    }
+
+    /// The full FLAGS register as seen by PUSHF, LAHF and interrupts.
+    pub fn flags(&self) -> u16 {
+        let flags = match self.lazy_flags {
+            Some(l) => (self.flags & !FLAGS_ARITH_MASK) | l.evaluate(),
+            None => self.flags,
+        };
+        if self.emulation { flags & !FLAG_MD } else { flags }
+    }
+
+    /// Loads the whole FLAGS register, e.g. from POPF or IRET. Reserved
+    /// bits keep their hardwired values. The V20 only loads MD between
+    /// BRKEM and RETEM, so that IRET from an interrupt taken in 8080 mode
+    /// resumes emulation.
+    pub fn load_flags(&mut self, v:u16) {
+        self.lazy_flags = None;
+        self.flags = (v & FLAGS_WRITABLE_MASK) | FLAGS_FIXED;
+        if self.md_writable {
+            self.emulation = v & FLAG_MD == 0;
+        }
+    }
+
+    pub fn flags_mode(&self) -> FlagsMode {
+        self.flags_mode
+    }
+
+    pub fn set_flags_mode(&mut self, mode:FlagsMode) {
+        self.materialize_flags();
+        self.flags_mode = mode;
+    }
+
+    /// Number of times pending flags were evaluated into FLAGS. In eager
+    /// mode this is once per flag-setting ALU operation.
+    pub fn flags_materializations(&self) -> u64 {
+        self.materializations
+    }
+
+    /// Folds any pending lazy flags into the FLAGS register.
+    pub(crate) fn materialize_flags(&mut self) {
+        if let Some(l) = self.lazy_flags.take() {
+            self.flags = (self.flags & !FLAGS_ARITH_MASK) | l.evaluate();
+            self.materializations += 1;
+        }
+    }
+
+    /// Replaces all arithmetic flags at once, dropping any pending ones
+    /// without evaluating them.
+    pub(crate) fn set_arith_flags(&mut self, f:u16) {
+        self.lazy_flags = None;
+        self.flags = (self.flags & !FLAGS_ARITH_MASK) | (f & FLAGS_ARITH_MASK);
+    }
+
+    /// CF as left by the last operation, without evaluating it.
+    pub(crate) fn lazy_carry(&self) -> LazyCarry {
+        match self.lazy_flags {
+            Some(l) => l.carry_out(),
+            None => LazyCarry::Value(self.flags & FLAG_CF != 0),
+        }
+    }
+
+    /// Records the flag-setting ALU operation [l]. In eager mode the flags
+    /// are computed immediately, in lazy mode on the next read.
+    pub(crate) fn defer_flags(&mut self, l:LazyFlags) {
+        self.lazy_flags = Some(l);
+        if self.flags_mode == FlagsMode::Eager {
+            self.materialize_flags();
+        }
+    }
+
+    /// SF, ZF and PF of a result of the given width, as FLAGS bits.
+    pub(crate) fn szp(res:u16, msb:u16) -> u16 {
+        let mut f = 0;
+        if res & msb != 0 { f |= FLAG_SF; }
+        if res == 0 { f |= FLAG_ZF; }
+        if (res as u8).count_ones().is_multiple_of(2) { f |= FLAG_PF; }
+        f
+    }
+
+    /// Sets SF, ZF and PF from a result of the given width.
+    pub(crate) fn set_szp(&mut self, res:u16, msb:u16) {
+        self.set_flag(FLAG_SF, res & msb != 0);
+        self.set_flag(FLAG_ZF, res == 0);
+        self.set_flag(FLAG_PF, (res as u8).count_ones().is_multiple_of(2));
+    }
+}
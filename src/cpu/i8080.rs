@@ -0,0 +1,368 @@
+use crate::cpu::{
+    I8088, CpuStatus, CpuError,
+    addr::Segment,
+    decode::{DecodeError, OperandSize},
+    flags::*,
+    register::Register,
+};
+
+/// 8080 registers by their 3-bit encoding, as mapped onto the V20. [None]
+/// is M, the byte addressed by HL.
+const REGISTERS:[Option<Register>; 8] = [
+    Some(Register::CH), Some(Register::CL), Some(Register::DH),
+    Some(Register::DL), Some(Register::BH), Some(Register::BL),
+    None, Some(Register::AL),
+];
+
+const B:OperandSize = OperandSize::Byte;
+
+// Bytes of immediate data or address following an 8080 opcode.
+fn operand_len(op:u8) -> u16 {
+    match op {
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2A | 0x32 | 0x3A | 0xC3 | 0xCB
+        | 0xCD | 0xDD | 0xFD => 2,
+        0xD3 | 0xDB => 1,
+        _ if op & 0xC7 == 0xC2 || op & 0xC7 == 0xC4 => 2,
+        _ if op & 0xC7 == 0x06 || op & 0xC7 == 0xC6 => 1,
+        _ => 0,
+    }
+}
+
+impl I8088 {
+    /// Runs one instruction in the 8080 emulation mode of the V20. The 8080
+    /// registers live in the native ones: A in AL, BC in CX, DE in DX, HL in
+    /// BX, SP in BP and PC in IP, and the low byte of FLAGS has the layout
+    /// of the 8080 PSW. Code is fetched from CS, while all other memory,
+    /// including the stack, is addressed through DS.
+    ///
+    /// Arithmetic goes through the native ALU, so AF follows the 8086 rules
+    /// for subtraction and logic, and clocks are the 8080 state counts.
+    /// Undocumented opcodes run as their documented aliases. ED is a prefix
+    /// of the V20: ED ED n is CALLN, which calls native interrupt handler n
+    /// and resumes emulation on its IRET, and ED FD is RETEM, which returns
+    /// from the handler entered by BRKEM back to native code.
+    pub(crate) fn execute_8080(&mut self) -> Result<CpuStatus, CpuError> {
+        let ip = self.ip();
+        let op = self.fetch_code_8();
+        if op == 0xED {
+            return self.execute_8080_prefixed(ip);
+        }
+        let mut data = 0;
+        for i in 0..operand_len(op) {
+            data |= (self.fetch_code_8() as u16) << (8 * i);
+        }
+
+        let dst = (op >> 3) & 0x07;
+        let src = op & 0x07;
+        let pair = (op >> 4) & 0x03;
+        let clocks = match op {
+            0x76 => {
+                self.ins_clocks = 7;
+                return Ok(self.halt());
+            },
+            0x40..=0x7F => {
+                let v = self.read_8080(src);
+                self.write_8080(dst, v);
+                if src == 6 || dst == 6 { 7 } else { 5 }
+            },
+            0x80..=0xBF => {
+                let v = self.read_8080(src);
+                self.alu_8080(dst, v);
+                if src == 6 { 7 } else { 4 }
+            },
+            0x02 | 0x12 => {
+                let addr = self.read_pair(pair);
+                self.write_mem_8(Segment::DS, addr, self.ax as u8);
+                7
+            },
+            0x0A | 0x1A => {
+                let v = self.read_mem_8(Segment::DS, self.read_pair(pair));
+                self.set(Register::AL, v as u16);
+                7
+            },
+            0x22 => { self.write_mem_16(Segment::DS, data, self.bx); 16 },
+            0x2A => { self.bx = self.read_mem_16(Segment::DS, data); 16 },
+            0x32 => { self.write_mem_8(Segment::DS, data, self.ax as u8); 13 },
+            0x3A => {
+                let v = self.read_mem_8(Segment::DS, data);
+                self.set(Register::AL, v as u16);
+                13
+            },
+            0x07 | 0x0F | 0x17 | 0x1F => {
+                let a = self.ax as u8;
+                let cf = self.flag(FLAG_CF) as u8;
+                let (res, out) = match op {
+                    0x07 => (a.rotate_left(1), a >> 7),
+                    0x0F => (a.rotate_right(1), a & 0x01),
+                    0x17 => ((a << 1) | cf, a >> 7),
+                    _ => ((a >> 1) | (cf << 7), a & 0x01),
+                };
+                self.set(Register::AL, res as u16);
+                self.set_flag(FLAG_CF, out != 0);
+                4
+            },
+            0x27 => {
+                let al = self.alu_daa(self.ax as u8);
+                self.set(Register::AL, al as u16);
+                4
+            },
+            0x2F => { self.set(Register::AL, !self.ax & 0xFF); 4 },
+            0x37 => { self.set_flag(FLAG_CF, true); 4 },
+            0x3F => { self.set_flag(FLAG_CF, !self.flag(FLAG_CF)); 4 },
+            0xC9 | 0xD9 => {
+                let pc = self.pop_8080();
+                self.set_ip(pc);
+                10
+            },
+            0xC3 | 0xCB => { self.set_ip(data); 10 },
+            0xCD | 0xDD | 0xFD => { self.call_8080(data); 17 },
+            0xD3 => { self.write_io_8(data & 0xFF, self.ax as u8); 10 },
+            0xDB => {
+                let v = self.read_io_8(data & 0xFF);
+                self.set(Register::AL, v as u16);
+                10
+            },
+            0xE3 => {
+                let v = self.read_mem_16(Segment::DS, self.bp);
+                self.write_mem_16(Segment::DS, self.bp, self.bx);
+                self.bx = v;
+                18
+            },
+            0xE9 => { self.set_ip(self.bx); 5 },
+            0xEB => { std::mem::swap(&mut self.bx, &mut self.dx); 4 },
+            0xF3 => { self.set_flag(FLAG_IF, false); 4 },
+            0xFB => {
+                self.interrupt_inhibit |= !self.flag(FLAG_IF);
+                self.set_flag(FLAG_IF, true);
+                4
+            },
+            0xF9 => { self.bp = self.bx; 5 },
+            // NOP and its aliases.
+            _ if op & 0xC7 == 0x00 => 4,
+            _ if op & 0xC7 == 0x06 => {
+                self.write_8080(dst, data as u8);
+                if dst == 6 { 10 } else { 7 }
+            },
+            _ if op & 0xC6 == 0x04 => {
+                let a = self.read_8080(dst) as u16;
+                let res = if op & 0x01 == 0 {
+                    self.alu_inc(a, B)
+                } else {
+                    self.alu_dec(a, B)
+                };
+                self.write_8080(dst, res as u8);
+                if dst == 6 { 10 } else { 5 }
+            },
+            _ if op & 0xCF == 0x01 => { self.write_pair(pair, data); 10 },
+            _ if op & 0xC7 == 0x03 => {
+                let v = self.read_pair(pair);
+                let v = if op & 0x08 == 0 { v.wrapping_add(1) } else { v.wrapping_sub(1) };
+                self.write_pair(pair, v);
+                5
+            },
+            _ if op & 0xCF == 0x09 => {
+                let (res, carry) = self.bx.overflowing_add(self.read_pair(pair));
+                self.bx = res;
+                self.set_flag(FLAG_CF, carry);
+                10
+            },
+            _ if op & 0xC7 == 0xC6 => { self.alu_8080(dst, data as u8); 7 },
+            _ if op & 0xC7 == 0xC0 => {
+                if self.condition_8080(dst) {
+                    let pc = self.pop_8080();
+                    self.set_ip(pc);
+                    11
+                } else {
+                    5
+                }
+            },
+            _ if op & 0xC7 == 0xC2 => {
+                if self.condition_8080(dst) { self.set_ip(data); }
+                10
+            },
+            _ if op & 0xC7 == 0xC4 => {
+                if self.condition_8080(dst) {
+                    self.call_8080(data);
+                    17
+                } else {
+                    11
+                }
+            },
+            // POP and PUSH, with PSW in place of SP.
+            _ if op & 0xCF == 0xC1 => {
+                let v = self.pop_8080();
+                if pair == 3 {
+                    self.set(Register::AL, v >> 8);
+                    self.load_flags((self.flags() & !FLAGS_LOW_MASK)
+                        | (v & FLAGS_LOW_MASK));
+                } else {
+                    self.write_pair(pair, v);
+                }
+                10
+            },
+            _ if op & 0xCF == 0xC5 => {
+                let v = if pair == 3 {
+                    ((self.ax & 0xFF) << 8) | (self.flags() & 0xFF)
+                } else {
+                    self.read_pair(pair)
+                };
+                self.push_8080(v);
+                11
+            },
+            // RST
+            _ => { self.call_8080((op & 0x38) as u16); 11 },
+        };
+        self.ins_clocks = clocks;
+        Ok(CpuStatus::Normal)
+    }
+
+    fn execute_8080_prefixed(&mut self, ip:u16) -> Result<CpuStatus, CpuError> {
+        match self.fetch_code_8() {
+            0xED => {
+                let vector = self.fetch_code_8();
+                self.interrupt(vector);
+                self.ins_clocks = 58;
+            },
+            0xFD => {
+                let pc = self.pop();
+                self.cs = self.pop();
+                self.set_ip(pc);
+                let v = self.pop();
+                self.load_flags(v);
+                self.md_writable = false;
+                self.ins_clocks = 27;
+            },
+            _ => {
+                self.set_ip(ip);
+                return Err(CpuError::Decode(DecodeError::UnknownOpcode(0xED)));
+            },
+        }
+        Ok(CpuStatus::Normal)
+    }
+
+    fn read_8080(&mut self, r:u8) -> u8 {
+        match REGISTERS[r as usize] {
+            Some(reg) => self.get(reg) as u8,
+            None => self.read_mem_8(Segment::DS, self.bx),
+        }
+    }
+
+    fn write_8080(&mut self, r:u8, v:u8) {
+        match REGISTERS[r as usize] {
+            Some(reg) => self.set(reg, v as u16),
+            None => self.write_mem_8(Segment::DS, self.bx, v),
+        }
+    }
+
+    // BC, DE, HL and SP.
+    fn read_pair(&self, pair:u8) -> u16 {
+        match pair {
+            0 => self.cx,
+            1 => self.dx,
+            2 => self.bx,
+            _ => self.bp,
+        }
+    }
+
+    fn write_pair(&mut self, pair:u8, v:u16) {
+        match pair {
+            0 => self.cx = v,
+            1 => self.dx = v,
+            2 => self.bx = v,
+            _ => self.bp = v,
+        }
+    }
+
+    fn push_8080(&mut self, v:u16) {
+        self.bp = self.bp.wrapping_sub(2);
+        self.write_mem_16(Segment::DS, self.bp, v);
+    }
+
+    fn pop_8080(&mut self) -> u16 {
+        let v = self.read_mem_16(Segment::DS, self.bp);
+        self.bp = self.bp.wrapping_add(2);
+        v
+    }
+
+    fn call_8080(&mut self, addr:u16) {
+        self.push_8080(self.ip());
+        self.set_ip(addr);
+    }
+
+    // ADD, ADC, SUB, SBB, ANA, XRA, ORA and CMP into A.
+    fn alu_8080(&mut self, f:u8, v:u8) {
+        let (a, b) = (self.ax & 0xFF, v as u16);
+        let cf = self.flag(FLAG_CF);
+        let res = match f {
+            0 => self.alu_add(a, b, false, B),
+            1 => self.alu_add(a, b, cf, B),
+            2 | 7 => self.alu_sub(a, b, false, B),
+            3 => self.alu_sub(a, b, cf, B),
+            4 => self.alu_logic(a & b, B),
+            5 => self.alu_logic(a ^ b, B),
+            _ => self.alu_logic(a | b, B),
+        };
+        if f != 7 {
+            self.set(Register::AL, res);
+        }
+    }
+
+    // NZ, Z, NC, C, PO, PE, P and M.
+    fn condition_8080(&self, cc:u8) -> bool {
+        let flag = match cc >> 1 {
+            0 => FLAG_ZF,
+            1 => FLAG_CF,
+            2 => FLAG_PF,
+            _ => FLAG_SF,
+        };
+        self.flag(flag) == (cc & 0x01 != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CpuModel;
+
+    #[test]
+    fn test_8080_instructions() {
+        let mut cpu = I8088::new();
+        cpu.set_model(CpuModel::V20);
+        cpu.sp = 0x1000;
+        cpu.bus.load(0x200, &[0x00, 0x00, 0x00, 0x10]).unwrap();
+        // lxi sp, 0x100; lxi b, 0x0003; mvi a, 0x09; adi 0x08; daa;
+        // loop: dcr c; jnz loop; call sub; ret (skipped); sub: push psw;
+        // pop d; xchg; dad h; rc
+        cpu.bus.load(0x10000, &[0x31, 0x00, 0x01, 0x01, 0x03, 0x00, 0x3E, 0x09,
+                                0xC6, 0x08, 0x27, 0x0D, 0xC2, 0x0B, 0x00, 0xCD,
+                                0x13, 0x00, 0xC9, 0xF5, 0xD1, 0xEB, 0x29, 0xD8])
+            .unwrap();
+        cpu.ds = 0x1000;
+        // brkem 0x80
+        cpu.bus.load(0x100, &[0x0F, 0xFF, 0x80]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        for _ in 0..5 {
+            cpu.advance().unwrap();
+        }
+        assert_eq!(cpu.ax & 0xFF, 0x17);
+        assert_eq!(cpu.instruction_clocks(), 4);
+        for _ in 0..7 {
+            cpu.advance().unwrap();
+        }
+        assert_eq!((cpu.cx, cpu.ip()), (0x0000, 0x0013));
+        assert_eq!(cpu.read_mem_16(Segment::DS, cpu.bp), 0x0012);
+
+        // PSW holds A over the low byte of FLAGS, with ZF and PF from DCR.
+        for _ in 0..3 {
+            cpu.advance().unwrap();
+        }
+        assert_eq!(cpu.bx, 0x1746);
+        assert_eq!(cpu.flags() & 0xFF, 0x46);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.bx, 0x2E8C);
+        assert!(!cpu.flag(FLAG_CF));
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x0018);
+    }
+}
@@ -0,0 +1,427 @@
+use crate::cpu::{
+    I8088, CpuStatus, CpuError,
+    cycle::BusStatus,
+    decode::Instruction,
+    flags::*,
+    timing::*,
+};
+
+/// Fixed interrupt vectors
+pub const VECTOR_DIVIDE_ERROR:u8            = 0x00;
+pub const VECTOR_SINGLE_STEP:u8             = 0x01;
+pub const VECTOR_NMI:u8                     = 0x02;
+pub const VECTOR_BREAKPOINT:u8              = 0x03;
+pub const VECTOR_OVERFLOW:u8                = 0x04;
+/// Raised by BOUND on the V20.
+pub const VECTOR_BOUND:u8                   = 0x05;
+
+impl I8088 {
+    /// Transfers control through the interrupt vector table at 0000:0000.
+    /// FLAGS, CS and IP are pushed, and IF and TF are cleared so the handler
+    /// runs without further interrupts or single-step traps. Handlers are
+    /// native code, so the V20 leaves 8080 emulation until IRET.
+    pub fn interrupt(&mut self, vector:u8) {
+        self.push(self.flags());
+        self.emulation = false;
+        self.set_flag(FLAG_IF, false);
+        self.set_flag(FLAG_TF, false);
+        self.push(self.cs);
+        self.push(self.ip());
+
+        let entry = vector as u32 * 4;
+        let ip = self.read_phys_16(entry);
+        self.cs = self.read_phys_16(entry + 2);
+        self.set_ip(ip);
+    }
+
+    /// Raises INT 0 for a failed DIV, IDIV or AAM. Unlike later processors,
+    /// the 8088 pushes the address of the following instruction, so a
+    /// handler returning with IRET does not retry the division.
+    pub(crate) fn divide_error(&mut self) {
+        self.interrupt(VECTOR_DIVIDE_ERROR);
+        self.ins_clocks += DIVIDE_ERROR_CLOCKS as u32;
+    }
+
+    /// Drives the INTR input. It is level triggered and ORed with the line
+    /// from the interrupt controller on the bus.
+    pub fn set_intr(&mut self, level:bool) {
+        self.intr = level;
+    }
+
+    /// Drives the NMI input. A rising edge is latched until the CPU gets to
+    /// the next instruction boundary, regardless of IF.
+    pub fn set_nmi(&mut self, level:bool) {
+        let was = self.nmi_level();
+        self.nmi = level;
+        self.latch_nmi(was);
+    }
+
+    // The 5150 wires INT of the 8087 to NMI as well, ORed with the line
+    // driven through [set_nmi].
+    pub(crate) fn set_fpu_int(&mut self, level:bool) {
+        let was = self.nmi_level();
+        self.fpu_int = level;
+        self.latch_nmi(was);
+    }
+
+    fn nmi_level(&self) -> bool {
+        self.nmi || self.fpu_int
+    }
+
+    fn latch_nmi(&mut self, was:bool) {
+        if !was && self.nmi_level() {
+            self.nmi_latch = true;
+        }
+    }
+
+    /// Stops the EU after HLT. The BIU runs a single bus cycle with halt
+    /// status and then fetches nothing further, until NMI, INTR with IF set
+    /// or a reset resumes execution.
+    pub(crate) fn halt(&mut self) -> CpuStatus {
+        self.halted = true;
+        self.run_bus_cycle(BusStatus::Halt, 0, 0);
+        self.halt_status()
+    }
+
+    pub(crate) fn halt_status(&self) -> CpuStatus {
+        if self.flag(FLAG_IF) { CpuStatus::Halt } else { CpuStatus::Hang }
+    }
+
+    /// Whether the CPU is stopped by HLT.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether an interrupt would be taken at an instruction boundary.
+    pub(crate) fn interrupt_pending(&self) -> bool {
+        self.nmi_latch || self.trap
+            || (self.flag(FLAG_IF) && (self.intr || self.bus.intr()))
+    }
+
+    /// Executes an instruction, arming the single-step trap if TF was set
+    /// before it ran. TF is sampled up front, so the POPF or IRET that sets
+    /// TF does not trap, while the one that clears it still does.
+    pub(crate) fn execute_traced(&mut self, ins:&Instruction)
+                                 -> Result<CpuStatus, CpuError> {
+        let tf = self.flag(FLAG_TF);
+        let status = self.execute(ins);
+        self.run_coprocessor();
+        self.trap = tf;
+        status
+    }
+
+    /// Samples NMI, INTR and the single-step trap at an instruction boundary
+    /// and transfers control to the handler of the one with the highest
+    /// priority. Returns whether an interrupt was taken.
+    ///
+    /// The trap has the lowest priority. It stays armed while NMI or INTR
+    /// enter their handlers, and is then taken ahead of the first handler
+    /// instruction. Entering that handler has already cleared TF and IF, so
+    /// the flags the trap pushes have both clear, while the frame of NMI or
+    /// INTR below holds TF set and tracing goes on once it returns.
+    ///
+    /// Prefixes are decoded together with the instruction they belong to, so
+    /// there is never a boundary between them. The boundary following a
+    /// segment register load or STI is skipped, so that SS:SP can be
+    /// switched and IRET reached without an interrupt getting in between.
+    pub(crate) fn service_interrupts(&mut self) -> bool {
+        if std::mem::take(&mut self.interrupt_inhibit) {
+            return false;
+        }
+        let nmi = self.nmi_latch;
+        if self.sample(nmi) {
+            self.nmi_latch = false;
+            self.interrupt(VECTOR_NMI);
+            self.ins_clocks = NMI_CLOCKS as u32;
+            return true;
+        }
+        let intr = self.flag(FLAG_IF) && (self.intr || self.bus.intr());
+        if self.sample(intr) {
+            let vector = self.interrupt_acknowledge();
+            self.interrupt(vector);
+            self.ins_clocks = INTR_CLOCKS as u32;
+            return true;
+        }
+        if std::mem::take(&mut self.trap) {
+            self.interrupt(VECTOR_SINGLE_STEP);
+            self.ins_clocks = TRAP_CLOCKS as u32;
+            return true;
+        }
+        false
+    }
+
+    // The 8088 runs two locked INTA cycles. The first one freezes the
+    // controller's priority logic, and the vector is read in the second.
+    fn interrupt_acknowledge(&mut self) -> u8 {
+        self.run_bus_cycle(BusStatus::InterruptAck, 0, 0);
+        self.run_bus_cycle(BusStatus::InterruptAck, 1, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::{addr::Segment, cycle::TState},
+        devices::InterruptController,
+    };
+
+    struct Pic(u8);
+
+    impl InterruptController for Pic {
+        fn intr(&self) -> bool { true }
+        fn acknowledge(&mut self) -> u8 { self.0 }
+    }
+
+    #[test]
+    fn test_hardware_interrupts() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        // INT 8 handler at 0060:0000, NMI handler at 0070:0000
+        cpu.bus.load(0x20, &[0x00, 0x00, 0x60, 0x00]).unwrap();
+        cpu.bus.load(0x08, &[0x00, 0x00, 0x70, 0x00]).unwrap();
+        cpu.bus.attach_interrupt_controller(Box::new(Pic(0x08)));
+        // nop; nop
+        cpu.bus.load(0x100, &[0x90, 0x90]).unwrap();
+        cpu.set_ip(0x100);
+
+        // INTR is masked with IF clear, NMI is not.
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x101);
+        cpu.set_nmi(true);
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0070, 0x0000));
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x101);
+        // Holding NMI high does not retrigger it.
+        cpu.cs = 0x0000;
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x101);
+
+        cpu.set_flag(FLAG_IF, true);
+        cpu.set_ip(0x100);
+        let mut inta = 0;
+        for _ in 0..100 {
+            cpu.cycle().unwrap();
+            if cpu.t_state() == TState::T1
+                && cpu.bus_cycle().status == BusStatus::InterruptAck {
+                inta += 1;
+            }
+        }
+        assert_eq!(inta, 2);
+        assert_eq!(cpu.cs, 0x0060);
+        assert!(!cpu.flag(FLAG_IF));
+    }
+
+    #[test]
+    fn test_nmi_sources() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        cpu.set_fpu_int(true);
+        assert!(cpu.service_interrupts());
+
+        // While the 8087 holds NMI high, an edge from the board is lost.
+        cpu.set_nmi(true);
+        assert!(!cpu.interrupt_pending());
+        // The 8087 letting go leaves the board's level in place.
+        cpu.set_fpu_int(false);
+        cpu.set_fpu_int(true);
+        assert!(!cpu.interrupt_pending());
+
+        cpu.set_nmi(false);
+        cpu.set_fpu_int(false);
+        cpu.set_nmi(true);
+        assert!(cpu.interrupt_pending());
+    }
+
+    #[test]
+    fn test_reset_keeps_inputs() {
+        let mut cpu = I8088::new();
+        cpu.set_intr(true);
+        cpu.set_nmi(true);
+        cpu.halted = true;
+        cpu.trap = true;
+        cpu.reset();
+        assert!(cpu.intr && cpu.nmi);
+        assert!(!cpu.nmi_latch && !cpu.halted && !cpu.trap && !cpu.interrupt_inhibit);
+        // NMI held high over the reset needs a new edge.
+        cpu.set_nmi(true);
+        assert!(!cpu.interrupt_pending());
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        assert!(cpu.interrupt_pending());
+    }
+
+    #[test]
+    fn test_interrupt_delay() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        cpu.bus.load(0x20, &[0x00, 0x00, 0x60, 0x00]).unwrap();
+        cpu.set_flag(FLAG_IF, true);
+        // mov ss, ax; nop
+        cpu.bus.load(0x100, &[0x8E, 0xD0, 0x90]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        cpu.bus.attach_interrupt_controller(Box::new(Pic(0x08)));
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x103);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.cs, 0x0060);
+
+        // mov ss, ax; es: rep movsb
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        cpu.bus.load(0x20, &[0x00, 0x00, 0x60, 0x00]).unwrap();
+        cpu.bus.load(0x100, &[0x8E, 0xD0, 0x26, 0xF3, 0xA4]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.cx = 5;
+        cpu.set_flag(FLAG_IF, true);
+        cpu.advance().unwrap();
+        cpu.bus.attach_interrupt_controller(Box::new(Pic(0x08)));
+        // The string instruction starts, and is interrupted after the first
+        // iteration.
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cx, cpu.di), (4, 1));
+        assert_eq!(cpu.ip(), 0x102);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.cs, 0x0060);
+        // The return address is the last prefix, dropping the override.
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x103);
+        assert_eq!(cpu.cx, 4);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        cpu.bus.load(0x08, &[0x00, 0x00, 0x70, 0x00]).unwrap();
+        cpu.bus.load(0x20, &[0x00, 0x00, 0x60, 0x00]).unwrap();
+        // hlt; nop
+        cpu.bus.load(0x100, &[0xF4, 0x90]).unwrap();
+        cpu.set_ip(0x100);
+
+        // Halted with IF clear, the CPU idles without fetching.
+        let mut halt = 0;
+        for _ in 0..40 {
+            cpu.cycle().unwrap();
+            if cpu.t_state() == TState::T1 {
+                match cpu.bus_cycle().status {
+                    BusStatus::Halt => halt += 1,
+                    BusStatus::CodeFetch => assert_eq!(halt, 0),
+                    _ => {},
+                }
+            }
+        }
+        assert_eq!(halt, 1);
+        assert!(cpu.halted());
+        assert!(matches!(cpu.advance(), Ok(CpuStatus::Hang)));
+        assert_eq!(cpu.ip(), 0x101);
+
+        // NMI still wakes it, returning past HLT.
+        cpu.set_nmi(true);
+        cpu.advance().unwrap();
+        assert!(!cpu.halted());
+        assert_eq!((cpu.cs, cpu.ip()), (0x0070, 0x0000));
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x101);
+
+        // With IF set it is a plain halt, ended by INTR.
+        cpu.cs = 0x0000;
+        cpu.set_ip(0x100);
+        cpu.set_flag(FLAG_IF, true);
+        assert!(matches!(cpu.advance(), Ok(CpuStatus::Halt)));
+        assert!(matches!(cpu.advance(), Ok(CpuStatus::Halt)));
+        cpu.bus.attach_interrupt_controller(Box::new(Pic(0x08)));
+        cpu.advance().unwrap();
+        assert_eq!(cpu.cs, 0x0060);
+    }
+
+    #[test]
+    fn test_single_step() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        // INT 1 handler at 0060:0000, INT 3 handler at 0070:0000
+        cpu.bus.load(0x04, &[0x00, 0x00, 0x60, 0x00]).unwrap();
+        cpu.bus.load(0x0C, &[0x00, 0x00, 0x70, 0x00]).unwrap();
+        // iret
+        cpu.bus.load(0x600, &[0xCF]).unwrap();
+        // popf; nop; popf; nop; int3
+        cpu.bus.load(0x100, &[0x9D, 0x90, 0x9D, 0x90, 0xCC]).unwrap();
+        cpu.write_mem_16(Segment::SS, 0x1000, FLAGS_FIXED | FLAG_TF);
+        cpu.write_mem_16(Segment::SS, 0x1002, FLAGS_FIXED);
+        cpu.set_ip(0x100);
+
+        // The POPF setting TF does not trap, the NOP after it does.
+        cpu.advance().unwrap();
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x102);
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0060, 0x0000));
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x102);
+        assert!(!cpu.flag(FLAG_TF));
+        // The handler runs untraced, and IRET restores TF.
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0000, 0x0102));
+        assert!(cpu.flag(FLAG_TF));
+
+        // The POPF clearing TF still traps.
+        cpu.advance().unwrap();
+        assert!(!cpu.flag(FLAG_TF));
+        cpu.advance().unwrap();
+        assert_eq!(cpu.cs, 0x0060);
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0000, 0x0103));
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ip(), 0x104);
+
+        // INT 3 returns past itself.
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0070, 0x0000));
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x105);
+    }
+
+    #[test]
+    fn test_trap_after_nmi() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        // INT 1 handler at 0060:0000, NMI handler at 0070:0000
+        cpu.bus.load(0x04, &[0x00, 0x00, 0x60, 0x00]).unwrap();
+        cpu.bus.load(0x08, &[0x00, 0x00, 0x70, 0x00]).unwrap();
+        // nop; nop
+        cpu.bus.load(0x100, &[0x90, 0x90]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.set_flag(FLAG_TF, true);
+
+        cpu.advance().unwrap();
+        cpu.set_nmi(true);
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0070, 0x0000));
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0060, 0x0000));
+        // IP, CS and flags of the trap frame, then of the NMI frame.
+        let frame:Vec<u16> = (0..6).map(|i| cpu.read_mem_16(Segment::SS, cpu.sp + 2 * i))
+            .collect();
+        assert_eq!(frame, [0x0000, 0x0070, FLAGS_FIXED, 0x0101, 0x0000, FLAGS_FIXED | FLAG_TF]);
+    }
+
+    #[test]
+    fn test_debugger_step() {
+        let mut cpu = I8088::new();
+        cpu.sp = 0x1000;
+        // nop; nop
+        cpu.bus.load(0x100, &[0x90, 0x90]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.set_breakpoint(0x100);
+        assert!(matches!(cpu.advance(), Ok(CpuStatus::Breakpoint)));
+        assert_eq!(cpu.ip(), 0x100);
+
+        // Execution stays there until resumed.
+        assert!(matches!(cpu.advance(), Ok(CpuStatus::Breakpoint)));
+
+        // Stepping off a breakpoint neither hits it nor touches TF.
+        assert!(matches!(cpu.step(), Ok(CpuStatus::Normal)));
+        assert_eq!((cpu.ip(), cpu.sp), (0x101, 0x1000));
+        assert!(!cpu.flag(FLAG_TF));
+        cpu.set_ip(0x100);
+        assert!(matches!(cpu.advance(), Ok(CpuStatus::Breakpoint)));
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(PartialEq, Copy, Clone, Debug)]
+use std::fmt;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum Mnemonic {
     AAA,
     AAD,
@@ -6,14 +8,19 @@ pub enum Mnemonic {
     AAS,
     ADC,
     ADD,
+    ADD4S,
     AND,
+    BOUND,
+    BRKEM,
     CALL,
     CBW,
     CLC,
     CLD,
     CLI,
+    CLR1,
     CMC,
     CMP,
+    CMP4S,
     CMPSB,
     CMPSW,
     CWD,
@@ -21,12 +28,17 @@ pub enum Mnemonic {
     DAS,
     DEC,
     DIV,
+    ENTER,
     ESC,
+    EXT,
     HLT,
     IDIV,
     IMUL,
     IN,
     INC,
+    INS,
+    INSB,
+    INSW,
     INT,
     INTO,
     IRET,
@@ -65,10 +77,12 @@ pub enum Mnemonic {
     LAHF,
     LDS,
     LEA,
+    LEAVE,
     LES,
     LOCK,
     LODSB,
     LODSW,
+    LOOP,
     LOOPE,
     LOOPNE,
     LOOPNZ,
@@ -80,11 +94,16 @@ pub enum Mnemonic {
     NEG,
     NOP,
     NOT,
+    NOT1,
     OR,
     OUT,
+    OUTSB,
+    OUTSW,
     POP,
+    POPA,
     POPF,
     PUSH,
+    PUSHA,
     PUSHF,
     RCL,
     RCR,
@@ -97,13 +116,18 @@ pub enum Mnemonic {
     RETN,
     RETF,
     ROL,
+    ROL4,
     ROR,
+    ROR4,
     SAHF,
     SAL,
+    SALC,
     SAR,
     SBB,
     SCASB,
     SCASW,
+    SET1,
+    SETMO,
     SHL,
     SHR,
     STC,
@@ -112,9 +136,18 @@ pub enum Mnemonic {
     STOSB,
     STOSW,
     SUB,
+    SUB4S,
     TEST,
+    TEST1,
     WAIT,
     XCHG,
     XLAT,
     XOR,
 }
+
+/// Intel syntax, in lower case.
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
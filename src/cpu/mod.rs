@@ -1,34 +1,115 @@
 pub mod addr;
+pub mod alu;
+pub mod asm;
+pub mod biu;
+pub mod cache;
+pub mod coprocessor;
+pub mod cores;
 pub mod cycle;
+pub mod decode;
+pub mod disasm;
+pub mod eu;
+pub mod execute;
+pub mod flags;
+pub mod i8080;
+pub mod interrupt;
+pub mod mnemonic;
+pub mod register;
+pub mod run;
+pub mod state;
+#[cfg(test)]
+mod testing;
+pub mod timing;
+pub mod v20;
 
 use std::fmt::{self, Debug};
+use std::collections::HashSet;
 use crate::{
     ext::queue::StaticQueue, 
     core::bus::BusInterface, 
-    cpu::cycle::TState
+    devices::fpu::I8087,
+    cpu::{
+        cache::DecodeCache,
+        cycle::{BusCycle, EuProgress, TState},
+        decode::{DecodeError, Instruction},
+        flags::{FLAGS_FIXED, FlagsMode, LazyFlags},
+    },
 };
 
+/// CS:IP the processor starts fetching from after RESET.
+pub const RESET_CS:u16                      = 0xFFFF;
+pub const RESET_IP:u16                      = 0x0000;
+
+/// Processor fitted in the 8088 socket. Both share the register file and
+/// bus interface of [I8088], and differ in decoding, timing and execution.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CpuModel {
+    I8088,
+    /// NEC V20, with the 80186 additions, NEC's bit field, bit and BCD
+    /// string instructions, and an 8080 emulation mode.
+    V20,
+}
+
+impl fmt::Display for CpuModel {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuModel::I8088 => write!(f, "Intel 8088"),
+            CpuModel::V20 => write!(f, "NEC V20"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum CpuStatus {
+    Normal,
     Breakpoint,
+    Halt,
+    /// Halted with IF clear, so only NMI or reset can resume execution.
+    Hang,
+    /// The clocks or instructions given to a run went by.
+    BudgetExhausted,
+    /// The predicate of [I8088::run_until] held.
+    ConditionMet,
 }
 
 impl fmt::Display for CpuStatus {
     fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
         match self {
+            CpuStatus::Normal => write!(f, "Execution OK."),
             CpuStatus::Breakpoint => write!(f, "Breakpoint hit."),
+            CpuStatus::Halt => write!(f, "Processor halted."),
+            CpuStatus::Hang => write!(f, "Processor halted with interrupts disabled."),
+            CpuStatus::BudgetExhausted => write!(f, "Run budget exhausted."),
+            CpuStatus::ConditionMet => write!(f, "Stop condition met."),
         }
     }
 }
 
+/// Errors stopping the CPU. The divide errors are raised by the ALU and
+/// taken by the EU as INT 0, as the 8088 does, so they never reach the
+/// callers of [I8088::step] or [I8088::cycle].
 #[derive(Debug, Clone)]
 pub enum CpuError {
-
+    Decode(DecodeError),
+    /// DIV, IDIV or AAM by zero.
+    DivideByZero,
+    /// The quotient of DIV or IDIV does not fit the destination.
+    DivideOverflow,
 }
 
-// impl fmt::Display for CpuError {}
+impl fmt::Display for CpuError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::Decode(e) => write!(f, "Decode error: {}", e),
+            CpuError::DivideByZero => write!(f, "Divide by zero."),
+            CpuError::DivideOverflow => write!(f, "Divide overflow."),
+        }
+    }
+}
 
 pub struct I8088 {
+    model:CpuModel,
+
     /* due to the 8088 utilizing a 4-byte prefetch queue, the PC will
      * point to the next byte to be fetched, not executed. */
     prefetch_queue:StaticQueue<u8, 0x04>,
@@ -36,6 +117,21 @@ pub struct I8088 {
     le:u32, /* last calculated effective address */
 
     bus:BusInterface,
+    t_state:TState,
+    bus_cycle:BusCycle,  /* transfer in progress */
+    bus_data:u8,         /* byte written by it */
+    fetch_dropped:bool,  /* the queue was flushed under the fetch */
+    trace_bus:bool,      /* the EU runs under [cycle], stalling on the bus */
+    ready:bool,
+    wait_states:u8, /* remaining wait states for the current bus cycle */
+    clocks:u64,
+    ins_clocks:u32,    /* clocks taken by the last step of the EU */
+    ins_transfers:u32, /* bus cycles run by the last step of the EU */
+
+    eu:EuProgress,         /* instruction being run under [cycle] */
+    transfer_index:usize,  /* next result of it to replay */
+    code_pending:u16,      /* code bytes of it not yet replayed */
+    stalled:bool,          /* it has to wait for the bus */
 
     ax:u16,
     bx:u16,
@@ -52,6 +148,28 @@ pub struct I8088 {
     es:u16,
 
     flags:u16,
+    flags_mode:FlagsMode,
+    lazy_flags:Option<LazyFlags>, /* pending flags in lazy mode */
+    materializations:u64,         /* pending flags evaluated into FLAGS */
+    emulation:bool,   /* V20 running 8080 code, MD flag clear */
+    md_writable:bool, /* MD is loaded along with FLAGS, set by BRKEM */
+
+    rep:Option<Instruction>, /* repeated string instruction in progress */
+    decode_cache:DecodeCache,
+    decode_cache_enabled:bool,
+
+    intr:bool,
+    nmi:bool,
+    fpu_int:bool,           /* INT of the 8087, also wired to NMI */
+    nmi_latch:bool,         /* NMI is edge triggered */
+    interrupt_inhibit:bool, /* no interrupts at the next boundary */
+    trap:bool,              /* single-step trap at the next boundary */
+    halted:bool,            /* stopped by HLT until an interrupt */
+
+    breakpoints:HashSet<u32>, /* physical addresses */
+    breakpoint_resume:bool,
+
+    fpu:Option<I8087>,
 }
 
 impl Default for I8088 {
@@ -61,13 +179,33 @@ impl Default for I8088 {
 }
 
 impl I8088 {
+    /// A processor with every register zeroed, convenient for running code
+    /// loaded anywhere in low memory. [reset] gives the state the 8088
+    /// actually powers up in.
     pub fn new() -> Self {
         Self {
+            model:CpuModel::I8088,
+
             prefetch_queue:StaticQueue::<u8, 0x04>::new(),
             pc:0x00, /* program counter / instruction pointer */
             le:0x00,
 
             bus:BusInterface::new(),
+            t_state:TState::None,
+            bus_cycle:BusCycle::PASSIVE,
+            bus_data:0,
+            fetch_dropped:false,
+            trace_bus:false,
+            ready:true,
+            wait_states:0,
+            clocks:0,
+            ins_clocks:0,
+            ins_transfers:0,
+
+            eu:EuProgress::default(),
+            transfer_index:0,
+            code_pending:0,
+            stalled:false,
 
             ax:0x00,
             bx:0x00,
@@ -83,7 +221,71 @@ impl I8088 {
             ss:0x00,
             es:0x00,
 
-            flags:0x00,
+            flags:FLAGS_FIXED,
+            flags_mode:FlagsMode::Eager,
+            lazy_flags:None,
+            materializations:0,
+            emulation:false,
+            md_writable:false,
+
+            rep:None,
+            decode_cache:DecodeCache::new(),
+            decode_cache_enabled:true,
+
+            intr:false,
+            nmi:false,
+            fpu_int:false,
+            nmi_latch:false,
+            interrupt_inhibit:false,
+            trap:false,
+            halted:false,
+
+            breakpoints:HashSet::new(),
+            breakpoint_resume:false,
+
+            fpu:None,
+        }
+    }
+
+    /// Asserts RESET. The 8088 comes out of it fetching from FFFF:0000,
+    /// the top 16 bytes of the address space where the BIOS ROM places a
+    /// jump to its entry point. DS, SS, ES and FLAGS are cleared, the queue
+    /// is emptied, and a HLT, pending trap, latched NMI or interrupt inhibit
+    /// is forgotten. The other general registers are undefined, and left
+    /// zeroed here. The levels on the INTR, NMI and READY inputs are driven
+    /// from outside and stay as they are, though an NMI held high does not
+    /// trigger until it rises again. What is fitted around the processor is
+    /// kept too: the bus and its devices, the model, breakpoints, the flags
+    /// mode and decode cache setting. The coprocessor shares the RESET line
+    /// and is initialized as by FINIT, which drops its INT output.
+    pub fn reset(&mut self) {
+        let bus = std::mem::take(&mut self.bus);
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        let mut fpu = self.fpu.take();
+        if let Some(fpu) = fpu.as_mut() {
+            fpu.init();
         }
+        *self = Self {
+            model:self.model,
+            bus,
+            cs:RESET_CS,
+            pc:RESET_IP,
+            flags_mode:self.flags_mode,
+            decode_cache_enabled:self.decode_cache_enabled,
+            ready:self.ready,
+            intr:self.intr,
+            nmi:self.nmi,
+            breakpoints,
+            fpu,
+            ..Self::new()
+        };
+    }
+
+    pub fn bus(&self) -> &BusInterface {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut BusInterface {
+        &mut self.bus
     }
 }
@@ -0,0 +1,226 @@
+use std::fmt;
+use crate::cpu::{I8088, addr::Segment};
+
+/// Architectural registers. All but IP and FLAGS can be encoded in the
+/// ModR/M byte and opcode fields.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    AL,
+    CL,
+    DL,
+    BL,
+    AH,
+    CH,
+    DH,
+    BH,
+
+    AX,
+    CX,
+    DX,
+    BX,
+    SP,
+    BP,
+    SI,
+    DI,
+
+    ES,
+    CS,
+    SS,
+    DS,
+
+    IP,
+    FLAGS,
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+impl Register {
+    /// 8-bit general register from its 3-bit encoding.
+    pub fn reg8(idx:u8) -> Self {
+        match idx & 0x07 {
+            0 => Register::AL,
+            1 => Register::CL,
+            2 => Register::DL,
+            3 => Register::BL,
+            4 => Register::AH,
+            5 => Register::CH,
+            6 => Register::DH,
+            _ => Register::BH,
+        }
+    }
+
+    /// 16-bit general register from its 3-bit encoding.
+    pub fn reg16(idx:u8) -> Self {
+        match idx & 0x07 {
+            0 => Register::AX,
+            1 => Register::CX,
+            2 => Register::DX,
+            3 => Register::BX,
+            4 => Register::SP,
+            5 => Register::BP,
+            6 => Register::SI,
+            _ => Register::DI,
+        }
+    }
+
+    /// Segment register from its 2-bit encoding. The 8088 ignores the
+    /// upper bit of the ModR/M reg field, so 4-7 alias 0-3.
+    pub fn sreg(idx:u8) -> Self {
+        match idx & 0x03 {
+            0 => Register::ES,
+            1 => Register::CS,
+            2 => Register::SS,
+            _ => Register::DS,
+        }
+    }
+
+    /// The segment this register selects, if it is a segment register.
+    pub fn segment(&self) -> Option<Segment> {
+        match self {
+            Register::ES => Some(Segment::ES),
+            Register::CS => Some(Segment::CS),
+            Register::SS => Some(Segment::SS),
+            Register::DS => Some(Segment::DS),
+            _ => None,
+        }
+    }
+
+    /// 3-bit encoding of a general register, or 2-bit of a segment
+    /// register, as used by [reg8], [reg16] and [sreg]. IP and FLAGS have
+    /// no encoding and give 0.
+    pub fn index(&self) -> u8 {
+        match self {
+            Register::IP | Register::FLAGS => 0,
+            Register::AL | Register::AX | Register::ES => 0,
+            Register::CL | Register::CX | Register::CS => 1,
+            Register::DL | Register::DX | Register::SS => 2,
+            Register::BL | Register::BX | Register::DS => 3,
+            Register::AH | Register::SP => 4,
+            Register::CH | Register::BP => 5,
+            Register::DH | Register::SI => 6,
+            Register::BH | Register::DI => 7,
+        }
+    }
+
+    pub fn is_8bit(&self) -> bool {
+        matches!(self, Register::AL | Register::CL | Register::DL
+            | Register::BL | Register::AH | Register::CH | Register::DH
+            | Register::BH)
+    }
+}
+
+impl From<Segment> for Register {
+    fn from(s:Segment) -> Self {
+        match s {
+            Segment::ES => Register::ES,
+            Segment::CS => Register::CS,
+            Segment::SS => Register::SS,
+            Segment::DS => Register::DS,
+        }
+    }
+}
+
+/// Reads [r] from the general registers [gp] and segment registers [sr],
+/// both in encoding order. 8-bit registers are zero-extended.
+pub(crate) fn read_register(gp:[u16; 8], sr:[u16; 4], ip:u16, flags:u16, r:Register) -> u16 {
+    let i = r.index() as usize;
+    match r {
+        Register::IP => ip,
+        Register::FLAGS => flags,
+        Register::ES | Register::CS | Register::SS | Register::DS => sr[i],
+        _ if r.is_8bit() && i >= 4 => gp[i - 4] >> 8,
+        _ if r.is_8bit() => gp[i] & 0xFF,
+        _ => gp[i],
+    }
+}
+
+impl I8088 {
+    /// Reads a register. 8-bit registers are zero-extended, and IP and
+    /// FLAGS read as [ip] and [flags] do.
+    pub fn get(&self, r:Register) -> u16 {
+        let gp = [self.ax, self.cx, self.dx, self.bx, self.sp, self.bp, self.si, self.di];
+        read_register(gp, [self.es, self.cs, self.ss, self.ds], self.ip(), self.flags(), r)
+    }
+
+    /// Writes a register. Only the low byte of [v] is used for 8-bit
+    /// registers. Writing IP jumps through [set_ip], and FLAGS is loaded
+    /// through [load_flags], keeping its reserved bits.
+    pub fn set(&mut self, r:Register, v:u16) {
+        let lo = |x:u16| (x & 0xFF00) | (v & 0xFF);
+        let hi = |x:u16| (x & 0x00FF) | (v << 8);
+        match r {
+            Register::AL => self.ax = lo(self.ax),
+            Register::CL => self.cx = lo(self.cx),
+            Register::DL => self.dx = lo(self.dx),
+            Register::BL => self.bx = lo(self.bx),
+            Register::AH => self.ax = hi(self.ax),
+            Register::CH => self.cx = hi(self.cx),
+            Register::DH => self.dx = hi(self.dx),
+            Register::BH => self.bx = hi(self.bx),
+
+            Register::AX => self.ax = v,
+            Register::CX => self.cx = v,
+            Register::DX => self.dx = v,
+            Register::BX => self.bx = v,
+            Register::SP => self.sp = v,
+            Register::BP => self.bp = v,
+            Register::SI => self.si = v,
+            Register::DI => self.di = v,
+
+            Register::ES => self.es = v,
+            Register::CS => self.cs = v,
+            Register::SS => self.ss = v,
+            Register::DS => self.ds = v,
+
+            Register::IP => self.set_ip(v),
+            Register::FLAGS => self.load_flags(v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CpuModel, flags::{Flag, FLAGS_FIXED}};
+
+    #[test]
+    fn test_register_access() {
+        let mut cpu = I8088::new();
+        cpu.set(Register::AX, 0x1234);
+        cpu.set(Register::AH, 0xAB);
+        assert_eq!(cpu.get(Register::AX), 0xAB34);
+        assert_eq!(cpu.get(Register::AL), 0x34);
+        cpu.set(Register::DL, 0x1FF);
+        assert_eq!(cpu.get(Register::DX), 0x00FF);
+
+        cpu.set(Register::ES, 0xB800);
+        assert_eq!(cpu.get(Register::from(Segment::ES)), 0xB800);
+
+        cpu.set(Register::IP, 0x0100);
+        assert_eq!(cpu.get(Register::IP), 0x0100);
+        assert_eq!(cpu.fetch_pointer(), 0x0100);
+    }
+
+    #[test]
+    fn test_flags_access() {
+        let mut cpu = I8088::new();
+        cpu.set(Register::FLAGS, 0x0000);
+        assert_eq!(cpu.get(Register::FLAGS), FLAGS_FIXED);
+
+        cpu.set_flag(Flag::CF, true);
+        cpu.set_flag(Flag::DF, true);
+        assert!(cpu.flag(Flag::CF) && cpu.flag(Flag::DF));
+        assert!(!cpu.flag(Flag::ZF));
+        assert_eq!(cpu.get(Register::FLAGS), FLAGS_FIXED | 0x0401);
+        let set:Vec<_> = Flag::ALL.iter().filter(|&&f| cpu.flag(f)).collect();
+        // Bit 15 is hardwired on the 8088, and only MD on the V20.
+        assert_eq!(set, [&Flag::CF, &Flag::DF]);
+        assert_ne!(cpu.get(Register::FLAGS) & Flag::MD.mask(), 0);
+        cpu.set_model(CpuModel::V20);
+        assert!(cpu.flag(Flag::MD));
+    }
+}
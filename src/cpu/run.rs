@@ -0,0 +1,110 @@
+use crate::cpu::{I8088, CpuStatus, CpuError};
+
+impl I8088 {
+    /// Runs up to [n] clocks through [cycle]. Stops early, with the status,
+    /// when the EU hits a breakpoint or halts, and returns
+    /// [CpuStatus::BudgetExhausted] otherwise. A halted CPU stops after a
+    /// single clock, leaving the caller to advance the rest of the machine
+    /// until an interrupt wakes it.
+    pub fn run_cycles(&mut self, n:u64) -> Result<CpuStatus, CpuError> {
+        if self.at_boundary() {
+            self.resume_breakpoint();
+        }
+        for _ in 0..n {
+            match self.cycle()? {
+                CpuStatus::Normal => {},
+                status => return Ok(status),
+            }
+        }
+        Ok(CpuStatus::BudgetExhausted)
+    }
+
+    /// Runs up to [n] instructions at instruction level, without playing
+    /// out bus cycles. REP iterations and interrupt entries count as one
+    /// instruction each, as they do for [step].
+    pub fn run_instructions(&mut self, n:u64) -> Result<CpuStatus, CpuError> {
+        self.resume_breakpoint();
+        for _ in 0..n {
+            match self.advance_charged()? {
+                CpuStatus::Normal => {},
+                status => return Ok(status),
+            }
+        }
+        Ok(CpuStatus::BudgetExhausted)
+    }
+
+    /// Runs at instruction level until [pred] holds, checking it before
+    /// each instruction, or until a breakpoint or halt. There is no budget:
+    /// the predicate can count instructions or clocks to bound the run.
+    pub fn run_until<F:FnMut(&I8088) -> bool>(&mut self, mut pred:F)
+                                              -> Result<CpuStatus, CpuError> {
+        self.resume_breakpoint();
+        loop {
+            if pred(self) {
+                return Ok(CpuStatus::ConditionMet);
+            }
+            match self.advance_charged()? {
+                CpuStatus::Normal => {},
+                status => return Ok(status),
+            }
+        }
+    }
+
+    fn advance_charged(&mut self) -> Result<CpuStatus, CpuError> {
+        let status = self.advance();
+        self.charge_clocks();
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{I8088, CpuStatus, CpuError, register::Register};
+
+    fn program() -> I8088 {
+        let mut cpu = I8088::new();
+        // inc ax; cmp ax, 5; jne $-4; hlt with IF clear
+        cpu.bus.load(0x100, &[0x40, 0x3D, 0x05, 0x00, 0x75, 0xFA, 0xF4]).unwrap();
+        cpu.set_ip(0x100);
+        cpu
+    }
+
+    #[test]
+    fn test_run_instructions() {
+        let mut cpu = program();
+        assert!(matches!(cpu.run_instructions(6), Ok(CpuStatus::BudgetExhausted)));
+        assert_eq!(cpu.get(Register::AX), 2);
+        assert!(cpu.clocks() > 0);
+        assert!(matches!(cpu.run_instructions(100), Ok(CpuStatus::Hang)));
+        assert_eq!(cpu.get(Register::AX), 5);
+
+        // Decode errors end the run as errors.
+        let mut cpu = I8088::new();
+        cpu.bus.load(0x100, &[0xFE, 0xD0]).unwrap();
+        cpu.set_ip(0x100);
+        assert!(matches!(cpu.run_instructions(1), Err(CpuError::Decode(_))));
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut cpu = program();
+        let res = cpu.run_until(|cpu| cpu.get(Register::AX) == 3);
+        assert!(matches!(res, Ok(CpuStatus::ConditionMet)));
+        assert_eq!(cpu.ip(), 0x101);
+
+        // Resuming from a breakpoint runs past it.
+        cpu.set_breakpoint(0x106);
+        assert!(matches!(cpu.run_until(|_| false), Ok(CpuStatus::Breakpoint)));
+        assert_eq!(cpu.get(Register::AX), 5);
+        assert!(matches!(cpu.run_until(|_| false), Ok(CpuStatus::Hang)));
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        let mut cpu = program();
+        assert!(matches!(cpu.run_cycles(10), Ok(CpuStatus::BudgetExhausted)));
+        assert_eq!(cpu.clocks(), 10);
+        assert!(matches!(cpu.run_cycles(10_000), Ok(CpuStatus::Hang)));
+        assert_eq!(cpu.get(Register::AX), 5);
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt;
+use crate::{
+    cpu::{
+        I8088,
+        biu::instruction_pointer,
+        cycle::{BusCycle, EuProgress, TState},
+        decode::Instruction,
+        register::{Register, read_register},
+    },
+    ext::queue::Queue,
+};
+
+/// Registers listed by [CpuState::diff], in display order.
+const DIFF_REGISTERS:[Register; 14] = [
+    Register::AX, Register::BX, Register::CX, Register::DX,
+    Register::SP, Register::BP, Register::SI, Register::DI,
+    Register::CS, Register::DS, Register::SS, Register::ES,
+    Register::IP, Register::FLAGS,
+];
+
+/// Complete state of the processor, as exported by [I8088::state]. The bus
+/// and its devices, the coprocessor and breakpoints are not part of it, nor
+/// is the clock count, so two runs reaching the same point compare equal.
+/// The model, flags mode and decode cache setting are configuration rather
+/// than state, and are kept by [I8088::restore].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuState {
+    pub ax:u16,
+    pub bx:u16,
+    pub cx:u16,
+    pub dx:u16,
+    pub si:u16,
+    pub di:u16,
+    pub bp:u16,
+    pub sp:u16,
+
+    pub ds:u16,
+    pub cs:u16,
+    pub ss:u16,
+    pub es:u16,
+
+    pub flags:u16,
+    pub emulation:bool,
+    pub md_writable:bool,
+
+    pub pc:u16,        /* fetch pointer */
+    pub queue:Vec<u8>, /* prefetch queue, oldest first */
+
+    pub t_state:TState,
+    pub bus_cycle:BusCycle,
+    pub bus_data:u8,
+    pub fetch_dropped:bool,
+    pub ready:bool,
+    pub wait_states:u8,
+    pub ins_clocks:u32, /* clocks of the last instruction, see [I8088::instruction_clocks] */
+    pub eu:EuProgress, /* instruction in progress under [I8088::cycle] */
+
+    pub rep:Option<Instruction>, /* repeated string instruction in progress */
+
+    pub intr:bool,
+    pub nmi:bool,
+    pub fpu_int:bool,
+    pub nmi_latch:bool,
+    pub interrupt_inhibit:bool,
+    pub trap:bool,
+    pub halted:bool,
+    pub breakpoint_resume:bool, /* the next breakpoint hit is passed over */
+}
+
+impl CpuState {
+    /// Reads a register as [I8088::get] would.
+    pub fn get(&self, r:Register) -> u16 {
+        let gp = [self.ax, self.cx, self.dx, self.bx, self.sp, self.bp, self.si, self.di];
+        read_register(gp, [self.es, self.cs, self.ss, self.ds], self.ip(), self.flags, r)
+    }
+
+    /// Address of the instruction being executed, see [I8088::ip].
+    pub fn ip(&self) -> u16 {
+        instruction_pointer(self.pc, self.queue.len(), self.rep, self.eu.code)
+    }
+
+    /// Registers that differ in [other], e.g. the state after a step.
+    pub fn diff(&self, other:&CpuState) -> Vec<RegisterChange> {
+        DIFF_REGISTERS.iter()
+            .map(|&r| RegisterChange { register:r, old:self.get(r), new:other.get(r) })
+            .filter(|c| c.old != c.new)
+            .collect()
+    }
+}
+
+/// A register changed between two states.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegisterChange {
+    pub register:Register,
+    pub old:u16,
+    pub new:u16,
+}
+
+impl fmt::Display for RegisterChange {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {:04X} -> {:04X}", self.register, self.old, self.new)
+    }
+}
+
+impl I8088 {
+    /// Exports the state of the processor.
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            ax:self.ax,
+            bx:self.bx,
+            cx:self.cx,
+            dx:self.dx,
+            si:self.si,
+            di:self.di,
+            bp:self.bp,
+            sp:self.sp,
+
+            ds:self.ds,
+            cs:self.cs,
+            ss:self.ss,
+            es:self.es,
+
+            flags:self.flags(),
+            emulation:self.emulation,
+            md_writable:self.md_writable,
+
+            pc:self.pc,
+            queue:self.queue_contents(),
+
+            t_state:self.t_state,
+            bus_cycle:self.bus_cycle,
+            bus_data:self.bus_data,
+            fetch_dropped:self.fetch_dropped,
+            ready:self.ready,
+            wait_states:self.wait_states,
+            ins_clocks:self.ins_clocks,
+            eu:self.eu.clone(),
+
+            rep:self.rep,
+
+            intr:self.intr,
+            nmi:self.nmi,
+            fpu_int:self.fpu_int,
+            nmi_latch:self.nmi_latch,
+            interrupt_inhibit:self.interrupt_inhibit,
+            trap:self.trap,
+            halted:self.halted,
+            breakpoint_resume:self.breakpoint_resume,
+        }
+    }
+
+    /// Imports a state exported by [state]. Execution continues exactly
+    /// where it was taken, including a bus cycle or REP in progress.
+    pub fn restore(&mut self, s:&CpuState) {
+        self.ax = s.ax;
+        self.bx = s.bx;
+        self.cx = s.cx;
+        self.dx = s.dx;
+        self.si = s.si;
+        self.di = s.di;
+        self.bp = s.bp;
+        self.sp = s.sp;
+
+        self.ds = s.ds;
+        self.cs = s.cs;
+        self.ss = s.ss;
+        self.es = s.es;
+
+        self.lazy_flags = None;
+        self.flags = s.flags;
+        self.emulation = s.emulation;
+        self.md_writable = s.md_writable;
+
+        self.pc = s.pc;
+        self.prefetch_queue.clear();
+        for &b in &s.queue {
+            self.prefetch_queue.try_push(b);
+        }
+
+        self.t_state = s.t_state;
+        self.bus_cycle = s.bus_cycle;
+        self.bus_data = s.bus_data;
+        self.fetch_dropped = s.fetch_dropped;
+        self.ready = s.ready;
+        self.wait_states = s.wait_states;
+        self.ins_clocks = s.ins_clocks;
+        self.eu = s.eu.clone();
+        self.code_pending = s.eu.code;
+
+        self.rep = s.rep;
+
+        self.intr = s.intr;
+        self.nmi = s.nmi;
+        self.fpu_int = s.fpu_int;
+        self.nmi_latch = s.nmi_latch;
+        self.interrupt_inhibit = s.interrupt_inhibit;
+        self.trap = s.trap;
+        self.halted = s.halted;
+        self.breakpoint_resume = s.breakpoint_resume;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let mut cpu = I8088::new();
+        // mov ax, 0x1234; inc ax
+        cpu.bus.load(0x100, &[0xB8, 0x34, 0x12, 0x40]).unwrap();
+        cpu.set_ip(0x100);
+        let before = cpu.state();
+        cpu.step().unwrap();
+        assert!(before.diff(&before).is_empty());
+        let changes:Vec<_> = before.diff(&cpu.state()).iter()
+            .map(|c| c.to_string()).collect();
+        assert_eq!(changes, ["ax: 0000 -> 1234", "ip: 0100 -> 0103"]);
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut cpu = I8088::new();
+        // mov cx, 3; rep stosb; hlt
+        cpu.bus.load(0x100, &[0xB9, 0x03, 0x00, 0xF3, 0xAA, 0xF4]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.set(Register::DI, 0x200);
+        cpu.set(Register::AX, 0x55);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+
+        // Taken in the middle of the REP, with bytes queued.
+        let saved = cpu.state();
+        assert!(saved.rep.is_some() && !saved.queue.is_empty());
+        assert_eq!(saved.ip(), 0x103);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let done = cpu.state();
+        assert_ne!(done, saved);
+
+        cpu.restore(&saved);
+        assert_eq!(cpu.state(), saved);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.state(), done);
+    }
+
+    #[test]
+    fn test_restore_mid_instruction() {
+        let mut cpu = I8088::new();
+        // mov ax, [0x200]; inc ax
+        cpu.bus.load(0x100, &[0xA1, 0x00, 0x02, 0x40]).unwrap();
+        cpu.set_ip(0x100);
+        let mut ips = Vec::new();
+        for _ in 0..24 {
+            cpu.cycle().unwrap();
+            let s = cpu.state();
+            assert_eq!(s.ip(), cpu.ip());
+            ips.push(s.ip());
+            let mut other = I8088::new();
+            other.restore(&s);
+            assert_eq!(other.state(), s);
+        }
+        assert!(ips.contains(&0x100) && ips.contains(&0x103));
+
+        // Inputs and a pending breakpoint resume are carried too.
+        cpu.set_ready(false);
+        cpu.set_breakpoint(0x104);
+        cpu.resume_breakpoint();
+        let s = cpu.state();
+        assert!(!s.ready && s.breakpoint_resume);
+        let mut other = I8088::new();
+        other.restore(&s);
+        assert_eq!(other.state(), s);
+    }
+}
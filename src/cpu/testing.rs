@@ -0,0 +1,11 @@
+use crate::cpu::I8088;
+
+/// Fixture shared by the tests of the CPU modules. Runs [code] from
+/// 0000:0100 until IP leaves it.
+pub(crate) fn run(cpu:&mut I8088, code:&[u8]) {
+    cpu.bus.load(0x100, code).unwrap();
+    cpu.set_ip(0x100);
+    while (0x100..0x100 + code.len()).contains(&(cpu.ip() as usize)) {
+        cpu.advance().unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+use crate::cpu::{
+    decode::{Instruction, Operand, OperandSize},
+    mnemonic::Mnemonic::{self, *},
+    register::Register,
+};
+
+/// Clocks added by the 8-bit bus for every word transferred to or from
+/// memory or I/O.
+pub const WORD_TRANSFER_CLOCKS:u16          = 4;
+/// Clocks taken by each segment override or LOCK prefix.
+pub const PREFIX_CLOCKS:u16                 = 2;
+/// Clocks taken to acknowledge an INTR or NMI and enter its handler.
+pub const INTR_CLOCKS:u16                   = 61;
+pub const NMI_CLOCKS:u16                    = 50;
+/// Clocks taken to enter INT 1 after an instruction executed with TF set.
+pub const TRAP_CLOCKS:u16                   = 50;
+/// Clocks taken to enter INT 0 after a divide error, including the stack
+/// and vector transfers.
+pub const DIVIDE_ERROR_CLOCKS:u16           = 51 + 5 * WORD_TRANSFER_CLOCKS;
+/// Clocks of the V20 for any effective address, which it calculates in
+/// dedicated hardware.
+pub const V20_EA_CLOCKS:u16                 = 2;
+/// Clocks of the V20 per byte of ADD4S, SUB4S and CMP4S.
+pub const V20_BCD_CLOCKS:u16                = 19;
+/// Clocks of the V20 per nesting level of ENTER.
+pub const V20_ENTER_CLOCKS:u16              = 16;
+
+/// Clock counts of a decoded instruction, following the datasheet. The
+/// parts that depend on data, such as multiply and divide or shifts by CL,
+/// are added by the EU as it executes the instruction.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timing {
+    pub base:u16,      /* clocks of the 8086, including prefixes */
+    pub ea:u16,        /* effective address calculation */
+    pub transfers:u16, /* word memory transfers, each taking 4 more clocks */
+    pub taken:u16,     /* extra clocks when a branch is taken */
+    pub repeat:u16,    /* clocks per iteration of a REP string instruction */
+}
+
+impl Timing {
+    /// Total clocks on the 8088, with or without the branch taken.
+    pub fn clocks(&self, taken:bool) -> u32 {
+        let t = if taken { self.taken } else { 0 };
+        (self.base + self.ea + self.transfers * WORD_TRANSFER_CLOCKS + t) as u32
+    }
+
+    /// Builds the timing of [ins], which was preceded by [prefixes] bytes of
+    /// prefixes.
+    pub(crate) fn of(ins:&Instruction, prefixes:u16) -> Self {
+        let word = ins.size == OperandSize::Word;
+        // Word transfers only apply to word sized operands.
+        let w = |n:u16| if word { n } else { 0 };
+        let mem = match (ins.dst, ins.src) {
+            (Operand::Memory(m), _) | (_, Operand::Memory(m)) => Some(m),
+            _ => None,
+        };
+        let mem_dst = matches!(ins.dst, Operand::Memory(_));
+        let imm = matches!(ins.src, Operand::Immediate(_));
+        let string = matches!(ins.mnemonic, MOVSB | MOVSW | CMPSB | CMPSW
+            | SCASB | SCASW | LODSB | LODSW | STOSB | STOSW
+            | INSB | INSW | OUTSB | OUTSW);
+        let mut t = Timing::default();
+
+        let (base, transfers) = match ins.mnemonic {
+            ADD | ADC | SUB | SBB | AND | OR | XOR => match (mem, mem_dst, imm) {
+                (None, _, false) => (3, 0),
+                (None, _, true) => (4, 0),
+                (Some(_), false, _) => (9, w(1)),
+                (Some(_), true, false) => (16, w(2)),
+                (Some(_), true, true) => (17, w(2)),
+            },
+            CMP => match (mem, imm) {
+                (None, false) => (3, 0),
+                (None, true) => (4, 0),
+                (Some(_), false) => (9, w(1)),
+                (Some(_), true) => (10, w(1)),
+            },
+            TEST => match (mem, imm) {
+                (None, false) => (3, 0),
+                // The accumulator form has no ModR/M byte.
+                (None, true) if ins.modrm.is_none() => (4, 0),
+                (None, true) => (5, 0),
+                (Some(_), false) => (9, w(1)),
+                (Some(_), true) => (11, w(1)),
+            },
+            MOV => match (mem, mem_dst, imm) {
+                _ if (0xA0..=0xA3).contains(&ins.opcode) => (10, w(1)),
+                (None, _, false) => (2, 0),
+                (None, _, true) => (4, 0),
+                (Some(_), false, _) => (8, w(1)),
+                (Some(_), true, false) => (9, w(1)),
+                (Some(_), true, true) => (10, w(1)),
+            },
+            INC | DEC => match mem {
+                None if (0x40..=0x4F).contains(&ins.opcode) => (2, 0),
+                None => (3, 0),
+                Some(_) => (15, w(2)),
+            },
+            NOT | NEG => if mem.is_some() { (16, w(2)) } else { (3, 0) },
+            ROL | ROR | RCL | RCR | SHL | SAL | SHR | SAR | SETMO => {
+                let cl = ins.src == Operand::Register(Register::CL);
+                match (mem, cl) {
+                    (None, false) => (2, 0),
+                    (None, true) => (8, 0),
+                    (Some(_), false) => (15, w(2)),
+                    (Some(_), true) => (20, w(2)),
+                }
+            },
+            // Minimum counts, the EU adds the data dependent part.
+            MUL => mul_div_base(mem.is_some(), word, 70, 118),
+            IMUL => mul_div_base(mem.is_some(), word, 80, 128),
+            DIV => mul_div_base(mem.is_some(), word, 80, 144),
+            IDIV => mul_div_base(mem.is_some(), word, 101, 165),
+            AAA | AAS | DAA | DAS | SALC => (4, 0),
+            AAD => (60, 0),
+            AAM => (83, 0),
+            CBW => (2, 0),
+            CWD => (5, 0),
+
+            XCHG => match mem {
+                Some(_) => (17, w(2)),
+                None if (0x91..=0x97).contains(&ins.opcode) => (3, 0),
+                None => (4, 0),
+            },
+            LEA => (2, 0),
+            LDS | LES => (16, 2),
+            XLAT => (11, 0),
+            LAHF | SAHF => (4, 0),
+            PUSHF => (10, 1),
+            POPF => (8, 1),
+            PUSH => match ins.dst {
+                Operand::Memory(_) => (16, 2),
+                Operand::Register(r) if r.segment().is_some() => (10, 1),
+                _ => (11, 1),
+            },
+            POP => if mem.is_some() { (17, 2) } else { (8, 1) },
+            IN => if ins.src == Operand::Register(Register::DX) {
+                (8, w(1))
+            } else {
+                (10, w(1))
+            },
+            OUT => if ins.dst == Operand::Register(Register::DX) {
+                (8, w(1))
+            } else {
+                (10, w(1))
+            },
+
+            JO | JNO | JB | JNB | JZ | JNZ | JBE | JA | JS | JNS | JP | JNP
+            | JL | JNL | JLE | JG => { t.taken = 12; (4, 0) },
+            JCXZ => { t.taken = 12; (6, 0) },
+            LOOP => { t.taken = 12; (5, 0) },
+            LOOPE | LOOPZ => { t.taken = 12; (6, 0) },
+            LOOPNE | LOOPNZ => { t.taken = 14; (5, 0) },
+            JMP => match (ins.dst, ins.far) {
+                (Operand::Memory(_), false) => (18, 1),
+                (Operand::Memory(_), true) => (24, 2),
+                (Operand::Register(_), _) => (11, 0),
+                _ => (15, 0),
+            },
+            CALL => match (ins.dst, ins.far) {
+                (Operand::Memory(_), false) => (21, 2),
+                (Operand::Memory(_), true) => (37, 4),
+                (Operand::Register(_), _) => (16, 1),
+                (Operand::Far(..), _) => (28, 2),
+                _ => (19, 1),
+            },
+            // Returns take longer on the 8088 than the 8086 counts and word
+            // transfers give, refilling the queue through the 8-bit bus.
+            RET | RETN | RETF => match (ins.far, ins.dst != Operand::None) {
+                (false, false) => (16, 1),
+                (false, true) => (20, 1),
+                (true, false) => (24, 2),
+                (true, true) => (23, 2),
+            },
+            // FLAGS, CS and IP are pushed and the vector read.
+            INT if ins.opcode == 0xCC => (52, 5),
+            INT => (51, 5),
+            INTO => {
+                t.taken = 49 + 5 * WORD_TRANSFER_CLOCKS;
+                (4, 0)
+            },
+            IRET => (32, 3),
+
+            MOVSB | MOVSW => (18, w(2)),
+            CMPSB | CMPSW => (22, w(2)),
+            SCASB | SCASW => (15, w(1)),
+            LODSB | LODSW => (12, w(1)),
+            STOSB | STOSW => (11, w(1)),
+
+            WAIT | NOP => (3, 0),
+            ESC if mem.is_some() => (8, 0),
+            _ => (2, 0),
+        };
+
+        t.base = base + prefixes * PREFIX_CLOCKS;
+        t.transfers = transfers;
+        if let Some(m) = mem {
+            // LEA is EA only, and moffs forms carry their address directly.
+            if !(0xA0..=0xA3).contains(&ins.opcode) {
+                t.ea = m.ea_clocks() as u16;
+            }
+        }
+
+        // REP strings have a fixed setup time, including the prefix, and then
+        // a cost per iteration.
+        if string && ins.rep.is_some() {
+            let per = match ins.mnemonic {
+                MOVSB | MOVSW => 17,
+                CMPSB | CMPSW => 22,
+                SCASB | SCASW => 15,
+                LODSB | LODSW => 13,
+                _ => 10,
+            };
+            t.base = 9 + (prefixes - 1) * PREFIX_CLOCKS;
+            t.repeat = per + t.transfers * WORD_TRANSFER_CLOCKS;
+            t.transfers = 0;
+        }
+        t
+    }
+
+    /// Builds the timing of [ins] on the V20. The counts are approximate:
+    /// the V20 runs most instructions in the clocks of the 8088, but takes
+    /// a fixed time for any effective address, multiplies and divides with
+    /// no data dependent part, and shifts and repeats strings faster.
+    pub(crate) fn of_v20(ins:&Instruction, prefixes:u16) -> Self {
+        let mut t = Self::of(ins, prefixes);
+        let word = ins.size == OperandSize::Word;
+        let mem = ins.has_memory_operand();
+        let by_size = |b:u16, w:u16| if word { w } else { b };
+        if t.ea != 0 {
+            t.ea = V20_EA_CLOCKS;
+        }
+
+        let base = match ins.mnemonic {
+            MUL => by_size(21, 29) + if mem { 6 } else { 0 },
+            IMUL if ins.src2 != Operand::None => if mem { 34 } else { 28 },
+            IMUL => by_size(33, 47) + if mem { 6 } else { 0 },
+            DIV => by_size(19, 25) + if mem { 6 } else { 0 },
+            IDIV => by_size(29, 38) + if mem { 6 } else { 0 },
+            AAM => 15,
+            AAD => 7,
+            // Counts in CL take a clock per bit on execution.
+            ROL | ROR | RCL | RCR | SHL | SAL | SHR | SAR | SETMO => {
+                let m = if mem { 12 } else { 0 };
+                match ins.src {
+                    _ if matches!(ins.opcode, 0xD0 | 0xD1) => if mem { 16 } else { 2 },
+                    Operand::Immediate(n) => 7 + m + (n & 0xFF),
+                    _ => 7 + m,
+                }
+            },
+            PUSH if matches!(ins.dst, Operand::Immediate(_)) => 11,
+            PUSHA => 67,
+            POPA => 75,
+            BOUND => 24,
+            ENTER => 16,
+            LEAVE => 10,
+            INSB | INSW | OUTSB | OUTSW => 9,
+            TEST1 => if mem { 12 } else { 4 },
+            CLR1 | SET1 | NOT1 => if mem { 14 } else { 5 },
+            ADD4S | SUB4S | CMP4S => 7,
+            ROL4 | ROR4 => if mem { 28 } else { 25 },
+            INS => 35,
+            EXT => 34,
+            BRKEM => 50,
+            _ => t.base - prefixes * PREFIX_CLOCKS,
+        };
+        t.base = base + prefixes * PREFIX_CLOCKS;
+
+        if t.repeat != 0 {
+            let (per, transfers) = match ins.mnemonic {
+                MOVSB | MOVSW => (8, 2),
+                CMPSB | CMPSW => (14, 2),
+                SCASB | SCASW => (10, 1),
+                LODSB | LODSW => (7, 1),
+                STOSB | STOSW => (6, 1),
+                _ => (8, 1),
+            };
+            t.base = 9 + (prefixes - 1) * PREFIX_CLOCKS;
+            t.repeat = per + by_size(0, transfers * WORD_TRANSFER_CLOCKS);
+        }
+        t
+    }
+}
+
+fn mul_div_base(mem:bool, word:bool, byte_reg:u16, word_reg:u16) -> (u16, u16) {
+    match (mem, word) {
+        (false, false) => (byte_reg, 0),
+        (false, true) => (word_reg, 0),
+        (true, false) => (byte_reg + 6, 0),
+        (true, true) => (word_reg + 6, 1),
+    }
+}
+
+/// Data dependent clocks of MUL and IMUL of the accumulator [a] by [b],
+/// above the minimum in the datasheet. The CORX microcode loop shifts
+/// through the bits of the accumulator and takes one more clock on each set
+/// bit, where it adds [b] into the product. IMUL works on magnitudes, and
+/// spends extra clocks negating negative operands and the product.
+pub(crate) fn mul_clocks(m:Mnemonic, s:OperandSize, a:u16, b:u16) -> u32 {
+    let (mut a, b) = (a & s.mask(), b & s.mask());
+    let mut clocks = 0;
+    if m == IMUL {
+        let (a_neg, b_neg) = (a & s.msb() != 0, b & s.msb() != 0);
+        if a_neg {
+            a = a.wrapping_neg() & s.mask();
+            clocks += 4;
+        }
+        if b_neg { clocks += 4; }
+        if a_neg != b_neg { clocks += 2; }
+    }
+    clocks + a.count_ones()
+}
+
+/// Data dependent clocks of DIV and IDIV of [hi]:lo by [d] giving [q],
+/// above the minimum in the datasheet. The CORD microcode loop makes a
+/// trial subtraction per quotient bit and takes one more clock to undo it
+/// for each 0 bit. IDIV spends a clock on each negative operand and on
+/// negating the quotient.
+pub(crate) fn div_clocks(m:Mnemonic, s:OperandSize, hi:u16, d:u16, q:u16) -> u32 {
+    let bits = s.bytes() as u32 * 8;
+    let mut q = q & s.mask();
+    let mut clocks = 0;
+    if m == IDIV {
+        let (n_neg, d_neg) = (hi & s.msb() != 0, d & s.msb() != 0);
+        clocks += n_neg as u32 + d_neg as u32;
+        if n_neg != d_neg {
+            q = q.wrapping_neg() & s.mask();
+            clocks += 1;
+        }
+    }
+    clocks + bits - q.count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(bytes:&[u8]) -> Timing {
+        let mut it = bytes.iter().copied();
+        Instruction::decode(|| it.next().unwrap()).unwrap().timing
+    }
+
+    #[test]
+    fn test_instruction_timing() {
+        // add ax, bx
+        assert_eq!(timing(&[0x01, 0xD8]).clocks(false), 3);
+        // add [bx+si+0x10], ax: 16 + EA 11 + two word transfers
+        assert_eq!(timing(&[0x01, 0x40, 0x10]).clocks(false), 35);
+        // ret, ret 4, retf, retf 4 and iret, as the 8088 datasheet gives
+        assert_eq!(timing(&[0xC3]).clocks(false), 20);
+        assert_eq!(timing(&[0xC2, 0x04, 0x00]).clocks(false), 24);
+        assert_eq!(timing(&[0xCB]).clocks(false), 32);
+        assert_eq!(timing(&[0xCA, 0x04, 0x00]).clocks(false), 31);
+        assert_eq!(timing(&[0xCF]).clocks(false), 44);
+        // add [bx+si+0x10], al
+        assert_eq!(timing(&[0x00, 0x40, 0x10]).clocks(false), 27);
+        // es: mov ax, [0x1234]
+        assert_eq!(timing(&[0x26, 0xA1, 0x34, 0x12]).clocks(false), 16);
+        // jz: 4 not taken, 16 taken
+        let t = timing(&[0x74, 0x00]);
+        assert_eq!((t.clocks(false), t.clocks(true)), (4, 16));
+        // loop
+        let t = timing(&[0xE2, 0x00]);
+        assert_eq!((t.clocks(false), t.clocks(true)), (5, 17));
+        // call near pushes one word
+        assert_eq!(timing(&[0xE8, 0x00, 0x00]).clocks(false), 23);
+        // rep movsw
+        let t = timing(&[0xF3, 0xA5]);
+        assert_eq!((t.clocks(false), t.repeat), (9, 25));
+        // shl ax, cl: 8 plus 4 per bit, added on execution
+        assert_eq!(timing(&[0xD3, 0xE0]).clocks(false), 8);
+    }
+
+    #[test]
+    fn test_execution_clocks() {
+        use crate::cpu::I8088;
+        let mut cpu = I8088::new();
+        // shl ax, cl; jz -4; mul bl; rep stosb
+        cpu.bus.load(0x100, &[0xD3, 0xE0, 0x74, 0xFC, 0xF6, 0xE3, 0xF3, 0xAA])
+            .unwrap();
+        cpu.set_ip(0x100);
+        cpu.cx = 3;
+        cpu.advance().unwrap();
+        assert_eq!(cpu.instruction_clocks(), 20);
+        // ZF is set by shifting out all bits of zero.
+        cpu.advance().unwrap();
+        assert_eq!((cpu.ip(), cpu.instruction_clocks()), (0x100, 16));
+
+        // One more clock per set bit of AL.
+        cpu.set_ip(0x104);
+        cpu.ax = 0x0B;
+        cpu.bx = 0x0F;
+        cpu.advance().unwrap();
+        assert_eq!(cpu.instruction_clocks(), 73);
+
+        cpu.cx = 2;
+        cpu.advance().unwrap();
+        assert_eq!(cpu.instruction_clocks(), 9 + 10);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.instruction_clocks(), 10);
+    }
+
+    #[test]
+    fn test_mul_div_clocks() {
+        let (b, w) = (OperandSize::Byte, OperandSize::Word);
+        assert_eq!(mul_clocks(MUL, b, 0x0F, 0xFF), 4);
+        assert_eq!(mul_clocks(MUL, w, 0xFFFF, 1), 16);
+        // -2 * 3: one negation each for AX and the product.
+        assert_eq!(mul_clocks(IMUL, w, 0xFFFE, 3), 4 + 2 + 1);
+
+        // 0x0F has four 0 bits to undo.
+        assert_eq!(div_clocks(DIV, b, 0, 7, 0x0F), 4);
+        assert_eq!(div_clocks(IDIV, b, 0xFF, 0x02, 0xFD), 1 + 1 + 6);
+    }
+}
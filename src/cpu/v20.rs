@@ -0,0 +1,447 @@
+use crate::cpu::{
+    I8088, CpuModel,
+    addr::Segment,
+    decode::{Instruction, OperandSize},
+    eu::Location,
+    flags::*,
+    interrupt::VECTOR_BOUND,
+    mnemonic::Mnemonic::{self, *},
+    timing::{V20_BCD_CLOCKS, V20_ENTER_CLOCKS},
+};
+
+impl I8088 {
+    pub fn model(&self) -> CpuModel {
+        self.model
+    }
+
+    /// Fits another processor into the socket. Instructions are decoded
+    /// for [model] from the next one on, and 8080 emulation is left.
+    pub fn set_model(&mut self, model:CpuModel) {
+        self.model = model;
+        self.emulation = false;
+        self.md_writable = false;
+        self.decode_cache.clear();
+    }
+
+    /// Whether the V20 is running 8080 code, entered through BRKEM.
+    pub fn emulation_mode(&self) -> bool {
+        self.emulation
+    }
+
+    /// Executes the instructions the V20 adds to the 8088.
+    pub(crate) fn execute_v20(&mut self, ins:&Instruction) {
+        let s = ins.size;
+        match ins.mnemonic {
+            IMUL => {
+                let a = self.read_operand(&ins.src, ins, s);
+                let b = self.read_operand(&ins.src2, ins, s);
+                let (_, lo) = self.alu_imul(a, b, s);
+                let dst = self.resolve(&ins.dst, ins);
+                self.write_loc(dst, s, lo);
+            },
+            // The value of SP from before the first push is stored, and
+            // skipped on the way back.
+            PUSHA => {
+                let sp = self.sp;
+                for v in [self.ax, self.cx, self.dx, self.bx, sp, self.bp,
+                          self.si, self.di] {
+                    self.push(v);
+                }
+            },
+            POPA => {
+                self.di = self.pop();
+                self.si = self.pop();
+                self.bp = self.pop();
+                self.pop();
+                self.bx = self.pop();
+                self.dx = self.pop();
+                self.cx = self.pop();
+                self.ax = self.pop();
+            },
+            BOUND => {
+                let index = self.read_operand(&ins.dst, ins, s) as i16;
+                if let Location::Memory(sg, o) = self.resolve(&ins.src, ins) {
+                    let lo = self.read_mem_16(sg, o) as i16;
+                    let hi = self.read_mem_16(sg, o.wrapping_add(2)) as i16;
+                    if !(lo..=hi).contains(&index) {
+                        self.interrupt(VECTOR_BOUND);
+                    }
+                }
+            },
+            ENTER => {
+                let size = self.read_operand(&ins.dst, ins, OperandSize::Word);
+                let level = self.read_operand(&ins.src, ins, OperandSize::Byte) & 0x1F;
+                self.push(self.bp);
+                let frame = self.sp;
+                if level > 0 {
+                    // Frame pointers of the enclosing levels, then our own.
+                    for _ in 1..level {
+                        self.bp = self.bp.wrapping_sub(2);
+                        let v = self.read_mem_16(Segment::SS, self.bp);
+                        self.push(v);
+                    }
+                    self.push(frame);
+                }
+                self.bp = frame;
+                self.sp = self.sp.wrapping_sub(size);
+                self.ins_clocks += (V20_ENTER_CLOCKS * level) as u32;
+            },
+            LEAVE => {
+                self.sp = self.bp;
+                self.bp = self.pop();
+            },
+            // The bit number is taken modulo the operand width. Only TEST1
+            // affects flags.
+            TEST1 | CLR1 | SET1 | NOT1 => {
+                let dst = self.resolve(&ins.dst, ins);
+                let a = self.read_loc(dst, s);
+                let n = self.read_operand(&ins.src, ins, OperandSize::Byte);
+                let bit = 1 << (n & (s.bytes() * 8 - 1));
+                match ins.mnemonic {
+                    TEST1 => {
+                        self.set_flag(FLAG_ZF, a & bit == 0);
+                        self.set_flag(FLAG_CF, false);
+                        self.set_flag(FLAG_OF, false);
+                    },
+                    CLR1 => self.write_loc(dst, s, a & !bit),
+                    SET1 => self.write_loc(dst, s, a | bit),
+                    _ => self.write_loc(dst, s, a ^ bit),
+                }
+            },
+            ADD4S | SUB4S | CMP4S => self.bcd_string(ins),
+            // Rotate a digit through the low nibble of AL.
+            ROL4 | ROR4 => {
+                let dst = self.resolve(&ins.dst, ins);
+                let b = self.read_loc(dst, OperandSize::Byte) as u8;
+                let al = self.ax as u8;
+                let (b, digit) = if ins.mnemonic == ROL4 {
+                    ((b << 4) | (al & 0x0F), b >> 4)
+                } else {
+                    ((al << 4) | (b >> 4), b & 0x0F)
+                };
+                self.write_loc(dst, OperandSize::Byte, b as u16);
+                self.ax = (self.ax & 0xFFF0) | digit as u16;
+            },
+            INS | EXT => self.bit_field(ins),
+            // An interrupt that switches to 8080 emulation for its handler.
+            // RETEM returns through the same frame.
+            BRKEM => {
+                let v = self.read_operand(&ins.dst, ins, OperandSize::Byte);
+                self.interrupt(v as u8);
+                self.emulation = true;
+                self.md_writable = true;
+            },
+            m => unreachable!("{:?} is not a V20 instruction", m),
+        }
+    }
+
+    /// ADD4S, SUB4S and CMP4S on packed BCD strings of CL digits, at DS:SI
+    /// (or the segment override) and ES:DI, from the least significant byte
+    /// up. The result goes to ES:DI, except for CMP4S. CF is the final
+    /// carry or borrow, and ZF is set if all result digits are zero.
+    fn bcd_string(&mut self, ins:&Instruction) {
+        let src = ins.segment.unwrap_or(Segment::DS);
+        let bytes = (self.cx as u8 as u16).div_ceil(2);
+        let (mut carry, mut zero) = (false, true);
+        for i in 0..bytes {
+            let b = self.read_mem_8(src, self.si.wrapping_add(i));
+            let a = self.read_mem_8(Segment::ES, self.di.wrapping_add(i));
+            let (res, c) = bcd_byte(ins.mnemonic, a, b, carry);
+            carry = c;
+            zero &= res == 0;
+            if ins.mnemonic != CMP4S {
+                self.write_mem_8(Segment::ES, self.di.wrapping_add(i), res);
+            }
+        }
+        self.ins_clocks += (V20_BCD_CLOCKS * bytes) as u32;
+        self.set_flag(FLAG_CF, carry);
+        self.set_flag(FLAG_ZF, zero);
+    }
+
+    /// INS stores the low bits of AX into a bit field at ES:DI, and EXT
+    /// loads one from DS:SI into AX. The bit offset is held in the first
+    /// register operand and the length less one in the second, both modulo
+    /// 16. The offset is then advanced past the field, moving the pointer
+    /// on to the next word when it wraps.
+    fn bit_field(&mut self, ins:&Instruction) {
+        let offset_loc = self.resolve(&ins.dst, ins);
+        let offset = self.read_loc(offset_loc, OperandSize::Byte) & 0x0F;
+        let len = (self.read_operand(&ins.src, ins, OperandSize::Byte) & 0x0F) + 1;
+        let (sg, o) = match ins.mnemonic {
+            INS => (Segment::ES, self.di),
+            _ => (Segment::DS, self.si),
+        };
+        let lo = self.read_mem_16(sg, o) as u32;
+        let hi = self.read_mem_16(sg, o.wrapping_add(2)) as u32;
+        let v = (hi << 16) | lo;
+        let mask = ((1u32 << len) - 1) << offset;
+        if ins.mnemonic == INS {
+            let v = (v & !mask) | (((self.ax as u32) << offset) & mask);
+            self.write_mem_16(sg, o, v as u16);
+            if offset + len > 16 {
+                self.write_mem_16(sg, o.wrapping_add(2), (v >> 16) as u16);
+            }
+        } else {
+            self.ax = ((v & mask) >> offset) as u16;
+        }
+
+        let end = offset + len;
+        self.write_loc(offset_loc, OperandSize::Byte, end & 0x0F);
+        if end > 0x0F {
+            match ins.mnemonic {
+                INS => self.di = self.di.wrapping_add(2),
+                _ => self.si = self.si.wrapping_add(2),
+            }
+        }
+    }
+}
+
+// Adds or subtracts two packed BCD bytes with carry, a digit at a time.
+fn bcd_byte(m:Mnemonic, a:u8, b:u8, carry:bool) -> (u8, bool) {
+    let mut carry = carry;
+    let mut res = 0;
+    for shift in [0, 4] {
+        let (x, y) = ((a >> shift) & 0x0F, (b >> shift) & 0x0F);
+        let mut d = if m == ADD4S {
+            x as i8 + y as i8 + carry as i8
+        } else {
+            x as i8 - y as i8 - carry as i8
+        };
+        carry = !(0..=9).contains(&d);
+        if d > 9 { d -= 10; }
+        if d < 0 { d += 10; }
+        res |= (d as u8) << shift;
+    }
+    (res, carry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CpuStatus, decode::{DecodeError, Operand}, testing::run};
+
+    fn v20() -> I8088 {
+        let mut cpu = I8088::new();
+        cpu.set_model(CpuModel::V20);
+        cpu.sp = 0x1000;
+        cpu
+    }
+
+    fn decode(model:CpuModel, bytes:&[u8]) -> Result<Instruction, DecodeError> {
+        let mut it = bytes.iter().copied();
+        Instruction::decode_for(model, || it.next().expect("decoder overran input"))
+    }
+
+    #[test]
+    fn test_decode_v20() {
+        // The 8088 aliases are the 80186 additions on the V20.
+        let ins = decode(CpuModel::V20, &[0x6B, 0xC3, 0xFD]).unwrap();
+        assert_eq!(ins.to_string(), "imul ax, bx, 0xfffd");
+        let ins = decode(CpuModel::I8088, &[0x6B, 0xC3]).unwrap();
+        assert_eq!(ins.mnemonic, JNP);
+        let ins = decode(CpuModel::V20, &[0xC8, 0x10, 0x00, 0x02]).unwrap();
+        assert_eq!((ins.to_string(), ins.far), ("enter 0x10, 0x2".into(), false));
+        assert_eq!(decode(CpuModel::V20, &[0xC1, 0x27, 0x03]).unwrap().to_string(),
+                   "shl word [bx], 0x3");
+
+        // 0x0F leads the NEC opcodes instead of popping CS.
+        let ins = decode(CpuModel::V20, &[0x0F, 0x19, 0xC0, 0x0F]).unwrap();
+        assert_eq!((ins.to_string(), ins.len), ("test1 ax, 0xf".into(), 4));
+        let ins = decode(CpuModel::V20, &[0x0F, 0x28, 0x07]).unwrap();
+        assert_eq!(ins.to_string(), "rol4 byte [bx]");
+        assert_eq!(decode(CpuModel::V20, &[0x0F, 0xFF, 0x80]).unwrap().to_string(),
+                   "brkem 0x80");
+        assert!(matches!(decode(CpuModel::V20, &[0x0F, 0x00]),
+            Err(DecodeError::UnknownOpcode(0x0F))));
+        assert!(matches!(decode(CpuModel::V20, &[0x64]),
+            Err(DecodeError::UnimplementedOpcode(0x64))));
+        assert_eq!(decode(CpuModel::V20, &[0x90]).unwrap().src2, Operand::None);
+    }
+
+    #[test]
+    fn test_80186_instructions() {
+        let mut cpu = v20();
+        // mov ax, 0x1234; pusha; xor ax, ax; popa; push byte -2; pop dx
+        run(&mut cpu, &[0xB8, 0x34, 0x12, 0x60, 0x31, 0xC0, 0x61, 0x6A, 0xFE,
+                        0x5A]);
+        assert_eq!((cpu.ax, cpu.dx, cpu.sp), (0x1234, 0xFFFE, 0x1000));
+
+        // mov bx, 7; imul cx, bx, -3; mov dl, 0x81; shr dl, 4
+        run(&mut cpu, &[0xBB, 0x07, 0x00, 0x6B, 0xCB, 0xFD, 0xB2, 0x81, 0xC0,
+                        0xEA, 0x04]);
+        assert_eq!((cpu.cx, cpu.dx & 0xFF), (0xFFEB, 0x08));
+
+        // mov bp, 0x2000; enter 4, 1; leave
+        cpu.bus.load(0x100, &[0xBD, 0x00, 0x20, 0xC8, 0x04, 0x00, 0x01])
+            .unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        cpu.advance().unwrap();
+        assert_eq!((cpu.bp, cpu.sp), (0x0FFE, 0x0FF8));
+        assert_eq!(cpu.read_mem_16(Segment::SS, 0x0FFE), 0x2000);
+        assert_eq!(cpu.read_mem_16(Segment::SS, 0x0FFC), 0x0FFE);
+        run(&mut cpu, &[0xC9]);
+        assert_eq!((cpu.bp, cpu.sp), (0x2000, 0x1000));
+    }
+
+    #[test]
+    fn test_bound() {
+        let mut cpu = v20();
+        // INT 5 handler at 0050:0000, bounds of 0..=9 at 0x200
+        cpu.bus.load(0x14, &[0x00, 0x00, 0x50, 0x00]).unwrap();
+        cpu.bus.load(0x200, &[0x00, 0x00, 0x09, 0x00]).unwrap();
+        // mov si, 9; bound si, [0x200]
+        run(&mut cpu, &[0xBE, 0x09, 0x00, 0x62, 0x36, 0x00, 0x02]);
+        assert_eq!(cpu.cs, 0x0000);
+        cpu.si = 10;
+        cpu.set_ip(0x103);
+        cpu.advance().unwrap();
+        assert_eq!((cpu.cs, cpu.ip()), (0x0050, 0x0000));
+    }
+
+    #[test]
+    fn test_bit_instructions() {
+        let mut cpu = v20();
+        // mov cl, 0x13; set1 ax, cl; not1 byte [0x200], 7; test1 ax, 3
+        run(&mut cpu, &[0xB1, 0x13, 0x0F, 0x15, 0xC0, 0x0F, 0x1E, 0x06, 0x00,
+                        0x02, 0x07, 0x0F, 0x19, 0xC0, 0x03]);
+        assert_eq!(cpu.ax, 0x0008);
+        assert_eq!(cpu.read_mem_8(Segment::DS, 0x200), 0x80);
+        assert!(!cpu.flag(FLAG_ZF));
+        // clr1 ax, 3; test1 ax, 3
+        run(&mut cpu, &[0x0F, 0x1B, 0xC0, 0x03, 0x0F, 0x19, 0xC0, 0x03]);
+        assert_eq!(cpu.ax, 0);
+        assert!(cpu.flag(FLAG_ZF));
+
+        // mov al, 0x5; mov byte [0x300], 0x12; rol4 [0x300]
+        run(&mut cpu, &[0xB0, 0x05, 0xC6, 0x06, 0x00, 0x03, 0x12, 0x0F, 0x28,
+                        0x06, 0x00, 0x03]);
+        assert_eq!((cpu.ax, cpu.read_mem_8(Segment::DS, 0x300)), (0x01, 0x25));
+        // ror4 [0x300]
+        run(&mut cpu, &[0x0F, 0x2A, 0x06, 0x00, 0x03]);
+        assert_eq!((cpu.ax, cpu.read_mem_8(Segment::DS, 0x300)), (0x05, 0x12));
+    }
+
+    #[test]
+    fn test_bit_fields() {
+        let mut cpu = v20();
+        cpu.di = 0x400;
+        cpu.si = 0x400;
+        cpu.ax = 0x002B;
+        // ins bl, 5 with BL = 14: six bits straddling two words
+        cpu.bx = 14;
+        run(&mut cpu, &[0x0F, 0x39, 0xC3, 0x05]);
+        assert_eq!(cpu.read_mem_16(Segment::ES, 0x400), 0xC000);
+        assert_eq!(cpu.read_mem_16(Segment::ES, 0x402), 0x000A);
+        assert_eq!((cpu.bx, cpu.di), (4, 0x402));
+
+        // mov bl, 14; mov cl, 5; ext bl, cl
+        run(&mut cpu, &[0xB3, 0x0E, 0xB1, 0x05, 0x0F, 0x33, 0xCB]);
+        assert_eq!((cpu.ax, cpu.bx, cpu.si), (0x002B, 4, 0x402));
+    }
+
+    #[test]
+    fn test_bcd_strings() {
+        let mut cpu = v20();
+        // 0x1999 + 0x0001 and 0x0099 - 0x0100
+        cpu.bus.load(0x200, &[0x99, 0x19]).unwrap();
+        cpu.bus.load(0x300, &[0x01, 0x00]).unwrap();
+        cpu.si = 0x300;
+        cpu.di = 0x200;
+        cpu.cx = 4;
+        run(&mut cpu, &[0x0F, 0x20]);
+        assert_eq!(cpu.read_mem_16(Segment::ES, 0x200), 0x2000);
+        assert!(!cpu.flag(FLAG_CF) && !cpu.flag(FLAG_ZF));
+
+        cpu.bus.load(0x200, &[0x99, 0x00]).unwrap();
+        cpu.bus.load(0x300, &[0x00, 0x01]).unwrap();
+        run(&mut cpu, &[0x0F, 0x26]);
+        assert!(cpu.flag(FLAG_CF));
+        assert_eq!(cpu.read_mem_16(Segment::ES, 0x200), 0x0099);
+        run(&mut cpu, &[0x0F, 0x22]);
+        assert_eq!(cpu.read_mem_16(Segment::ES, 0x200), 0x9999);
+        assert!(cpu.flag(FLAG_CF));
+    }
+
+    #[test]
+    fn test_v20_differences() {
+        let mut cpu = v20();
+        // mov ax, 0x0123; aam 0x10; aad 0x10
+        run(&mut cpu, &[0xB8, 0x23, 0x01, 0xD4, 0x10]);
+        assert_eq!(cpu.ax, 0x0305);
+        run(&mut cpu, &[0xD5, 0x10]);
+        assert_eq!(cpu.ax, 35);
+
+        // Multiplies take fixed time, shifts a clock per bit.
+        cpu.bus.load(0x100, &[0xF6, 0xE3, 0xD3, 0xE0]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.instruction_clocks(), 21);
+        cpu.cx = 3;
+        cpu.advance().unwrap();
+        assert_eq!(cpu.instruction_clocks(), 10);
+
+        // An interrupted ES: REP STOSB resumes with its override.
+        cpu.bus.load(0x100, &[0x26, 0xF3, 0xAA]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.cx = 4;
+        cpu.advance().unwrap();
+        cpu.set_nmi(true);
+        cpu.advance().unwrap();
+        assert_eq!(cpu.read_mem_16(Segment::SS, cpu.sp), 0x100);
+    }
+
+    #[test]
+    fn test_emulation_mode() {
+        let mut cpu = v20();
+        cpu.ds = 0x1000;
+        // BRKEM 0x80 enters 8080 code at 1000:0000.
+        cpu.bus.load(0x200, &[0x00, 0x00, 0x00, 0x10]).unwrap();
+        // CALLN 0x81 reaches a native handler at 0000:0400.
+        cpu.bus.load(0x204, &[0x00, 0x04, 0x00, 0x00]).unwrap();
+        // inc ax; iret
+        cpu.bus.load(0x400, &[0x40, 0xCF]).unwrap();
+        // mvi a, 0x40; lxi h, 0x0100; mov m, a; inr m; push h; calln 0x81;
+        // pop d; retem
+        cpu.bus.load(0x10000, &[0x3E, 0x40, 0x21, 0x00, 0x01, 0x77, 0x34,
+                                0xE5, 0xED, 0xED, 0x81, 0xD1, 0xED, 0xFD])
+            .unwrap();
+        cpu.bp = 0x2000;
+        // brkem 0x80
+        cpu.bus.load(0x100, &[0x0F, 0xFF, 0x80]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        assert!(cpu.emulation_mode());
+        assert_eq!(cpu.flags() & FLAG_MD, 0);
+        assert_eq!(cpu.flag(Flag::MD), cpu.flags() & FLAG_MD != 0);
+        for _ in 0..5 {
+            cpu.advance().unwrap();
+        }
+        assert_eq!(cpu.read_mem_8(Segment::DS, 0x100), 0x41);
+        assert_eq!(cpu.read_mem_16(Segment::DS, 0x1FFE), 0x0100);
+
+        // CALLN runs the handler natively, and its IRET resumes emulation.
+        cpu.advance().unwrap();
+        assert!(!cpu.emulation_mode());
+        cpu.advance().unwrap();
+        assert_eq!(cpu.ax, 0x0041);
+        cpu.advance().unwrap();
+        assert!(cpu.emulation_mode());
+        cpu.advance().unwrap();
+        assert_eq!((cpu.dx, cpu.bp), (0x0100, 0x2000));
+
+        // RETEM returns to native code after BRKEM with MD protected again.
+        cpu.advance().unwrap();
+        assert!(!cpu.emulation_mode());
+        assert!(cpu.flag(Flag::MD));
+        assert_eq!((cpu.cs, cpu.ip(), cpu.sp), (0x0000, 0x103, 0x1000));
+        cpu.load_flags(0);
+        assert!(!cpu.emulation_mode());
+
+        // HLT stops in 8080 mode too.
+        cpu.bus.load(0x10000, &[0x76]).unwrap();
+        cpu.set_ip(0x100);
+        cpu.advance().unwrap();
+        assert!(matches!(cpu.advance(), Ok(CpuStatus::Hang)));
+    }
+}
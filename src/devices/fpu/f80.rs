@@ -0,0 +1,515 @@
+use std::{cmp::Ordering, ops::Neg};
+use super::{FPU_EXC_INVALID, FPU_EXC_OVERFLOW, FPU_EXC_PRECISION, FPU_EXC_UNDERFLOW,
+            FPU_EXC_ZERO_DIVIDE};
+
+const BIAS:i32 = 16383;
+
+/// 80-bit temporary real, the format of the 8087 register stack. Values are
+/// held exactly, and loads, stores, integer conversions and sign changes
+/// are exact. The basic arithmetic works on the 64-bit significand and
+/// rounds under the rounding and precision control of the control word.
+/// The transcendental functions go through f64, so their results carry 53
+/// bits of precision rather than 64.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct F80 {
+    pub se:u16,       /* sign and biased exponent */
+    pub mantissa:u64, /* explicit integer bit in bit 63 */
+}
+
+impl Neg for F80 {
+    type Output = F80;
+
+    fn neg(self) -> F80 {
+        F80 { se:self.se ^ 0x8000, ..self }
+    }
+}
+
+/// Operand classes, as reported by FXAM.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Class {
+    Unnormal,
+    NaN,
+    Normal,
+    Infinity,
+    Zero,
+    Denormal,
+}
+
+impl F80 {
+    pub const ZERO:F80 = F80 { se:0x0000, mantissa:0 };
+    pub const ONE:F80 = F80 { se:0x3FFF, mantissa:0x8000_0000_0000_0000 };
+    pub const INFINITY:F80 = F80 { se:0x7FFF, mantissa:0x8000_0000_0000_0000 };
+    /// Negative quiet NaN returned by masked invalid operations.
+    pub const INDEFINITE:F80 = F80 { se:0xFFFF, mantissa:0xC000_0000_0000_0000 };
+    pub const PI:F80 = F80 { se:0x4000, mantissa:0xC90F_DAA2_2168_C235 };
+    pub const LOG2_10:F80 = F80 { se:0x4000, mantissa:0xD49A_784B_CD1B_8AFE };
+    pub const LOG2_E:F80 = F80 { se:0x3FFF, mantissa:0xB8AA_3B29_5C17_F0BC };
+    pub const LOG10_2:F80 = F80 { se:0x3FFD, mantissa:0x9A20_9A84_FBCF_F799 };
+    pub const LN_2:F80 = F80 { se:0x3FFE, mantissa:0xB172_17F7_D1CF_79AC };
+
+    pub fn negative(&self) -> bool {
+        self.se & 0x8000 != 0
+    }
+
+    pub fn exponent(&self) -> u16 {
+        self.se & 0x7FFF
+    }
+
+    pub fn class(&self) -> Class {
+        match (self.exponent(), self.mantissa) {
+            (0, 0) => Class::Zero,
+            (0, _) => Class::Denormal,
+            (0x7FFF, m) if m << 1 == 0 => Class::Infinity,
+            (0x7FFF, _) => Class::NaN,
+            (_, m) if m >> 63 == 0 => Class::Unnormal,
+            _ => Class::Normal,
+        }
+    }
+
+    pub fn is_nan(&self) -> bool {
+        self.class() == Class::NaN
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.class() == Class::Zero
+    }
+
+    pub fn abs(self) -> F80 {
+        F80 { se:self.se & 0x7FFF, ..self }
+    }
+
+    pub fn from_f64(v:f64) -> F80 {
+        let bits = v.to_bits();
+        let sign = (bits >> 48) as u16 & 0x8000;
+        let exp = ((bits >> 52) & 0x7FF) as i32;
+        let frac = bits & ((1 << 52) - 1);
+        let (e, mantissa) = match exp {
+            0 if frac == 0 => (0, 0),
+            0 => {
+                let lz = frac.leading_zeros() as i32;
+                (15372 - lz, frac << lz)
+            },
+            0x7FF => (0x7FFF, 1 << 63 | frac << 11),
+            _ => (exp + 15360, 1 << 63 | frac << 11),
+        };
+        F80 { se:sign | e as u16, mantissa }
+    }
+
+    /// Nearest f64. Values outside of its range become zero or infinity.
+    pub fn to_f64(&self) -> f64 {
+        let sign = if self.negative() { -1.0 } else { 1.0 };
+        match (self.exponent(), self.mantissa) {
+            (0x7FFF, m) if m << 1 == 0 => sign * f64::INFINITY,
+            (0x7FFF, m) => {
+                let s = (self.se as u64 & 0x8000) << 48;
+                f64::from_bits(s | 0x7FF << 52 | 1 << 51 | (m >> 11) & ((1 << 52) - 1))
+            },
+            (_, 0) => sign * 0.0,
+            // Denormals share the exponent of the smallest normal.
+            (e, m) => sign * scale(m as f64, (e as i32).max(1) - BIAS - 63),
+        }
+    }
+
+    pub fn from_i64(v:i64) -> F80 {
+        F80::from_i128(v as i128)
+    }
+
+    /// Exact for magnitudes below 2^64.
+    pub fn from_i128(v:i128) -> F80 {
+        let m = v.unsigned_abs();
+        if m == 0 { return F80::ZERO; }
+        let lz = m.leading_zeros();
+        let sign = if v < 0 { 0x8000 } else { 0 };
+        F80 {
+            se:sign | (BIAS + 127 - lz as i32) as u16,
+            mantissa:((m << lz) >> 64) as u64,
+        }
+    }
+
+    /// Rounds to an integer under the rounding control [rc] of the control
+    /// word. Returns the integer and whether it is inexact, or None for
+    /// NaNs, infinities and magnitudes of 2^64 and above.
+    pub fn round_int(&self, rc:u8) -> Option<(i128, bool)> {
+        let e = self.exponent() as i32;
+        if e == 0x7FFF { return None; }
+        if self.mantissa == 0 { return Some((0, false)); }
+        let e = e.max(1) - BIAS;
+        if e > 63 { return None; }
+
+        let shift = (63 - e).min(127) as u32;
+        let m = self.mantissa as u128;
+        let int = m >> shift;
+        let rem = m & ((1u128 << shift) - 1);
+        let half = if shift == 0 { 0 } else { 1u128 << (shift - 1) };
+        let neg = self.negative();
+        let up = rem != 0 && match rc & 0x03 {
+            0 => rem > half || (rem == half && int & 1 == 1),
+            1 => neg,
+            2 => !neg,
+            _ => false,
+        };
+        let int = (int + up as u128) as i128;
+        Some((if neg { -int } else { int }, rem != 0))
+    }
+
+    /// Exact comparison, or None if either operand is a NaN.
+    pub fn compare(&self, other:&F80) -> Option<Ordering> {
+        if self.is_nan() || other.is_nan() { return None; }
+        if self.is_zero() && other.is_zero() { return Some(Ordering::Equal); }
+        let key = |v:&F80| {
+            let mag = (v.exponent() as i128) << 64 | v.mantissa as i128;
+            if v.negative() { -mag } else { mag }
+        };
+        Some(key(self).cmp(&key(other)))
+    }
+
+    /// Multiplies by 2^[n], exactly while the result stays normal. Tiny
+    /// results are rounded to nearest and huge ones become infinity.
+    pub fn scale(&self, n:i64) -> F80 {
+        match self.operand() {
+            Class::Normal => {
+                let (e, m) = self.unpack();
+                let e = (e as i64 + n).clamp(-100_000, 100_000) as i32;
+                pack(self.negative(), e, (m as u128) << 64, 0, 3).0
+            },
+            _ => *self,
+        }
+    }
+
+    /// Rounds to the precision control [pc] under the rounding control [rc]
+    /// of the control word. The arithmetic below returns the result along
+    /// with the exception flags it raises, and NaN operands pass through.
+    pub fn round(&self, rc:u8, pc:u8) -> (F80, u16) {
+        match self.operand() {
+            Class::Normal => {
+                let (e, m) = self.unpack();
+                pack(self.negative(), e, (m as u128) << 64, rc, pc)
+            },
+            _ => (*self, 0),
+        }
+    }
+
+    pub fn add(&self, other:&F80, rc:u8, pc:u8) -> (F80, u16) {
+        let (a, b) = (*self, *other);
+        match (a.operand(), b.operand()) {
+            (Class::NaN, _) => return (a, 0),
+            (_, Class::NaN) => return (b, 0),
+            (Class::Infinity, Class::Infinity) if a.negative() != b.negative() => {
+                return (F80::INDEFINITE, FPU_EXC_INVALID);
+            },
+            (Class::Infinity, _) => return (a, 0),
+            (_, Class::Infinity) => return (b, 0),
+            // Zeros of opposite sign add to +0, or -0 when rounding down.
+            (Class::Zero, Class::Zero) if a.negative() != b.negative() => {
+                return (F80::signed_zero(rc & 0x03 == 1), 0);
+            },
+            (Class::Zero, Class::Zero) => return (a, 0),
+            (Class::Zero, _) => return b.round(rc, pc),
+            (_, Class::Zero) => return a.round(rc, pc),
+            _ => {},
+        }
+        let (ea, ma) = a.unpack();
+        let (eb, mb) = b.unpack();
+        let ((ex, mx, neg), (ey, my)) = if (ea, ma) >= (eb, mb) {
+            ((ea, ma, a.negative()), (eb, mb))
+        }
+        else {
+            ((eb, mb, b.negative()), (ea, ma))
+        };
+        // Two bits of headroom for the carry, and 62 guard bits below.
+        let x = (mx as u128) << 62;
+        let y = shift_right_jam((my as u128) << 62, (ex - ey) as u32);
+        let r = if a.negative() == b.negative() { x + y } else { x - y };
+        if r == 0 { return (F80::signed_zero(rc & 0x03 == 1), 0); }
+        let lz = r.leading_zeros() as i32;
+        pack(neg, ex + 2 - lz, r << lz, rc, pc)
+    }
+
+    pub fn sub(&self, other:&F80, rc:u8, pc:u8) -> (F80, u16) {
+        if other.is_nan() { return (*other, 0); }
+        self.add(&-*other, rc, pc)
+    }
+
+    pub fn mul(&self, other:&F80, rc:u8, pc:u8) -> (F80, u16) {
+        let neg = self.negative() != other.negative();
+        match (self.operand(), other.operand()) {
+            (Class::NaN, _) => return (*self, 0),
+            (_, Class::NaN) => return (*other, 0),
+            (Class::Infinity, Class::Zero) | (Class::Zero, Class::Infinity) => {
+                return (F80::INDEFINITE, FPU_EXC_INVALID);
+            },
+            (Class::Infinity, _) | (_, Class::Infinity) => {
+                return (F80::INFINITY.with_sign(neg), 0);
+            },
+            (Class::Zero, _) | (_, Class::Zero) => return (F80::signed_zero(neg), 0),
+            _ => {},
+        }
+        let (ea, ma) = self.unpack();
+        let (eb, mb) = other.unpack();
+        let p = ma as u128 * mb as u128;
+        let lz = p.leading_zeros() as i32;
+        pack(neg, ea + eb + 1 - lz, p << lz, rc, pc)
+    }
+
+    pub fn div(&self, other:&F80, rc:u8, pc:u8) -> (F80, u16) {
+        let neg = self.negative() != other.negative();
+        match (self.operand(), other.operand()) {
+            (Class::NaN, _) => return (*self, 0),
+            (_, Class::NaN) => return (*other, 0),
+            (Class::Infinity, Class::Infinity) | (Class::Zero, Class::Zero) => {
+                return (F80::INDEFINITE, FPU_EXC_INVALID);
+            },
+            (Class::Infinity, _) => return (F80::INFINITY.with_sign(neg), 0),
+            (_, Class::Infinity) => return (F80::signed_zero(neg), 0),
+            (_, Class::Zero) => return (F80::INFINITY.with_sign(neg), FPU_EXC_ZERO_DIVIDE),
+            (Class::Zero, _) => return (F80::signed_zero(neg), 0),
+            _ => {},
+        }
+        let (ea, ma) = self.unpack();
+        let (eb, mb) = other.unpack();
+        // A 128-bit quotient in two steps, with the remainder as sticky bit.
+        let (n, d) = ((ma as u128) << 63, mb as u128);
+        let (hi, r) = (n / d, n % d);
+        let (lo, r) = ((r << 64) / d, (r << 64) % d);
+        let q = hi << 64 | lo;
+        let lz = q.leading_zeros() as i32;
+        pack(neg, ea - eb - lz, q << lz | (r != 0) as u128, rc, pc)
+    }
+
+    pub fn sqrt(&self, rc:u8, pc:u8) -> (F80, u16) {
+        match self.operand() {
+            Class::NaN | Class::Zero => return (*self, 0),
+            _ if self.negative() => return (F80::INDEFINITE, FPU_EXC_INVALID),
+            Class::Infinity => return (*self, 0),
+            _ => {},
+        }
+        let (e, m) = self.unpack();
+        // Shifted so that the exponent left over is even.
+        let k = if e & 1 == 0 { 63 } else { 64 };
+        let s = (m as u128) << k;
+        let root = s.isqrt();
+        let rem = s - root * root;
+        // The root is never halfway, so the guard bit is set exactly when
+        // the remainder exceeds the root.
+        let sig = root << 64 | ((rem > root) as u128) << 63 | ((rem != 0) as u128) << 62;
+        pack(false, 63 + (e - 63 - k) / 2, sig, rc, pc)
+    }
+
+    fn signed_zero(neg:bool) -> F80 {
+        F80::ZERO.with_sign(neg)
+    }
+
+    fn with_sign(self, neg:bool) -> F80 {
+        F80 { se:(self.se & 0x7FFF) | if neg { 0x8000 } else { 0 }, ..self }
+    }
+
+    // Class as an arithmetic operand: denormals and unnormals are taken by
+    // their value, and an unnormal without significand bits is a zero.
+    fn operand(&self) -> Class {
+        match self.class() {
+            Class::Unnormal if self.mantissa == 0 => Class::Zero,
+            Class::Denormal | Class::Unnormal => Class::Normal,
+            c => c,
+        }
+    }
+
+    // Unbiased exponent and normalized significand of a finite nonzero
+    // value, which is m * 2^(e - 63).
+    fn unpack(&self) -> (i32, u64) {
+        let lz = self.mantissa.leading_zeros();
+        (self.exponent().max(1) as i32 - BIAS - lz as i32, self.mantissa << lz)
+    }
+
+    pub fn from_bytes(b:&[u8]) -> F80 {
+        let mut m = [0u8; 8];
+        m.copy_from_slice(&b[0..8]);
+        F80 {
+            se:u16::from_le_bytes([b[8], b[9]]),
+            mantissa:u64::from_le_bytes(m),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 10] {
+        let mut b = [0u8; 10];
+        b[0..8].copy_from_slice(&self.mantissa.to_le_bytes());
+        b[8..10].copy_from_slice(&self.se.to_le_bytes());
+        b
+    }
+
+    /// Loads an 18-digit packed BCD integer with the sign in bit 7 of the
+    /// last byte.
+    pub fn from_bcd(b:&[u8]) -> F80 {
+        let v = b[0..9].iter().rev()
+            .fold(0i64, |v, d| v * 100 + (d >> 4) as i64 * 10 + (d & 0x0F) as i64);
+        let v = F80::from_i64(v);
+        if b[9] & 0x80 != 0 { -v } else { v }
+    }
+}
+
+/// Packs the integer [v] as 18 BCD digits, or None if it has more.
+pub fn to_bcd(v:i128) -> Option<[u8; 10]> {
+    let mut m = v.unsigned_abs();
+    if m >= 1_000_000_000_000_000_000 { return None; }
+    let mut b = [0u8; 10];
+    for byte in b.iter_mut().take(9) {
+        *byte = (m % 10) as u8 | ((m / 10 % 10) as u8) << 4;
+        m /= 100;
+    }
+    if v < 0 { b[9] = 0x80; }
+    Some(b)
+}
+
+// Rounds sig * 2^(e - 127), with bit 127 of [sig] set, to the precision
+// [pc] under [rc]. Tiny results are denormalized first, and flag underflow
+// if that loses bits. Returns the value and the exceptions it raises.
+fn pack(neg:bool, e:i32, sig:u128, rc:u8, pc:u8) -> (F80, u16) {
+    let p = match pc & 0x03 { 0 => 24, 2 => 53, _ => 64 };
+    let sign = if neg { 0x8000 } else { 0 };
+    let biased = e + BIAS;
+    let tiny = biased < 1;
+    // Bits kept, fewer than [p] for denormals.
+    let shift = 128 - (p - (1 - biased).max(0));
+    let (kept, half, rest) = match shift {
+        129.. => (0, false, sig != 0),
+        128 => (0, sig >> 127 != 0, sig << 1 != 0),
+        _ => {
+            let s = shift as u32;
+            (sig >> s, (sig >> (s - 1)) & 1 != 0, sig & ((1 << (s - 1)) - 1) != 0)
+        },
+    };
+    let inexact = half || rest;
+    let up = match rc & 0x03 {
+        0 => half && (rest || kept & 1 == 1),
+        1 => inexact && neg,
+        2 => inexact && !neg,
+        _ => false,
+    };
+    let kept = kept + up as u128;
+    let precision = if inexact { FPU_EXC_PRECISION } else { 0 };
+
+    if tiny {
+        // Rounding up may carry into the integer bit and give the smallest
+        // normal.
+        let m = (kept << (64 - p)) as u64;
+        let underflow = if inexact { FPU_EXC_UNDERFLOW } else { 0 };
+        return (F80 { se:sign | (m >> 63) as u16, mantissa:m }, underflow | precision);
+    }
+    let (kept, biased) = if kept >> p != 0 { (kept >> 1, biased + 1) } else { (kept, biased) };
+    if biased >= 0x7FFF {
+        // Infinity, or the largest finite value when rounding toward zero.
+        let inf = match rc & 0x03 { 0 => true, 1 => neg, 2 => !neg, _ => false };
+        let v = if inf {
+            F80::INFINITY.with_sign(neg)
+        }
+        else {
+            F80 { se:sign | 0x7FFE, mantissa:!0 << (64 - p) }
+        };
+        return (v, FPU_EXC_OVERFLOW | FPU_EXC_PRECISION);
+    }
+    (F80 { se:sign | biased as u16, mantissa:(kept << (64 - p)) as u64 }, precision)
+}
+
+fn shift_right_jam(v:u128, n:u32) -> u128 {
+    if n >= 128 { return (v != 0) as u128; }
+    v >> n | (v & ((1 << n) - 1) != 0) as u128
+}
+
+// Multiplies by 2^e in steps, so intermediate powers of two do not leave
+// the range of f64.
+fn scale(v:f64, e:i32) -> f64 {
+    let (mut v, mut e) = (v, e);
+    while e > 1000 {
+        v *= 2f64.powi(1000);
+        e -= 1000;
+    }
+    while e < -1000 {
+        v *= 2f64.powi(-1000);
+        e += 1000;
+    }
+    v * 2f64.powi(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        for v in [0.0, -0.0, 1.0, -2.5, 1e300, 5e-324, f64::MAX, f64::INFINITY] {
+            assert_eq!(F80::from_f64(v).to_f64().to_bits(), v.to_bits());
+        }
+        assert_eq!(F80::from_f64(1.0), F80::ONE);
+        assert_eq!(F80::from_i64(1), F80::ONE);
+        assert_eq!(F80::from_i64(-3).to_f64(), -3.0);
+        assert!((F80::PI.to_f64() - std::f64::consts::PI).abs() < 1e-15);
+        assert!((F80::LN_2.to_f64() - std::f64::consts::LN_2).abs() < 1e-16);
+        // Beyond the range of f64, but held exactly.
+        let big = F80 { se:0x7000, mantissa:0x8000_0000_0000_0001 };
+        assert_eq!(F80::from_bytes(&big.to_bytes()), big);
+        assert_eq!(big.to_f64(), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_round_int() {
+        let r = |v:f64, rc| F80::from_f64(v).round_int(rc);
+        assert_eq!(r(2.5, 0), Some((2, true)));
+        assert_eq!(r(3.5, 0), Some((4, true)));
+        assert_eq!(r(-2.5, 1), Some((-3, true)));
+        assert_eq!(r(2.1, 2), Some((3, true)));
+        assert_eq!(r(-2.9, 3), Some((-2, true)));
+        assert_eq!(r(0.25, 0), Some((0, true)));
+        assert_eq!(r(-7.0, 0), Some((-7, false)));
+        assert_eq!(F80::from_i64(i64::MIN).round_int(0), Some((i64::MIN as i128, false)));
+        assert_eq!(r(f64::NAN, 0), None);
+    }
+
+    #[test]
+    fn test_arith() {
+        let third = |rc, pc| F80::ONE.div(&F80::from_i64(3), rc, pc);
+        assert_eq!(third(0, 3), (F80 { se:0x3FFD, mantissa:0xAAAA_AAAA_AAAA_AAAB },
+                                 FPU_EXC_PRECISION));
+        assert_eq!(third(3, 3).0.mantissa, 0xAAAA_AAAA_AAAA_AAAA);
+        assert_eq!(third(0, 2).0.to_f64(), 1.0 / 3.0);
+        assert_eq!(third(0, 0).0.to_f64(), (1.0f32 / 3.0) as f64);
+
+        // Beyond the range of f64, without overflow.
+        let big = F80::from_f64(1e300);
+        let (sq, e) = big.mul(&big, 0, 3);
+        assert_eq!((sq.to_f64(), e & FPU_EXC_OVERFLOW), (f64::INFINITY, 0));
+        assert_eq!(sq.div(&big, 0, 3).0.to_f64(), 1e300);
+        // Overflow at the limit of the extended format.
+        let max = F80 { se:0x7FFE, mantissa:!0 };
+        let two = F80::from_i64(2);
+        assert_eq!(max.mul(&two, 0, 3), (F80::INFINITY, FPU_EXC_OVERFLOW | FPU_EXC_PRECISION));
+        assert_eq!(max.mul(&two, 3, 3).0, max);
+
+        // A half ulp ties to even, or rounds up under RC up.
+        let tiny = F80 { se:0x3FBF, mantissa:1 << 63 };
+        assert_eq!(F80::ONE.add(&tiny, 0, 3), (F80::ONE, FPU_EXC_PRECISION));
+        assert_eq!(F80::ONE.add(&tiny, 2, 3).0.mantissa, 1 << 63 | 1);
+        assert_eq!(F80::ONE.sub(&F80::ONE, 0, 3).0, F80::ZERO);
+        assert_eq!(F80::ONE.sub(&F80::ONE, 1, 3).0, -F80::ZERO);
+        assert_eq!(F80::ONE.add(&-tiny, 0, 3).0.mantissa, !0);
+
+        assert_eq!(two.sqrt(0, 3), (F80 { se:0x3FFF, mantissa:0xB504_F333_F9DE_6484 },
+                                    FPU_EXC_PRECISION));
+        assert_eq!(F80::from_i64(4).sqrt(0, 3), (two, 0));
+        assert_eq!((-two).sqrt(0, 3), (F80::INDEFINITE, FPU_EXC_INVALID));
+
+        // Tiny results are denormalized.
+        let min = F80 { se:0x0001, mantissa:1 << 63 };
+        assert_eq!(min.div(&two, 0, 3), (F80 { se:0, mantissa:1 << 62 }, 0));
+        let denormal = F80 { se:0, mantissa:1 };
+        assert_eq!(denormal.div(&two, 0, 3),
+                   (F80::ZERO, FPU_EXC_UNDERFLOW | FPU_EXC_PRECISION));
+        assert_eq!(denormal.mul(&two, 0, 3), (F80 { se:0, mantissa:2 }, 0));
+    }
+
+    #[test]
+    fn test_bcd() {
+        let b = to_bcd(-1234567890123456789 / 10).unwrap();
+        assert_eq!(b, [0x78, 0x56, 0x34, 0x12, 0x90, 0x78, 0x56, 0x34, 0x12, 0x80]);
+        assert_eq!(F80::from_bcd(&b).round_int(0), Some((-123456789012345678, false)));
+        assert_eq!(to_bcd(1_000_000_000_000_000_000), None);
+    }
+}
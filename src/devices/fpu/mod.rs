@@ -0,0 +1,919 @@
+pub mod f80;
+
+use std::cmp::Ordering;
+use crate::core::bus::BusInterface;
+use f80::{Class, F80, to_bcd};
+
+/// Exception flags in the status word, masked by the bits at the same
+/// positions in the control word.
+pub const FPU_EXC_INVALID:u16               = 0b0000_0000_0000_0001;
+pub const FPU_EXC_DENORMAL:u16              = 0b0000_0000_0000_0010;
+pub const FPU_EXC_ZERO_DIVIDE:u16           = 0b0000_0000_0000_0100;
+pub const FPU_EXC_OVERFLOW:u16              = 0b0000_0000_0000_1000;
+pub const FPU_EXC_UNDERFLOW:u16             = 0b0000_0000_0001_0000;
+pub const FPU_EXC_PRECISION:u16             = 0b0000_0000_0010_0000;
+pub const FPU_EXC_MASK:u16                  = 0b0000_0000_0011_1111;
+
+/// Control word
+/// ------------------------------------------------------
+/// Interrupt enable mask. Set by FDISI and cleared by FENI.
+pub const FPU_CW_IEM:u16                    = 0b0000_0000_1000_0000;
+/// Precision control: the arithmetic and FSQRT round to 24, 53 or 64 bits
+/// for 0, 2 and 3. The reserved setting 1 is taken as 64 bits.
+pub const FPU_CW_PC:u16                     = 0b0000_0011_0000_0000;
+/// Rounding control: nearest, down, up or chop.
+pub const FPU_CW_RC:u16                     = 0b0000_1100_0000_0000;
+/// Infinity control, projective when clear.
+pub const FPU_CW_IC:u16                     = 0b0001_0000_0000_0000;
+/// All exceptions masked and interrupts disabled, as set by FINIT.
+pub const FPU_CW_DEFAULT:u16                = 0x03FF;
+
+/// Status word
+/// ------------------------------------------------------
+/// Interrupt request, set along with any unmasked exception flag.
+pub const FPU_SW_IR:u16                     = 0b0000_0000_1000_0000;
+pub const FPU_SW_C0:u16                     = 0b0000_0001_0000_0000;
+pub const FPU_SW_C1:u16                     = 0b0000_0010_0000_0000;
+pub const FPU_SW_C2:u16                     = 0b0000_0100_0000_0000;
+/// Physical register at the top of the stack.
+pub const FPU_SW_TOP:u16                    = 0b0011_1000_0000_0000;
+pub const FPU_SW_C3:u16                     = 0b0100_0000_0000_0000;
+pub const FPU_SW_BUSY:u16                   = 0b1000_0000_0000_0000;
+pub const FPU_SW_CC:u16 = FPU_SW_C0 | FPU_SW_C1 | FPU_SW_C2 | FPU_SW_C3;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tag {
+    Valid,
+    Zero,
+    Special,
+    Empty,
+}
+
+impl Tag {
+    fn of(v:&F80) -> Tag {
+        match v.class() {
+            Class::Normal => Tag::Valid,
+            Class::Zero => Tag::Zero,
+            _ => Tag::Special,
+        }
+    }
+
+    fn from_bits(b:u16) -> Tag {
+        match b & 0x03 {
+            0 => Tag::Valid,
+            1 => Tag::Zero,
+            2 => Tag::Special,
+            _ => Tag::Empty,
+        }
+    }
+}
+
+/// Memory operand formats.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    Real32,
+    Real64,
+    Real80,
+    Int16,
+    Int32,
+    Int64,
+}
+
+/// 8087 numeric data processor. It sits on the local bus next to the 8088
+/// and executes the ESC instructions the CPU passes to it, reading and
+/// writing memory operands itself. Each instruction completes immediately,
+/// but BUSY, which drives the 8088 TEST pin, is held for the time the 8087
+/// would take, so WAIT stalls for it as on the real machine.
+///
+/// On the 5150, the INT output of the 8087 is wired to NMI.
+#[derive(Clone, Debug)]
+pub struct I8087 {
+    regs:[F80; 8], /* physical registers, ST(i) is regs[(top + i) % 8] */
+    tags:[Tag; 8],
+    top:u8,
+    cw:u16,
+    sw:u16, /* without TOP, which is kept in [top] */
+
+    /* Last non-control instruction, for exception handlers */
+    ip:u32,
+    opcode:u16, /* low 3 bits of the ESC opcode, then the ModR/M byte */
+    op:u32,
+
+    busy:u32, /* clocks until BUSY goes inactive */
+}
+
+impl Default for I8087 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl I8087 {
+    pub fn new() -> Self {
+        let mut fpu = Self {
+            regs:[F80::ZERO; 8],
+            tags:[Tag::Empty; 8],
+            top:0,
+            cw:0,
+            sw:0,
+            ip:0,
+            opcode:0,
+            op:0,
+            busy:0,
+        };
+        fpu.init();
+        fpu
+    }
+
+    /// FINIT, also the state after a hardware reset.
+    pub fn init(&mut self) {
+        self.cw = FPU_CW_DEFAULT;
+        self.sw = 0;
+        self.top = 0;
+        self.tags = [Tag::Empty; 8];
+        self.ip = 0;
+        self.opcode = 0;
+        self.op = 0;
+    }
+
+    pub fn control_word(&self) -> u16 {
+        self.cw
+    }
+
+    pub fn status_word(&self) -> u16 {
+        let busy = if self.busy > 0 { FPU_SW_BUSY } else { 0 };
+        self.sw | (self.top as u16) << 11 | busy
+    }
+
+    pub fn tag_word(&self) -> u16 {
+        self.tags.iter().enumerate().fold(0, |w, (i, t)| {
+            w | (*t as u16) << (2 * i)
+        })
+    }
+
+    /// ST([i]), or None if it is empty.
+    pub fn st(&self, i:u8) -> Option<F80> {
+        let p = self.physical(i);
+        (self.tags[p] != Tag::Empty).then_some(self.regs[p])
+    }
+
+    /// Level of the INT output: an unmasked exception is pending and
+    /// interrupts are enabled.
+    pub fn interrupt(&self) -> bool {
+        self.sw & FPU_SW_IR != 0 && self.cw & FPU_CW_IEM == 0
+    }
+
+    /// Level of the BUSY output.
+    pub fn busy(&self) -> bool {
+        self.busy > 0
+    }
+
+    pub fn busy_clocks(&self) -> u32 {
+        self.busy
+    }
+
+    /// Lets [clocks] pass on the 8087.
+    pub fn run(&mut self, clocks:u32) {
+        self.busy = self.busy.saturating_sub(clocks);
+    }
+
+    /// Executes an ESC instruction. [opcode] holds the low 3 bits of the
+    /// ESC opcode above the ModR/M byte, [ip] is the physical address of
+    /// the instruction and [mem] that of its memory operand, if any.
+    pub fn execute(&mut self, opcode:u16, ip:u32, mem:Option<u32>, bus:&mut BusInterface) {
+        let esc = (opcode >> 8) as u8 & 0x07;
+        let modrm = opcode as u8;
+        let reg = (modrm >> 3) & 0x07;
+
+        // Control instructions leave the exception pointers alone, so that
+        // a handler can save them.
+        let control = match mem {
+            Some(_) => matches!((esc, reg), (1, 4..=7) | (5, 4 | 6 | 7)),
+            None => esc == 3 && reg == 4,
+        };
+        if !control {
+            self.ip = ip;
+            self.opcode = opcode & 0x07FF;
+            if let Some(addr) = mem { self.op = addr; }
+        }
+
+        self.busy = match mem {
+            Some(addr) => self.execute_memory(esc, reg, addr & 0xFFFFF, bus),
+            None => self.execute_register(esc, reg, modrm & 0x07),
+        };
+    }
+
+    // Returns the clocks taken by the instruction.
+    fn execute_memory(&mut self, esc:u8, reg:u8, addr:u32, bus:&mut BusInterface) -> u32 {
+        match (esc, reg) {
+            (0 | 2 | 4 | 6, _) => {
+                let fmt = [Format::Real32, Format::Int32, Format::Real64, Format::Int16];
+                let v = load(bus, addr, fmt[esc as usize / 2]);
+                self.arith_st0(reg, v);
+                [90, 125, 95, 120][esc as usize / 2] + arith_clocks(reg)
+            },
+            (1, 0) => { self.push_value(load(bus, addr, Format::Real32)); 43 },
+            (5, 0) => { self.push_value(load(bus, addr, Format::Real64)); 46 },
+            (3, 5) => { self.push_value(load(bus, addr, Format::Real80)); 57 },
+            (3, 0) => { self.push_value(load(bus, addr, Format::Int32)); 56 },
+            (7, 0) => { self.push_value(load(bus, addr, Format::Int16)); 50 },
+            (7, 5) => { self.push_value(load(bus, addr, Format::Int64)); 64 },
+            (7, 4) => {
+                self.push_value(F80::from_bcd(&read(bus, addr, 10)));
+                300
+            },
+
+            (1, 2 | 3) => { self.store(bus, addr, Format::Real32, reg == 3); 87 },
+            (5, 2 | 3) => { self.store(bus, addr, Format::Real64, reg == 3); 100 },
+            (3, 7) => { self.store(bus, addr, Format::Real80, true); 55 },
+            (3, 2 | 3) => { self.store(bus, addr, Format::Int32, reg == 3); 88 },
+            (7, 2 | 3) => { self.store(bus, addr, Format::Int16, reg == 3); 86 },
+            (7, 7) => { self.store(bus, addr, Format::Int64, true); 100 },
+            (7, 6) => { self.store_bcd(bus, addr); 530 },
+
+            (1, 4) => { self.load_env(bus, addr); 40 },
+            (1, 5) => {
+                self.cw = u16::from_le_bytes([read_8(bus, addr), read_8(bus, addr + 1)]);
+                self.update_interrupt();
+                10
+            },
+            (1, 6) => {
+                self.store_env(bus, addr);
+                // Handlers run with exceptions masked until they reload
+                // the environment.
+                self.cw |= FPU_EXC_MASK;
+                45
+            },
+            (1, 7) => { write(bus, addr, &self.cw.to_le_bytes()); 15 },
+            (5, 7) => { write(bus, addr, &self.status_word().to_le_bytes()); 15 },
+            (5, 4) => {
+                self.load_env(bus, addr);
+                for i in 0..8 {
+                    let v = F80::from_bytes(&read(bus, addr + 14 + 10 * i as u32, 10));
+                    self.regs[self.physical(i)] = v;
+                }
+                205
+            },
+            (5, 6) => {
+                self.store_env(bus, addr);
+                for i in 0..8 {
+                    let v = self.regs[self.physical(i)];
+                    write(bus, addr + 14 + 10 * i as u32, &v.to_bytes());
+                }
+                self.init();
+                205
+            },
+            // (3, 1|4|6) and (5, 1|5) are reserved.
+            _ => 13,
+        }
+    }
+
+    fn execute_register(&mut self, esc:u8, reg:u8, i:u8) -> u32 {
+        match (esc, reg) {
+            (0 | 4, 2 | 3) => {
+                self.compare_st(i, reg - 2);
+                45
+            },
+            // DE D9 is FCOMPP, DE D0+i an alias of FCOMP.
+            (6, 2 | 3) => {
+                self.compare_st(i, if (reg, i) == (3, 1) { 2 } else { 1 });
+                45
+            },
+            (0, _) => {
+                let Some(b) = self.get(i) else { return 85 };
+                self.arith_st0(reg, b);
+                arith_clocks(reg) + 85
+            },
+            // DC and DE store the result to ST(i). Intel's subtract and
+            // divide mnemonics are reversed for these forms, so /4 is FSUBR.
+            (4 | 6, _) => {
+                let (Some(st0), Some(sti)) = (self.get(0), self.get(i)) else { return 85 };
+                let op = if reg >= 4 { reg ^ 1 } else { reg };
+                if let Some(r) = self.arith(op, sti, st0) {
+                    self.set(i, r);
+                    if esc == 6 { self.pop(); }
+                }
+                arith_clocks(reg) + 90
+            },
+            (1, 0) => {
+                if let Some(v) = self.get(i) { self.push_value(v); }
+                20
+            },
+            (1 | 5 | 7, 1) => {
+                let (a, b) = (self.get(0), self.get(i));
+                if let (Some(a), Some(b)) = (a, b) {
+                    self.set(0, b);
+                    self.set(i, a);
+                }
+                12
+            },
+            (5, 2 | 3) | (1 | 7, 3) => {
+                if let Some(v) = self.get(0) { self.set(i, v); }
+                if reg == 3 { self.pop(); }
+                18
+            },
+            (5 | 7, 0) => {
+                let p = self.physical(i);
+                self.tags[p] = Tag::Empty;
+                if esc == 7 { self.pop(); }
+                11
+            },
+
+            (1, 4) => self.execute_d9_e0(i),
+            (1, 5) => {
+                let c = [F80::ONE, F80::LOG2_10, F80::LOG2_E, F80::PI, F80::LOG10_2,
+                         F80::LN_2, F80::ZERO];
+                match c.get(i as usize) {
+                    Some(v) => { self.push_value(*v); 20 },
+                    None => 13,
+                }
+            },
+            (1, 6) => self.execute_d9_f0(i),
+            (1, 7) => self.execute_d9_f8(i),
+
+            (3, 4) => {
+                match i {
+                    0 => self.cw &= !FPU_CW_IEM,
+                    1 => self.cw |= FPU_CW_IEM,
+                    2 => self.sw &= !(FPU_EXC_MASK | FPU_SW_IR),
+                    3 => self.init(),
+                    _ => {},
+                }
+                5
+            },
+            // D9 D0 is FNOP. The rest of the register forms are reserved
+            // and treated alike.
+            _ => 13,
+        }
+    }
+
+    // FCHS, FABS, FTST and FXAM.
+    fn execute_d9_e0(&mut self, i:u8) -> u32 {
+        match i {
+            0 | 1 => {
+                if let Some(v) = self.get(0) {
+                    self.set(0, if i == 0 { -v } else { v.abs() });
+                }
+                15
+            },
+            4 => {
+                if let Some(v) = self.get(0) { self.compare(v, F80::ZERO); }
+                42
+            },
+            5 => {
+                let p = self.physical(0);
+                let v = self.regs[p];
+                let cc = match (self.tags[p], v.class()) {
+                    (Tag::Empty, _) => FPU_SW_C3 | FPU_SW_C0,
+                    (_, Class::Unnormal) => 0,
+                    (_, Class::NaN) => FPU_SW_C0,
+                    (_, Class::Normal) => FPU_SW_C2,
+                    (_, Class::Infinity) => FPU_SW_C2 | FPU_SW_C0,
+                    (_, Class::Zero) => FPU_SW_C3,
+                    (_, Class::Denormal) => FPU_SW_C3 | FPU_SW_C2,
+                };
+                let sign = if v.negative() { FPU_SW_C1 } else { 0 };
+                self.sw = (self.sw & !FPU_SW_CC) | cc | sign;
+                17
+            },
+            _ => 13,
+        }
+    }
+
+    // F2XM1, FYL2X, FPTAN, FPATAN, FXTRACT, FDECSTP and FINCSTP.
+    fn execute_d9_f0(&mut self, i:u8) -> u32 {
+        match i {
+            0 => {
+                if let Some(v) = self.get(0) {
+                    let x = v.to_f64();
+                    self.set(0, F80::from_f64((x * std::f64::consts::LN_2).exp_m1()));
+                }
+                500
+            },
+            1 | 3 => {
+                let (Some(st0), Some(st1)) = (self.get(0), self.get(1)) else { return 900 };
+                let (x, y) = (st0.to_f64(), st1.to_f64());
+                let r = if i == 1 {
+                    if x < 0.0 { None } else { Some(y * x.log2()) }
+                } else {
+                    Some(y.atan2(x))
+                };
+                match r {
+                    Some(r) => self.set(1, F80::from_f64(r)),
+                    None if !self.exception(FPU_EXC_INVALID) => return 900,
+                    None => self.set(1, F80::INDEFINITE),
+                }
+                self.pop();
+                if i == 1 { 950 } else { 650 }
+            },
+            2 => {
+                if let Some(v) = self.get(0) {
+                    self.set(0, F80::from_f64(v.to_f64().tan()));
+                    self.push_value(F80::ONE);
+                }
+                450
+            },
+            4 => {
+                let Some(v) = self.get(0) else { return 50 };
+                match v.class() {
+                    Class::Normal => {
+                        let e = v.exponent() as i64 - 16383;
+                        self.set(0, F80::from_i64(e));
+                        self.push_value(F80 { se:(v.se & 0x8000) | 0x3FFF, ..v });
+                    },
+                    Class::Zero => {
+                        if self.exception(FPU_EXC_ZERO_DIVIDE) {
+                            self.set(0, F80::from_f64(f64::NEG_INFINITY));
+                            self.push_value(v);
+                        }
+                    },
+                    _ => {
+                        let v = F80::from_f64(v.to_f64());
+                        let e = v.exponent() as i64 - 16383;
+                        self.set(0, F80::from_i64(e));
+                        self.push_value(F80 { se:(v.se & 0x8000) | 0x3FFF, ..v });
+                    },
+                }
+                50
+            },
+            6 => { self.top = self.top.wrapping_sub(1) & 0x07; 9 },
+            7 => { self.top = (self.top + 1) & 0x07; 9 },
+            _ => 13,
+        }
+    }
+
+    // FPREM, FYL2XP1, FSQRT, FRNDINT and FSCALE.
+    fn execute_d9_f8(&mut self, i:u8) -> u32 {
+        match i {
+            0 => {
+                let (Some(st0), Some(st1)) = (self.get(0), self.get(1)) else { return 125 };
+                let (x, y) = (st0.to_f64(), st1.to_f64());
+                if y == 0.0 || x.is_infinite() || x.is_nan() || y.is_nan() {
+                    if self.exception(FPU_EXC_INVALID) { self.set(0, F80::INDEFINITE); }
+                    return 125;
+                }
+                // The remainder is always complete, so C2 is clear. The
+                // low quotient bits go to C0, C3 and C1.
+                let r = x % y;
+                let q = ((x - r) / y).abs().round() as u64;
+                let cc = [(4, FPU_SW_C0), (2, FPU_SW_C3), (1, FPU_SW_C1)].iter()
+                    .filter(|(b, _)| q & b != 0)
+                    .fold(0, |cc, (_, f)| cc | f);
+                self.sw = (self.sw & !FPU_SW_CC) | cc;
+                self.set(0, F80::from_f64(r));
+                125
+            },
+            1 => {
+                let (Some(st0), Some(st1)) = (self.get(0), self.get(1)) else { return 850 };
+                let r = st1.to_f64() * st0.to_f64().ln_1p() / std::f64::consts::LN_2;
+                self.set(1, F80::from_f64(r));
+                self.pop();
+                850
+            },
+            2 => {
+                let Some(v) = self.get(0) else { return 183 };
+                let (r, e) = v.sqrt(self.rounding(), self.precision());
+                if let Some(r) = self.result(r, e) { self.set(0, r); }
+                183
+            },
+            4 => {
+                let Some(v) = self.get(0) else { return 45 };
+                if let Some((n, inexact)) = v.round_int(self.rounding()) {
+                    if inexact { self.sw |= FPU_EXC_PRECISION; }
+                    let r = F80::from_i128(n);
+                    // Rounding to zero keeps the sign.
+                    self.set(0, if r.is_zero() && v.negative() { -r } else { r });
+                }
+                45
+            },
+            5 => {
+                let (Some(st0), Some(st1)) = (self.get(0), self.get(1)) else { return 35 };
+                let n = st1.round_int(3).map_or(0, |(n, _)| n.clamp(-1 << 40, 1 << 40));
+                self.set(0, st0.scale(n as i64));
+                35
+            },
+            _ => 13,
+        }
+    }
+
+    fn physical(&self, i:u8) -> usize {
+        ((self.top + i) & 0x07) as usize
+    }
+
+    fn rounding(&self) -> u8 {
+        ((self.cw & FPU_CW_RC) >> 10) as u8
+    }
+
+    fn precision(&self) -> u8 {
+        ((self.cw & FPU_CW_PC) >> 8) as u8
+    }
+
+    /// Flags exceptions [e]. Returns whether all of them are masked, in
+    /// which case the 8087 goes on with a default result. Otherwise it
+    /// requests an interrupt and leaves the operands unchanged.
+    fn exception(&mut self, e:u16) -> bool {
+        self.sw |= e;
+        self.update_interrupt();
+        self.cw & e == e
+    }
+
+    fn update_interrupt(&mut self) {
+        if self.sw & !self.cw & FPU_EXC_MASK != 0 {
+            self.sw |= FPU_SW_IR;
+        }
+    }
+
+    /// Reads ST([i]). An empty register is a stack underflow, which yields
+    /// the indefinite NaN when masked.
+    fn get(&mut self, i:u8) -> Option<F80> {
+        let p = self.physical(i);
+        if self.tags[p] != Tag::Empty {
+            return Some(self.regs[p]);
+        }
+        self.exception(FPU_EXC_INVALID).then_some(F80::INDEFINITE)
+    }
+
+    fn set(&mut self, i:u8, v:F80) {
+        let p = self.physical(i);
+        self.regs[p] = v;
+        self.tags[p] = Tag::of(&v);
+    }
+
+    /// Pushes [v]. Pushing onto a full stack is an overflow, which loads
+    /// the indefinite NaN when masked.
+    fn push_value(&mut self, v:F80) {
+        let p = self.physical(7);
+        if self.tags[p] != Tag::Empty {
+            if !self.exception(FPU_EXC_INVALID) { return; }
+            self.top = p as u8;
+            self.set(0, F80::INDEFINITE);
+            return;
+        }
+        self.top = p as u8;
+        self.set(0, v);
+    }
+
+    fn pop(&mut self) {
+        let p = self.physical(0);
+        self.tags[p] = Tag::Empty;
+        self.top = (self.top + 1) & 0x07;
+    }
+
+    // ST = ST op [v], for the D8 register and all memory arithmetic forms.
+    fn arith_st0(&mut self, reg:u8, v:F80) {
+        let Some(st0) = self.get(0) else { return };
+        match reg {
+            2 | 3 => {
+                if self.compare(st0, v) && reg == 3 { self.pop(); }
+            },
+            _ => if let Some(r) = self.arith(reg, st0, v) {
+                self.set(0, r);
+            },
+        }
+    }
+
+    /// Computes [a] op [b] for the ModR/M reg field [op] of D8: add, mul,
+    /// (compares), sub, subr, div and divr. Returns None if an unmasked
+    /// exception suppresses the result.
+    fn arith(&mut self, op:u8, a:F80, b:F80) -> Option<F80> {
+        if a.is_nan() { return Some(a); }
+        if b.is_nan() { return Some(b); }
+        let (rc, pc) = (self.rounding(), self.precision());
+        let (r, e) = match op {
+            0 => a.add(&b, rc, pc),
+            1 => a.mul(&b, rc, pc),
+            4 => a.sub(&b, rc, pc),
+            5 => b.sub(&a, rc, pc),
+            6 => a.div(&b, rc, pc),
+            _ => b.div(&a, rc, pc),
+        };
+        self.result(r, e)
+    }
+
+    // Flags the exceptions [e] raised along with the result [r]. Precision
+    // alone never suppresses the result, even when unmasked.
+    fn result(&mut self, r:F80, e:u16) -> Option<F80> {
+        if e & FPU_EXC_PRECISION != 0 { self.exception(FPU_EXC_PRECISION); }
+        let e = e & !FPU_EXC_PRECISION;
+        (e == 0 || self.exception(e)).then_some(r)
+    }
+
+    fn compare_st(&mut self, i:u8, pops:u8) {
+        let (Some(a), Some(b)) = (self.get(0), self.get(i)) else { return };
+        if self.compare(a, b) {
+            for _ in 0..pops { self.pop(); }
+        }
+    }
+
+    // Sets C3, C2 and C0 as for FCOM. NaNs compare unordered and are
+    // invalid operands. Returns false if an unmasked exception aborts it.
+    fn compare(&mut self, a:F80, b:F80) -> bool {
+        let cc = match a.compare(&b) {
+            Some(Ordering::Greater) => 0,
+            Some(Ordering::Less) => FPU_SW_C0,
+            Some(Ordering::Equal) => FPU_SW_C3,
+            None => {
+                if !self.exception(FPU_EXC_INVALID) { return false; }
+                FPU_SW_C3 | FPU_SW_C2 | FPU_SW_C0
+            },
+        };
+        self.sw = (self.sw & !(FPU_SW_C3 | FPU_SW_C2 | FPU_SW_C0)) | cc;
+        true
+    }
+
+    fn store(&mut self, bus:&mut BusInterface, addr:u32, fmt:Format, pop:bool) {
+        let Some(v) = self.get(0) else { return };
+        let data = match fmt {
+            Format::Real32 => (v.to_f64() as f32).to_le_bytes().to_vec(),
+            Format::Real64 => v.to_f64().to_le_bytes().to_vec(),
+            Format::Real80 => v.to_bytes().to_vec(),
+            Format::Int16 | Format::Int32 | Format::Int64 => {
+                let bits = match fmt { Format::Int16 => 16, Format::Int32 => 32, _ => 64 };
+                let range = -(1i128 << (bits - 1))..(1i128 << (bits - 1));
+                let n = match v.round_int(self.rounding()) {
+                    Some((n, inexact)) if range.contains(&n) => {
+                        if inexact { self.sw |= FPU_EXC_PRECISION; }
+                        n
+                    },
+                    // The integer indefinite is the most negative value.
+                    _ => {
+                        if !self.exception(FPU_EXC_INVALID) { return; }
+                        range.start
+                    },
+                };
+                n.to_le_bytes()[..bits / 8].to_vec()
+            },
+        };
+        write(bus, addr, &data);
+        if pop { self.pop(); }
+    }
+
+    fn store_bcd(&mut self, bus:&mut BusInterface, addr:u32) {
+        let Some(v) = self.get(0) else { return };
+        let bcd = v.round_int(self.rounding()).and_then(|(n, inexact)| {
+            if inexact { self.sw |= FPU_EXC_PRECISION; }
+            to_bcd(n)
+        });
+        let data = match bcd {
+            Some(b) => b,
+            None => {
+                if !self.exception(FPU_EXC_INVALID) { return; }
+                [0, 0, 0, 0, 0, 0, 0, 0xC0, 0xFF, 0xFF]
+            },
+        };
+        write(bus, addr, &data);
+        self.pop();
+    }
+
+    // The 14-byte real mode environment: control, status and tag words,
+    // then the instruction and operand pointers with the opcode.
+    fn store_env(&mut self, bus:&mut BusInterface, addr:u32) {
+        let words = [
+            self.cw,
+            self.status_word(),
+            self.tag_word(),
+            self.ip as u16,
+            ((self.ip >> 16) << 12) as u16 | self.opcode,
+            self.op as u16,
+            ((self.op >> 16) << 12) as u16,
+        ];
+        let data:Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        write(bus, addr, &data);
+    }
+
+    fn load_env(&mut self, bus:&mut BusInterface, addr:u32) {
+        let b = read(bus, addr, 14);
+        let w = |i:usize| u16::from_le_bytes([b[2 * i], b[2 * i + 1]]);
+        self.cw = w(0);
+        self.sw = w(1) & !(FPU_SW_TOP | FPU_SW_BUSY);
+        self.top = ((w(1) & FPU_SW_TOP) >> 11) as u8;
+        for (i, t) in self.tags.iter_mut().enumerate() {
+            *t = Tag::from_bits(w(2) >> (2 * i));
+        }
+        self.ip = w(3) as u32 | ((w(4) as u32 >> 12) << 16);
+        self.opcode = w(4) & 0x07FF;
+        self.op = w(5) as u32 | ((w(6) as u32 >> 12) << 16);
+        self.update_interrupt();
+    }
+}
+
+// Extra clocks of multiply and divide over add and compare.
+fn arith_clocks(reg:u8) -> u32 {
+    match reg {
+        1 => 45,
+        6 | 7 => 113,
+        _ => 0,
+    }
+}
+
+fn load(bus:&mut BusInterface, addr:u32, fmt:Format) -> F80 {
+    match fmt {
+        Format::Real32 => {
+            let b = read(bus, addr, 4);
+            F80::from_f64(f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+        },
+        Format::Real64 => {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&read(bus, addr, 8));
+            F80::from_f64(f64::from_le_bytes(b))
+        },
+        Format::Real80 => F80::from_bytes(&read(bus, addr, 10)),
+        Format::Int16 => {
+            let b = read(bus, addr, 2);
+            F80::from_i64(i16::from_le_bytes([b[0], b[1]]) as i64)
+        },
+        Format::Int32 => {
+            let b = read(bus, addr, 4);
+            F80::from_i64(i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64)
+        },
+        Format::Int64 => {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&read(bus, addr, 8));
+            F80::from_i64(i64::from_le_bytes(b))
+        },
+    }
+}
+
+// The 8087 addresses its operands linearly, wrapping at 1 MB. Memory
+// outside of RAM reads as 0xFF.
+fn read_8(bus:&mut BusInterface, addr:u32) -> u8 {
+    bus.read_8((addr & 0xFFFFF) as usize).unwrap_or(0xFF)
+}
+
+fn read(bus:&mut BusInterface, addr:u32, len:usize) -> Vec<u8> {
+    (0..len as u32).map(|i| read_8(bus, addr + i)).collect()
+}
+
+fn write(bus:&mut BusInterface, addr:u32, data:&[u8]) {
+    for (i, b) in data.iter().enumerate() {
+        let _ = bus.write_8(((addr + i as u32) & 0xFFFFF) as usize, *b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn real64(bus:&mut BusInterface, addr:u32) -> f64 {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(&read(bus, addr, 8));
+        f64::from_le_bytes(b)
+    }
+
+    // Executes an instruction and lets it complete, as after a WAIT.
+    fn exec(fpu:&mut I8087, bus:&mut BusInterface, op:u16, ip:u32, mem:Option<u32>) {
+        fpu.execute(op, ip, mem, bus);
+        fpu.run(fpu.busy_clocks());
+    }
+
+    #[test]
+    fn test_memory_operands() {
+        let mut bus = BusInterface::new();
+        let mut fpu = I8087::new();
+        bus.load(0x1000, &2.0f64.to_le_bytes()).unwrap();
+        bus.load(0x1008, &3.0f64.to_le_bytes()).unwrap();
+        bus.load(0x1020, &(-5i16).to_le_bytes()).unwrap();
+
+        // fld qword [0x1000]; fadd qword [0x1008]; fstp qword [0x1010]
+        fpu.execute(0x506, 0, Some(0x1000), &mut bus);
+        fpu.execute(0x406, 0, Some(0x1008), &mut bus);
+        assert_eq!(fpu.tag_word(), 0x3FFF);
+        fpu.execute(0x51E, 0, Some(0x1010), &mut bus);
+        assert_eq!(real64(&mut bus, 0x1010), 5.0);
+        assert_eq!(fpu.tag_word(), 0xFFFF);
+
+        // fild word [0x1020]; fistp dword [0x1024]
+        fpu.execute(0x706, 0, Some(0x1020), &mut bus);
+        fpu.execute(0x31E, 0, Some(0x1024), &mut bus);
+        assert_eq!(read(&mut bus, 0x1024, 4), (-5i32).to_le_bytes());
+
+        // fld1; fld qword [0x1000]; fscale
+        fpu.execute(0x1E8, 0, None, &mut bus);
+        fpu.execute(0x506, 0, Some(0x1000), &mut bus);
+        fpu.execute(0x1FD, 0, None, &mut bus);
+        assert_eq!(fpu.st(0).unwrap().to_f64(), 4.0);
+
+        // fld qword [0x1030]; fistp word [0x1038] is out of range, and
+        // stores the integer indefinite.
+        bus.load(0x1030, &100000.0f64.to_le_bytes()).unwrap();
+        fpu.execute(0x506, 0, Some(0x1030), &mut bus);
+        fpu.execute(0x71E, 0, Some(0x1038), &mut bus);
+        assert_eq!(read(&mut bus, 0x1038, 2), [0x00, 0x80]);
+        assert_eq!(fpu.status_word() & FPU_EXC_MASK, FPU_EXC_INVALID);
+
+        // Extended reals are held exactly: fldpi; fstp tword [0x1040]
+        fpu.execute(0x1EB, 0, None, &mut bus);
+        fpu.execute(0x33E, 0, Some(0x1040), &mut bus);
+        assert_eq!(F80::from_bytes(&read(&mut bus, 0x1040, 10)), F80::PI);
+
+        // fbld/fbstp round trip through packed BCD.
+        let bcd = [0x89, 0x67, 0x45, 0x23, 0x01, 0, 0, 0, 0, 0x80];
+        bus.load(0x1050, &bcd).unwrap();
+        fpu.execute(0x726, 0, Some(0x1050), &mut bus);
+        assert_eq!(fpu.st(0).unwrap().to_f64(), -123456789.0);
+        fpu.execute(0x736, 0, Some(0x1060), &mut bus);
+        assert_eq!(read(&mut bus, 0x1060, 10), bcd);
+    }
+
+    #[test]
+    fn test_register_stack() {
+        let mut bus = BusInterface::new();
+        let mut fpu = I8087::new();
+        let mut exec = |fpu:&mut I8087, op| fpu.execute(op, 0, None, &mut bus);
+
+        // fld1; fldpi; fxch st1; fdivp st1 leaves pi / 1.
+        for op in [0x1E8, 0x1EB, 0x1C9, 0x6F9] { exec(&mut fpu, op); }
+        assert_eq!(fpu.st(0).unwrap().to_f64(), std::f64::consts::PI);
+        assert_eq!(fpu.st(1), None);
+        assert_eq!((fpu.status_word() & FPU_SW_TOP) >> 11, 7);
+
+        // fld1; fcom st1: 1 < pi sets C0.
+        exec(&mut fpu, 0x1E8);
+        exec(&mut fpu, 0x0D1);
+        assert_eq!(fpu.status_word() & FPU_SW_CC, FPU_SW_C0);
+        // fchs; fxam: negative normal.
+        exec(&mut fpu, 0x1E0);
+        exec(&mut fpu, 0x1E5);
+        assert_eq!(fpu.status_word() & FPU_SW_CC, FPU_SW_C2 | FPU_SW_C1);
+        // fcompp empties the stack, and fxam then reports empty.
+        exec(&mut fpu, 0x6D9);
+        exec(&mut fpu, 0x1E5);
+        assert_eq!(fpu.status_word() & (FPU_SW_C3 | FPU_SW_C2 | FPU_SW_C0),
+                   FPU_SW_C3 | FPU_SW_C0);
+        assert_eq!(fpu.tag_word(), 0xFFFF);
+
+        // A ninth push overflows the stack and loads the indefinite NaN.
+        for _ in 0..9 { exec(&mut fpu, 0x1EE); }
+        assert_eq!(fpu.st(0), Some(F80::INDEFINITE));
+        assert_eq!(fpu.status_word() & FPU_EXC_INVALID, FPU_EXC_INVALID);
+        assert!(!fpu.interrupt());
+        // fninit
+        exec(&mut fpu, 0x3E3);
+        assert!(fpu.busy());
+        fpu.run(5);
+        assert_eq!((fpu.control_word(), fpu.status_word(), fpu.tag_word()),
+                   (FPU_CW_DEFAULT, 0, 0xFFFF));
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut bus = BusInterface::new();
+        let mut fpu = I8087::new();
+        // Unmask zero divide and enable interrupts: fldcw [0x100]
+        bus.load(0x100, &0x037Bu16.to_le_bytes()).unwrap();
+        exec(&mut fpu, &mut bus, 0x12E, 0xF0000, Some(0x100));
+        // fld1; fldz at F000:0010; fdivp st1
+        exec(&mut fpu, &mut bus, 0x1E8, 0xF000C, None);
+        exec(&mut fpu, &mut bus, 0x1EE, 0xF0010, None);
+        exec(&mut fpu, &mut bus, 0x6F9, 0xF0012, None);
+        assert!(fpu.interrupt());
+        assert_eq!(fpu.status_word() & (FPU_EXC_MASK | FPU_SW_IR),
+                   FPU_EXC_ZERO_DIVIDE | FPU_SW_IR);
+        // The operands are left alone.
+        assert_eq!(fpu.st(1), Some(F80::ONE));
+
+        // fnstenv [0x200] records the faulting instruction, then masks all
+        // exceptions.
+        exec(&mut fpu, &mut bus, 0x136, 0xF0020, Some(0x200));
+        let env:Vec<u16> = read(&mut bus, 0x200, 14).chunks(2)
+            .map(|w| u16::from_le_bytes([w[0], w[1]])).collect();
+        assert_eq!(env, [0x037B, 0x3084, 0x1FFF, 0x0012, 0xF6F9, 0x0000, 0x0000]);
+        assert_eq!(fpu.control_word(), 0x037F);
+        // fnclex
+        exec(&mut fpu, &mut bus, 0x3E2, 0, None);
+        assert!(!fpu.interrupt());
+
+        // fsave [0x300]; frstor [0x300] brings it all back.
+        let before = (fpu.status_word(), fpu.tag_word(), fpu.st(0), fpu.st(1));
+        exec(&mut fpu, &mut bus, 0x536, 0, Some(0x300));
+        assert_eq!(fpu.tag_word(), 0xFFFF);
+        exec(&mut fpu, &mut bus, 0x526, 0, Some(0x300));
+        assert_eq!((fpu.status_word(), fpu.tag_word(), fpu.st(0), fpu.st(1)), before);
+    }
+
+    #[test]
+    fn test_precision() {
+        let mut bus = BusInterface::new();
+        let mut fpu = I8087::new();
+        bus.load(0x1000, &1e300f64.to_le_bytes()).unwrap();
+        bus.load(0x1008, &3i16.to_le_bytes()).unwrap();
+
+        // fld qword [0x1000]; fmul qword [0x1000] is past the range of f64
+        // but not of the 8087. fdiv qword [0x1000] brings it back.
+        exec(&mut fpu, &mut bus, 0x506, 0, Some(0x1000));
+        exec(&mut fpu, &mut bus, 0x40E, 0, Some(0x1000));
+        assert_eq!(fpu.status_word() & FPU_EXC_OVERFLOW, 0);
+        assert_eq!(fpu.st(0).unwrap().exponent(), 0x3FFF + 1993);
+        exec(&mut fpu, &mut bus, 0x436, 0, Some(0x1000));
+        assert_eq!(fpu.st(0).unwrap().to_f64(), 1e300);
+
+        // Single precision, chopped: fldcw [0x1010]; fld1; fidiv word [0x1008]
+        bus.load(0x1010, &0x0C7Fu16.to_le_bytes()).unwrap();
+        exec(&mut fpu, &mut bus, 0x12E, 0, Some(0x1010));
+        exec(&mut fpu, &mut bus, 0x1E8, 0, None);
+        exec(&mut fpu, &mut bus, 0x636, 0, Some(0x1008));
+        let v = fpu.st(0).unwrap();
+        assert_eq!((v.se, v.mantissa), (0x3FFD, 0xAAAA_AA00_0000_0000));
+        assert_eq!(fpu.status_word() & FPU_EXC_MASK, FPU_EXC_PRECISION);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod fpu;
+
 pub trait PortMappedDevice {
     fn write_8(&mut self, port:u16, val:u8);
     fn read_8(&mut self, port:u16) -> u8;
@@ -6,6 +8,14 @@ pub trait PortMappedDevice {
     fn debug_info(&self) -> String;
 }
 
+pub trait InterruptController {
+    /// Level of the INTR output to the CPU.
+    fn intr(&self) -> bool;
+    /// Answers the INTA bus cycles with the vector of the highest priority
+    /// pending request.
+    fn acknowledge(&mut self) -> u8;
+}
+
 pub trait Device {
     fn cycle(&mut self);
 }
@@ -1,3 +1,4 @@
+#[allow(non_camel_case_types)]
 #[derive(Debug)]
 pub struct u20 {
     val: u32,
@@ -5,14 +6,14 @@ pub struct u20 {
 
 impl u20 {
     pub fn new(val:u32) -> Self {
-        assert!(val <= 0xFFFF, "Value exceeds 20-bit limit.");
+        assert!(val <= 0xFFFFF, "Value exceeds 20-bit limit.");
         Self { val }
     }
 
     pub fn get(&self) -> u32 { self.val }
 
     pub fn set(&mut self, val:u32) {
-        assert!(val <= 0xFFFF, "Value exceeds 20-bit limit.");
+        assert!(val <= 0xFFFFF, "Value exceeds 20-bit limit.");
         self.val = val;
     }
 }
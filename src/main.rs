@@ -1,8 +1,7 @@
-#![feature(maybe_uninit_uninit_array)]
-
 pub mod ext;
 pub mod cpu;
 pub mod core;
+pub mod devices;
 use crate::ext::queue::Queue;
 
 fn main() {